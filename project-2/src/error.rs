@@ -10,12 +10,21 @@ pub enum KvsError {
     /// Serialization or deserialization error
     #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
+    /// Binary serialization or deserialization error
+    #[fail(display = "{}", _0)]
+    Bincode(#[cause] bincode::Error),
     /// Removing non-existent key error
     #[fail(display = "Key not found")]
     KeyNotFound,
     /// Incorrect command type error.
     #[fail(display = "Incorrect command type")]
     IncorrectCommandType,
+    /// The log file was written in an unknown format version
+    #[fail(display = "Unsupported log format version {}", _0)]
+    UnsupportedFormat(u32),
+    /// The data directory is locked by another open store.
+    #[fail(display = "Data directory {} is in use by another process", _0)]
+    Locked(String),
 }
 
 impl From<io::Error> for KvsError {
@@ -30,5 +39,11 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
 /// Result alias for Kvs.
 pub type Result<T> = std::result::Result<T, KvsError>;
@@ -6,17 +6,29 @@ use std::fs::{File, OpenOptions};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::{fs, io};
 
+use bincode::Options;
+use fs2::FileExt;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 use std::borrow::BorrowMut;
+use std::convert::TryInto;
 use std::ops::Range;
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 const LOG_FILE_NAME: &str = "kvs.log";
+// Name of the file locked by the store writing a data directory.
+const LOCK_FILE_NAME: &str = "LOCK";
+// Magic bytes at the start of the log file.
+const LOG_MAGIC: &[u8; 4] = b"KVSL";
+// Version of the on-disk record format.
+const FORMAT_VERSION: u32 = 1;
+// Length of the log header: magic bytes and little endian u32 format version.
+const LOG_HEADER_LEN: u64 = 8;
 
 /// The `KvStore` stores string key/value pairs.
 ///
-/// Key/value pairs are persisted to disk in log files.
+/// Key/value pairs are persisted to disk in log files as length-prefixed
+/// binary records, after a header holding the format version.
 /// A `BTreeMap` in memory stores the keys and the value locations for fast query.
 ///
 /// ```rust
@@ -42,6 +54,8 @@ pub struct KvStore {
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction.
     need_compacted: u64,
+    // the lock on the data directory, released when the store is dropped.
+    _lock: File,
 }
 
 impl KvStore {
@@ -51,11 +65,15 @@ impl KvStore {
     ///
     /// # Errors
     ///
+    /// It returns `KvsError::Locked` if another open store writes the directory.
+    ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
         let path = path.into();
         fs::create_dir_all(&path)?;
+        let lock = lock_dir(&path)?;
         let log_path = path.join(LOG_FILE_NAME);
+        upgrade_log(&log_path)?;
 
         let mut index = BTreeMap::new();
         let writer = new_log_file(&log_path)?;
@@ -70,6 +88,7 @@ impl KvStore {
             writer,
             index,
             need_compacted,
+            _lock: lock,
         })
     }
 
@@ -83,7 +102,7 @@ impl KvStore {
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
         let cmd = Command::set(key, value);
         let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
+        write_record(&mut self.writer, &cmd)?;
         self.writer.flush()?;
         if let Command::Set { key, .. } = cmd {
             if let Some(old_cmd) = self.index.insert(key, (pos..self.writer.pos).into()) {
@@ -109,7 +128,7 @@ impl KvStore {
             let reader = self.reader.borrow_mut();
             reader.seek(SeekFrom::Start(cmd_pos.pos))?;
             let cmd_reader = reader.take(cmd_pos.len);
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
+            if let Command::Set { value, .. } = read_record(cmd_reader)? {
                 Ok(Some(value))
             } else {
                 Err(KvsError::IncorrectCommandType)
@@ -129,7 +148,7 @@ impl KvStore {
     pub fn remove(&mut self, key: String) -> Result<()> {
         if self.index.contains_key(&key) {
             let cmd = Command::remove(key);
-            serde_json::to_writer(&mut self.writer, &cmd)?;
+            write_record(&mut self.writer, &cmd)?;
             self.writer.flush()?;
             if let Command::Remove { key } = cmd {
                 let old_cmd = self.index.remove(&key).expect("key not found");
@@ -146,7 +165,7 @@ impl KvStore {
         let path = self.path.join(LOG_FILE_NAME.to_owned() + ".temp");
         let mut temp_writer = new_log_file(&path)?;
 
-        let mut new_pos = 0;
+        let mut new_pos = temp_writer.pos;
         for cmd_pos in &mut self.index.values_mut() {
             let reader = self.reader.borrow_mut();
             if reader.pos != cmd_pos.pos {
@@ -177,20 +196,88 @@ impl KvStore {
     }
 }
 
+/// Takes an exclusive advisory lock on the `LOCK` file of the directory.
+///
+/// The file is left in place, the lock goes with the returned handle.
+fn lock_dir(dir: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(LOCK_FILE_NAME))?;
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(file),
+        Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+            Err(KvsError::Locked(dir.display().to_string()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
 /// Create a new log file.
 ///
+/// The log header is written if the file is empty.
+///
 /// Returns the writer to the log.
 fn new_log_file(path: &Path) -> Result<BufWriterWithPos<File>> {
-    let writer = BufWriterWithPos::new(
+    let mut writer = BufWriterWithPos::new(
         OpenOptions::new()
             .create(true)
             .write(true)
             .append(true)
             .open(&path)?,
     )?;
+    if writer.pos == 0 {
+        writer.write_all(LOG_MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.flush()?;
+    }
     Ok(writer)
 }
 
+/// Rewrites a json log file of earlier versions with binary records.
+///
+/// Does nothing if the log file does not exist or has a header. A header cut
+/// short by a crash is truncated, so it is written again.
+fn upgrade_log(log_path: &Path) -> Result<()> {
+    if !log_path.exists() {
+        return Ok(());
+    }
+    let mut header = Vec::with_capacity(LOG_HEADER_LEN as usize);
+    File::open(log_path)?
+        .take(LOG_HEADER_LEN)
+        .read_to_end(&mut header)?;
+    if header.is_empty() {
+        return Ok(());
+    }
+    let magic_len = header.len().min(LOG_MAGIC.len());
+    if header.len() < LOG_HEADER_LEN as usize && header[..magic_len] == LOG_MAGIC[..magic_len] {
+        OpenOptions::new().write(true).open(log_path)?.set_len(0)?;
+        return Ok(());
+    }
+    if header.starts_with(LOG_MAGIC) {
+        let version = u32::from_le_bytes(header[LOG_MAGIC.len()..].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(KvsError::UnsupportedFormat(version));
+        }
+        return Ok(());
+    }
+
+    let temp_path = log_path.with_extension("log.temp");
+    if temp_path.exists() {
+        fs::remove_file(&temp_path)?;
+    }
+    let mut temp_writer = new_log_file(&temp_path)?;
+    let reader = BufReader::new(File::open(log_path)?);
+    for cmd in Deserializer::from_reader(reader).into_iter::<Command>() {
+        write_record(&mut temp_writer, &cmd?)?;
+    }
+    temp_writer.flush()?;
+    fs::rename(temp_path, log_path)?;
+    Ok(())
+}
+
 /// Load the whole log file and store value locations in the index map.
 ///
 /// Returns how many bytes can be saved after a compaction.
@@ -198,12 +285,13 @@ fn load(
     reader: &mut BufReaderWithPos<File>,
     index: &mut BTreeMap<String, CommandPos>,
 ) -> Result<u64> {
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let mut pos = reader.seek(SeekFrom::Start(LOG_HEADER_LEN.min(file_len)))?;
     let mut need_compacted = 0;
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
+    while pos < file_len {
+        let cmd = read_record(&mut *reader)?;
+        let new_pos = reader.pos;
+        match cmd {
             Command::Set { key, .. } => {
                 if let Some(old_cmd) = index.insert(key, (pos..new_pos).into()) {
                     need_compacted += old_cmd.len;
@@ -221,6 +309,23 @@ fn load(
     Ok(need_compacted)
 }
 
+/// Writes the command as a binary record prefixed by its little endian u32 length.
+fn write_record<W: Write>(writer: &mut W, cmd: &Command) -> Result<()> {
+    let payload = bincode::DefaultOptions::new().serialize(cmd)?;
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// Reads a length-prefixed binary record and deserializes it to `Command`.
+fn read_record<R: Read>(mut reader: R) -> Result<Command> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let mut payload = vec![0u8; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(bincode::DefaultOptions::new().deserialize(&payload)?)
+}
+
 /// Struct representing a command.
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
@@ -238,7 +343,7 @@ impl Command {
     }
 }
 
-/// Represents the position and length of a json-serialized command in the log.
+/// Represents the position and length of a serialized command in the log.
 struct CommandPos {
     pos: u64,
     len: u64,
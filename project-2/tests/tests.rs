@@ -1,7 +1,8 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{KvStore, KvsError, Result};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::fs;
 use std::process::Command;
 use tempfile::TempDir;
 use walkdir::WalkDir;
@@ -254,6 +255,64 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Should refuse a second store writing the same directory until the first is dropped
+#[test]
+fn lock_data_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Locked(_)) => {}
+        res => panic!("second store opened a locked directory: {:?}", res.is_ok()),
+    }
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should open a json log file written by earlier versions
+#[test]
+fn open_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("kvs.log");
+    let json = r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key1"}}"#;
+    fs::write(&log_path, json)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(fs::read(&log_path)?.starts_with(b"KVSL"));
+    assert!(fs::metadata(&log_path)?.len() < json.len() as u64);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should write the header again over one cut short by a crash
+#[test]
+fn open_torn_header() -> Result<()> {
+    for torn in &[&b"KV"[..], &b"KVSL\x01"[..]] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let log_path = temp_dir.path().join("kvs.log");
+        fs::write(&log_path, torn)?;
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(fs::read(&log_path)?, b"KVSL\x01\x00\x00\x00");
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        drop(store);
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    }
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -1,23 +1,17 @@
 #[macro_use]
 extern crate criterion;
 
-use assert_cmd::prelude::*;
 use criterion::Criterion;
-use kvs::KvsClient;
+use kvs::{
+    KvEngine, KvStoreOptions, KvsClient, KvsServer, MyKvStore, RayonThreadPool,
+    SharedQueueThreadPool, SledKvs, ThreadPool,
+};
 use rand::prelude::*;
 use std::net::SocketAddr;
-use std::process::Command;
 use std::str::FromStr;
-use std::sync::mpsc;
-use std::thread;
-use std::time::Duration;
 use tempfile::tempdir;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:8080";
-const KVS: &str = "kvs";
-const SLED: &str = "sled";
-const SHARED_POOL: &str = "shared";
-const RAYON_POOL: &str = "rayon";
 
 fn shared_queue_kvs_write_bench(c: &mut Criterion) {
     let thread_nums = vec![2, 4, 8];
@@ -25,39 +19,22 @@ fn shared_queue_kvs_write_bench(c: &mut Criterion) {
         "shared_queue_kvs_write",
         |b, &num| {
             let temp_dir = tempdir().unwrap();
-            let mut server = Command::cargo_bin("kvs-server").unwrap();
-            let mut child = server
-                .args(&[
-                    "--engine",
-                    KVS,
-                    "--addr",
-                    DEFAULT_LISTENING_ADDRESS,
-                    "--thread_pool",
-                    SHARED_POOL,
-                    "--thread_pool_size",
-                    &num.to_string(),
-                ])
-                .current_dir(&temp_dir)
-                .spawn()
-                .unwrap();
-            let (sender, receiver) = mpsc::sync_channel(0);
-            let handle = thread::spawn(move || {
-                let _ = receiver.recv(); // wait for main thread to finish
-                child.kill().expect("server exited before killed");
-            });
-            thread::sleep(Duration::from_secs(3));
+            let server = KvsServer::new(
+                MyKvStore::open(temp_dir.path()).unwrap(),
+                SharedQueueThreadPool::new(num).unwrap(),
+            );
+            let handle = server.start(DEFAULT_LISTENING_ADDRESS).unwrap();
             b.iter(|| {
                 let mut client =
                     KvsClient::init(SocketAddr::from_str(DEFAULT_LISTENING_ADDRESS).unwrap())
                         .unwrap();
                 for i in 1..100 {
                     client
-                        .set(format!("key{}", i), "value".to_string())
+                        .set(format!("key{}", i).into_bytes(), b"value".to_vec())
                         .unwrap();
                 }
             });
-            sender.send(()).unwrap();
-            handle.join().unwrap();
+            handle.shutdown().unwrap();
         },
         thread_nums,
     );
@@ -69,41 +46,26 @@ fn shared_queue_kvs_read_bench(c: &mut Criterion) {
         "shared_queue_kvs_read",
         |b, &num| {
             let temp_dir = tempdir().unwrap();
-            let mut server = Command::cargo_bin("kvs-server").unwrap();
-            let mut child = server
-                .args(&[
-                    "--engine",
-                    KVS,
-                    "--addr",
-                    DEFAULT_LISTENING_ADDRESS,
-                    "--thread_pool",
-                    SHARED_POOL,
-                    "--thread_pool_size",
-                    &num.to_string(),
-                ])
-                .current_dir(&temp_dir)
-                .spawn()
-                .unwrap();
-            let (sender, receiver) = mpsc::sync_channel(0);
-            let handle = thread::spawn(move || {
-                let _ = receiver.recv(); // wait for main thread to finish
-                child.kill().expect("server exited before killed");
-            });
-            thread::sleep(Duration::from_secs(3));
+            let server = KvsServer::new(
+                MyKvStore::open(temp_dir.path()).unwrap(),
+                SharedQueueThreadPool::new(num).unwrap(),
+            );
+            let handle = server.start(DEFAULT_LISTENING_ADDRESS).unwrap();
             let address = SocketAddr::from_str(DEFAULT_LISTENING_ADDRESS).unwrap();
             let mut client = KvsClient::init(address).unwrap();
             for i in 1..100 {
                 client
-                    .set(format!("key{}", i), "value".to_string())
+                    .set(format!("key{}", i).into_bytes(), b"value".to_vec())
                     .unwrap();
             }
             let mut rng = StdRng::seed_from_u64(64);
             b.iter(|| {
                 let mut client = KvsClient::init(address).unwrap();
-                client.get(format!("key{}", rng.gen_range(1, 100))).unwrap();
+                client
+                    .get(format!("key{}", rng.gen_range(1, 100)).into_bytes())
+                    .unwrap();
             });
-            sender.send(()).unwrap();
-            handle.join().unwrap();
+            handle.shutdown().unwrap();
         },
         thread_nums,
     );
@@ -115,39 +77,22 @@ fn rayon_kvs_write_bench(c: &mut Criterion) {
         "rayon_kvs_write",
         |b, &num| {
             let temp_dir = tempdir().unwrap();
-            let mut server = Command::cargo_bin("kvs-server").unwrap();
-            let mut child = server
-                .args(&[
-                    "--engine",
-                    KVS,
-                    "--addr",
-                    DEFAULT_LISTENING_ADDRESS,
-                    "--thread_pool",
-                    RAYON_POOL,
-                    "--thread_pool_size",
-                    &num.to_string(),
-                ])
-                .current_dir(&temp_dir)
-                .spawn()
-                .unwrap();
-            let (sender, receiver) = mpsc::sync_channel(0);
-            let handle = thread::spawn(move || {
-                let _ = receiver.recv(); // wait for main thread to finish
-                child.kill().expect("server exited before killed");
-            });
-            thread::sleep(Duration::from_secs(3));
+            let server = KvsServer::new(
+                MyKvStore::open(temp_dir.path()).unwrap(),
+                RayonThreadPool::new(num).unwrap(),
+            );
+            let handle = server.start(DEFAULT_LISTENING_ADDRESS).unwrap();
             b.iter(|| {
                 let mut client =
                     KvsClient::init(SocketAddr::from_str(DEFAULT_LISTENING_ADDRESS).unwrap())
                         .unwrap();
                 for i in 1..100 {
                     client
-                        .set(format!("key{}", i), "value".to_string())
+                        .set(format!("key{}", i).into_bytes(), b"value".to_vec())
                         .unwrap();
                 }
             });
-            sender.send(()).unwrap();
-            handle.join().unwrap();
+            handle.shutdown().unwrap();
         },
         thread_nums,
     );
@@ -159,41 +104,26 @@ fn rayon_kvs_read_bench(c: &mut Criterion) {
         "rayon_kvs_read",
         |b, &num| {
             let temp_dir = tempdir().unwrap();
-            let mut server = Command::cargo_bin("kvs-server").unwrap();
-            let mut child = server
-                .args(&[
-                    "--engine",
-                    KVS,
-                    "--addr",
-                    DEFAULT_LISTENING_ADDRESS,
-                    "--thread_pool",
-                    RAYON_POOL,
-                    "--thread_pool_size",
-                    &num.to_string(),
-                ])
-                .current_dir(&temp_dir)
-                .spawn()
-                .unwrap();
-            let (sender, receiver) = mpsc::sync_channel(0);
-            let handle = thread::spawn(move || {
-                let _ = receiver.recv(); // wait for main thread to finish
-                child.kill().expect("server exited before killed");
-            });
-            thread::sleep(Duration::from_secs(3));
+            let server = KvsServer::new(
+                MyKvStore::open(temp_dir.path()).unwrap(),
+                RayonThreadPool::new(num).unwrap(),
+            );
+            let handle = server.start(DEFAULT_LISTENING_ADDRESS).unwrap();
             let address = SocketAddr::from_str(DEFAULT_LISTENING_ADDRESS).unwrap();
             let mut client = KvsClient::init(address).unwrap();
             for i in 1..100 {
                 client
-                    .set(format!("key{}", i), "value".to_string())
+                    .set(format!("key{}", i).into_bytes(), b"value".to_vec())
                     .unwrap();
             }
             let mut rng = StdRng::seed_from_u64(64);
             b.iter(|| {
                 let mut client = KvsClient::init(address).unwrap();
-                client.get(format!("key{}", rng.gen_range(1, 100))).unwrap();
+                client
+                    .get(format!("key{}", rng.gen_range(1, 100)).into_bytes())
+                    .unwrap();
             });
-            sender.send(()).unwrap();
-            handle.join().unwrap();
+            handle.shutdown().unwrap();
         },
         thread_nums,
     );
@@ -205,39 +135,22 @@ fn rayon_sled_write_bench(c: &mut Criterion) {
         "rayon_sled_write",
         |b, &num| {
             let temp_dir = tempdir().unwrap();
-            let mut server = Command::cargo_bin("kvs-server").unwrap();
-            let mut child = server
-                .args(&[
-                    "--engine",
-                    SLED,
-                    "--addr",
-                    DEFAULT_LISTENING_ADDRESS,
-                    "--thread_pool",
-                    RAYON_POOL,
-                    "--thread_pool_size",
-                    &num.to_string(),
-                ])
-                .current_dir(&temp_dir)
-                .spawn()
-                .unwrap();
-            let (sender, receiver) = mpsc::sync_channel(0);
-            let handle = thread::spawn(move || {
-                let _ = receiver.recv(); // wait for main thread to finish
-                child.kill().expect("server exited before killed");
-            });
-            thread::sleep(Duration::from_secs(3));
+            let server = KvsServer::new(
+                SledKvs::new(sled::open(temp_dir.path()).unwrap()),
+                RayonThreadPool::new(num).unwrap(),
+            );
+            let handle = server.start(DEFAULT_LISTENING_ADDRESS).unwrap();
             b.iter(|| {
                 let mut client =
                     KvsClient::init(SocketAddr::from_str(DEFAULT_LISTENING_ADDRESS).unwrap())
                         .unwrap();
                 for i in 1..100 {
                     client
-                        .set(format!("key{}", i), "value".to_string())
+                        .set(format!("key{}", i).into_bytes(), b"value".to_vec())
                         .unwrap();
                 }
             });
-            sender.send(()).unwrap();
-            handle.join().unwrap();
+            handle.shutdown().unwrap();
         },
         thread_nums,
     );
@@ -249,46 +162,63 @@ fn rayon_sled_read_bench(c: &mut Criterion) {
         "rayon_sled_read",
         |b, &num| {
             let temp_dir = tempdir().unwrap();
-            let mut server = Command::cargo_bin("kvs-server").unwrap();
-            let mut child = server
-                .args(&[
-                    "--engine",
-                    SLED,
-                    "--addr",
-                    DEFAULT_LISTENING_ADDRESS,
-                    "--thread_pool",
-                    RAYON_POOL,
-                    "--thread_pool_size",
-                    &num.to_string(),
-                ])
-                .current_dir(&temp_dir)
-                .spawn()
-                .unwrap();
-            let (sender, receiver) = mpsc::sync_channel(0);
-            let handle = thread::spawn(move || {
-                let _ = receiver.recv(); // wait for main thread to finish
-                child.kill().expect("server exited before killed");
-            });
-            thread::sleep(Duration::from_secs(3));
+            let server = KvsServer::new(
+                SledKvs::new(sled::open(temp_dir.path()).unwrap()),
+                RayonThreadPool::new(num).unwrap(),
+            );
+            let handle = server.start(DEFAULT_LISTENING_ADDRESS).unwrap();
             let address = SocketAddr::from_str(DEFAULT_LISTENING_ADDRESS).unwrap();
             let mut client = KvsClient::init(address).unwrap();
             for i in 1..100 {
                 client
-                    .set(format!("key{}", i), "value".to_string())
+                    .set(format!("key{}", i).into_bytes(), b"value".to_vec())
                     .unwrap();
             }
             let mut rng = StdRng::seed_from_u64(64);
             b.iter(|| {
                 let mut client = KvsClient::init(address).unwrap();
-                client.get(format!("key{}", rng.gen_range(1, 100))).unwrap();
+                client
+                    .get(format!("key{}", rng.gen_range(1, 100)).into_bytes())
+                    .unwrap();
             });
-            sender.send(()).unwrap();
-            handle.join().unwrap();
+            handle.shutdown().unwrap();
         },
         thread_nums,
     );
 }
 
+// Compare positional reads with memory mapped reads of values in sealed segments.
+fn kvs_get_bench(c: &mut Criterion) {
+    // The value cache is off for pread and mmap, so every read goes to the
+    // segments. "cached" reads with pread through the default cache.
+    let read_paths = vec!["pread", "mmap", "cached"];
+    c.bench_function_over_inputs(
+        "kvs_get",
+        |b, &read_path| {
+            let temp_dir = tempdir().unwrap();
+            let mut options = KvStoreOptions::default().mmap_reads(read_path == "mmap");
+            if read_path != "cached" {
+                options = options.cache_size(0);
+            }
+            let store = MyKvStore::open_with_options(temp_dir.path(), options).unwrap();
+            // Enough data to fill several segments, so most keys are in sealed ones.
+            let value = vec![b'v'; 4096];
+            for i in 1..1000 {
+                store
+                    .set(format!("key{}", i).into_bytes(), value.clone())
+                    .unwrap();
+            }
+            let mut rng = StdRng::seed_from_u64(64);
+            b.iter(|| {
+                store
+                    .get(format!("key{}", rng.gen_range(1, 1000)).into_bytes())
+                    .unwrap();
+            });
+        },
+        read_paths,
+    );
+}
+
 criterion_group!(
     benches,
     shared_queue_kvs_write_bench,
@@ -297,5 +227,6 @@ criterion_group!(
     rayon_kvs_read_bench,
     rayon_sled_write_bench,
     rayon_sled_read_bench,
+    kvs_get_bench,
 );
 criterion_main!(benches);
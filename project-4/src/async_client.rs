@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tokio::io::{self, AsyncWriteExt, BufReader, BufWriter, ReadHalf, WriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::error::KvsError;
+use crate::protocol::{
+    read_frame_async, read_hello_async, write_frame_async, write_hello_async, Codec,
+    PROTOCOL_VERSION,
+};
+use crate::{Request, Response, Result, WriteBatch};
+
+/// The async client of key value store, speaking the same protocol as `KvsClient`.
+///
+/// ```rust,no_run
+/// # use kvs::{AsyncKvsClient, Result};
+/// # async fn try_main() -> Result<()> {
+/// let mut client = AsyncKvsClient::connect("127.0.0.1:4000").await?;
+/// client.set(b"key".to_vec(), b"value".to_vec()).await?;
+/// assert_eq!(client.get(b"key".to_vec()).await?, Some(b"value".to_vec()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncKvsClient {
+    reader: BufReader<ReadHalf<TcpStream>>,
+    writer: BufWriter<WriteHalf<TcpStream>>,
+    codec: Codec,
+    next_id: u64,
+    // Responses which arrived while waiting for another one.
+    received: HashMap<u64, Response>,
+}
+
+impl AsyncKvsClient {
+    /// Connect to the server, with the binary codec.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        AsyncKvsClient::connect_with_codec(addr, Codec::Bincode).await
+    }
+
+    /// Connect to the server, asking for the given codec.
+    ///
+    /// The server may fall back to json.
+    pub async fn connect_with_codec<A: ToSocketAddrs>(addr: A, codec: Codec) -> Result<Self> {
+        let (reader, writer) = io::split(TcpStream::connect(addr).await?);
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+
+        write_hello_async(&mut writer, PROTOCOL_VERSION, codec).await?;
+        let (version, codec) = read_hello_async(&mut reader).await?;
+        if version == 0 || version > PROTOCOL_VERSION {
+            return Err(KvsError::Protocol(format!(
+                "unsupported protocol version {}",
+                version
+            )));
+        }
+        Ok(AsyncKvsClient {
+            reader,
+            writer,
+            codec,
+            next_id: 0,
+            received: HashMap::new(),
+        })
+    }
+
+    /// Returns the codec negotiated with the server.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Queues a request without waiting for its response.
+    ///
+    /// Returns the id to `receive` the response with.
+    pub async fn send(&mut self, request: Request) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        write_frame_async(&mut self.writer, self.codec, id, &request).await?;
+        Ok(id)
+    }
+
+    /// Sends the queued requests and waits for the response to the given one.
+    pub async fn receive(&mut self, id: u64) -> Result<Response> {
+        self.writer.flush().await?;
+        if let Some(response) = self.received.remove(&id) {
+            return Ok(response);
+        }
+        loop {
+            match read_frame_async(&mut self.reader, self.codec).await? {
+                Some((received_id, response)) if received_id == id => return Ok(response),
+                Some((received_id, response)) => {
+                    self.received.insert(received_id, response);
+                }
+                None => return Err(KvsError::Protocol("connection closed by server".to_owned())),
+            }
+        }
+    }
+
+    /// Get the value of key from server.
+    pub async fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.call(Request::Get { key }).await?.into_value()
+    }
+
+    /// Set the value to server.
+    pub async fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let request = Request::Set {
+            key,
+            value,
+            ttl: None,
+        };
+        self.call(request).await?.into_done()
+    }
+
+    /// Set the value of key on server, to expire after `ttl`.
+    pub async fn set_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let request = Request::Set {
+            key,
+            value,
+            ttl: Some(ttl),
+        };
+        self.call(request).await?.into_done()
+    }
+
+    /// Remove the key value from server.
+    pub async fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        self.call(Request::Remove { key }).await?.into_done()
+    }
+
+    /// Apply every write of the batch atomically on server.
+    pub async fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.call(Request::Batch { batch }).await?.into_done()
+    }
+
+    /// Set the value of a key to `new` on server if its current value is `expected`.
+    ///
+    /// Returns `false` if the current value is not the expected one.
+    pub async fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.call(Request::CompareAndSwap { key, expected, new })
+            .await?
+            .into_swapped()
+    }
+
+    /// Set the value of a key to a value on server if the key does not exist.
+    ///
+    /// Returns `false` if the key already exists.
+    pub async fn set_if_absent(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value)).await
+    }
+
+    /// Get up to `limit` key/value pairs with keys in `[start, end)` from server.
+    pub async fn scan(
+        &mut self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.call(Request::Scan { start, end, limit })
+            .await?
+            .into_pairs()
+    }
+
+    /// Get up to `limit` key/value pairs whose key starts with `prefix` from server.
+    pub async fn scan_prefix(
+        &mut self,
+        prefix: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.call(Request::ScanPrefix { prefix, limit })
+            .await?
+            .into_pairs()
+    }
+
+    /// Back up the data of server to the directory `dest` on server.
+    ///
+    /// `dest` is relative to the backup root of the server.
+    pub async fn backup(&mut self, dest: impl Into<PathBuf>) -> Result<()> {
+        self.call(Request::Backup { dest: dest.into() })
+            .await?
+            .into_done()
+    }
+
+    // Send the request and wait for its response.
+    async fn call(&mut self, request: Request) -> Result<Response> {
+        let id = self.send(request).await?;
+        self.receive(id).await
+    }
+}
@@ -0,0 +1,284 @@
+use serde_json::Deserializer;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::{self, JoinHandle};
+
+use crate::protocol::{
+    read_frame_async, read_hello_async, write_frame_async, write_hello_async, PROTOCOL_MAGIC,
+    PROTOCOL_VERSION,
+};
+use crate::request::JsonRequest;
+use crate::server::{execute, write_json_response};
+use crate::{KvEngine, KvsError, Request, Response, Result};
+
+/// The server of key value store, running on a tokio runtime.
+///
+/// Connections are served by tasks, so idle clients hold no thread. Engine
+/// calls block, they run on the blocking pool of the runtime.
+///
+/// ```rust,no_run
+/// # use kvs::{AsyncKvsServer, MyKvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// let server = AsyncKvsServer::new(MyKvStore::open(std::env::current_dir()?)?);
+/// let mut runtime = tokio::runtime::Runtime::new()?;
+/// let handle = runtime.block_on(server.start("127.0.0.1:4000"))?;
+/// // Serve the clients until told to stop...
+/// runtime.block_on(handle.shutdown())?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncKvsServer<E: KvEngine> {
+    engine: E,
+    backup_root: Option<Arc<Path>>,
+}
+
+impl<E: KvEngine> AsyncKvsServer<E> {
+    /// Create a server on the engine.
+    pub fn new(engine: E) -> Self {
+        AsyncKvsServer {
+            engine,
+            backup_root: None,
+        }
+    }
+
+    /// Lets clients back up the store to directories under `root`.
+    ///
+    /// Backup requests are refused unless a root is set.
+    pub fn backup_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.backup_root = Some(Arc::from(root.into()));
+        self
+    }
+
+    /// Listen on the address and serve the clients from tasks of the current runtime.
+    ///
+    /// Must run within a tokio runtime with the blocking pool enabled, the
+    /// returned handle stops the server.
+    pub async fn start<A: ToSocketAddrs>(self, addr: A) -> Result<AsyncShutdownHandle> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (shutdown, shutdown_received) = oneshot::channel();
+        let acceptor = tokio::spawn(self.serve(listener, shutdown_received));
+        Ok(AsyncShutdownHandle {
+            local_addr,
+            shutdown,
+            acceptor,
+        })
+    }
+
+    // Accept connections until shut down, then drain them.
+    async fn serve(
+        self,
+        mut listener: TcpListener,
+        mut shutdown: oneshot::Receiver<()>,
+    ) -> Result<()> {
+        // Dropped to tell the connections to stop reading requests.
+        let (stop_reading, _) = broadcast::channel::<()>(1);
+        // Held by every connection, so `recv` returns once they are all served.
+        let (served, mut all_served) = mpsc::channel::<()>(1);
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                // A dropped handle stops the server too.
+                _ = &mut shutdown => break,
+            };
+            match accepted {
+                Ok((stream, _)) => {
+                    let engine = self.engine.clone();
+                    let backup_root = self.backup_root.clone();
+                    let stop = stop_reading.subscribe();
+                    let served = served.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle(engine, backup_root, stream, stop).await {
+                            error!("Error on serving client: {}", e);
+                        }
+                        drop(served);
+                    });
+                }
+                Err(e) => error!("Connection failed: {}", e),
+            }
+        }
+        drop(listener);
+
+        drop(stop_reading);
+        drop(served);
+        all_served.recv().await;
+        let engine = self.engine;
+        match task::spawn_blocking(move || engine.flush()).await {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Flushing the engine failed: {}", e);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A handle to stop an `AsyncKvsServer`.
+///
+/// Dropping the handle stops the server in the background.
+pub struct AsyncShutdownHandle {
+    local_addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    acceptor: JoinHandle<Result<()>>,
+}
+
+impl AsyncShutdownHandle {
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops the server.
+    ///
+    /// New connections are refused, the requests already received are
+    /// answered, then the engine is flushed.
+    pub async fn shutdown(self) -> Result<()> {
+        // The server may have stopped already.
+        let _ = self.shutdown.send(());
+        match self.acceptor.await {
+            Ok(result) => result,
+            Err(e) => {
+                error!("Acceptor task failed: {}", e);
+                Ok(())
+            }
+        }
+    }
+}
+
+// Handle the stream, with the framed protocol or the bare json one.
+//
+// Requests stop being read once `stop` is closed.
+async fn handle<E: KvEngine>(
+    engine: E,
+    backup_root: Option<Arc<Path>>,
+    mut tcp: TcpStream,
+    mut stop: broadcast::Receiver<()>,
+) -> Result<()> {
+    let peer_addr = tcp.peer_addr()?;
+    debug!("Get the tcp stream form {}", peer_addr);
+    let mut first = [0u8; 1];
+    let peeked = tokio::select! {
+        peeked = tcp.peek(&mut first) => peeked?,
+        _ = stop.recv() => 0,
+    };
+    if peeked == 0 {
+        return Ok(());
+    }
+    let (reader, writer) = tcp.split();
+    let reader = BufReader::new(reader);
+    let writer = BufWriter::new(writer);
+    if first[0] == PROTOCOL_MAGIC[0] {
+        handle_framed(engine, backup_root, reader, writer, stop).await
+    } else {
+        handle_json(engine, backup_root, reader, writer, stop).await
+    }
+}
+
+// Serve the framed protocol.
+async fn handle_framed<E, R, W>(
+    engine: E,
+    backup_root: Option<Arc<Path>>,
+    mut reader: BufReader<R>,
+    mut writer: W,
+    mut stop: broadcast::Receiver<()>,
+) -> Result<()>
+where
+    E: KvEngine,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (version, codec) = read_hello_async(&mut reader).await?;
+    if version == 0 {
+        write_hello_async(&mut writer, 0, codec).await?;
+        return Err(KvsError::Protocol(
+            "unsupported protocol version".to_owned(),
+        ));
+    }
+    write_hello_async(&mut writer, version.min(PROTOCOL_VERSION), codec).await?;
+
+    loop {
+        let frame = tokio::select! {
+            frame = read_frame_async(&mut reader, codec) => frame?,
+            _ = stop.recv() => None,
+        };
+        let (id, request) = match frame {
+            Some(frame) => frame,
+            None => break,
+        };
+        let response = execute_blocking(engine.clone(), backup_root.clone(), request).await;
+        write_frame_async(&mut writer, codec, id, &response).await?;
+        // Answer pipelined requests in one go.
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+// Serve the bare json protocol of earlier versions.
+//
+// Requests are concatenated json values, read into a buffer until a whole
+// one has arrived.
+async fn handle_json<E, R, W>(
+    engine: E,
+    backup_root: Option<Arc<Path>>,
+    mut reader: R,
+    mut writer: W,
+    mut stop: broadcast::Receiver<()>,
+) -> Result<()>
+where
+    E: KvEngine,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = tokio::select! {
+            n = reader.read(&mut chunk) => n?,
+            _ = stop.recv() => 0,
+        };
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut requests = Deserializer::from_slice(&buf).into_iter::<JsonRequest>();
+        let mut parsed = Vec::new();
+        for request in &mut requests {
+            match request {
+                Ok(request) => parsed.push(request),
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let consumed = requests.byte_offset();
+        buf.drain(..consumed);
+
+        for request in parsed {
+            let legacy = request.is_legacy();
+            let response =
+                execute_blocking(engine.clone(), backup_root.clone(), request.into()).await;
+            let mut out = Vec::new();
+            write_json_response(&mut out, response, legacy)?;
+            writer.write_all(&out).await?;
+        }
+        writer.flush().await?;
+    }
+}
+
+// Run the request on the blocking pool, so the engine does not stall the runtime.
+async fn execute_blocking<E: KvEngine>(
+    engine: E,
+    backup_root: Option<Arc<Path>>,
+    request: Request,
+) -> Response {
+    task::spawn_blocking(move || execute(&engine, request, backup_root.as_deref()))
+        .await
+        .unwrap_or_else(|e| Response::Error(format!("{}", e)))
+}
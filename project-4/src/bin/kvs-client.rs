@@ -1,7 +1,9 @@
-use clap::AppSettings;
-use kvs::{KvsClient, Result};
+use clap::{arg_enum, AppSettings};
+use kvs::{KvsClient, KvsError, Result};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
@@ -15,10 +17,55 @@ const ADDRESS_VALUE_NAME: &str = "IP:PORT";
                            AppSettings::VersionlessSubcommands]")
 )]
 struct Opt {
+    #[structopt(
+        long,
+        help = "Sets how keys and values are written on the command line",
+        value_name = "ENCODING",
+        default_value = "text",
+        raw(possible_values = "&Encoding::variants()", global = "true")
+    )]
+    encoding: Encoding,
     #[structopt(subcommand)]
     command: Command,
 }
 
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Encoding {
+        text,
+        hex,
+        base64,
+    }
+}
+
+impl Encoding {
+    /// Decodes a key or value given on the command line.
+    fn decode(self, input: String) -> Result<Vec<u8>> {
+        match self {
+            Encoding::text => Ok(input.into_bytes()),
+            Encoding::hex => {
+                hex::decode(&input).map_err(|e| KvsError::InvalidInput(format!("{}: {}", input, e)))
+            }
+            Encoding::base64 => base64::decode(&input)
+                .map_err(|e| KvsError::InvalidInput(format!("{}: {}", input, e))),
+        }
+    }
+
+    /// Encodes a key or value to print.
+    fn encode(self, bytes: Vec<u8>) -> Result<String> {
+        match self {
+            Encoding::text => Ok(String::from_utf8(bytes)?),
+            Encoding::hex => Ok(hex::encode(bytes)),
+            Encoding::base64 => Ok(base64::encode(bytes)),
+        }
+    }
+
+    fn decode_opt(self, input: Option<String>) -> Result<Option<Vec<u8>>> {
+        input.map(|input| self.decode(input)).transpose()
+    }
+}
+
 #[derive(StructOpt, Debug)]
 enum Command {
     #[structopt(name = "get", about = "Get the string value of a given string key")]
@@ -40,6 +87,76 @@ enum Command {
         key: String,
         #[structopt(name = "VALUE", help = "The string value of the key")]
         value: String,
+        #[structopt(
+            long,
+            help = "Expires the key after the given number of seconds",
+            value_name = "SECONDS"
+        )]
+        ttl: Option<u64>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_VALUE_NAME"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "cas",
+        about = "Set or remove a given string key if its value is the expected one"
+    )]
+    CompareAndSwap {
+        #[structopt(name = "KEY", help = "A string key")]
+        key: String,
+        #[structopt(
+            long,
+            help = "The expected string value, the key is expected to be absent without it",
+            value_name = "VALUE"
+        )]
+        expected: Option<String>,
+        #[structopt(
+            long,
+            help = "The new string value, the key is removed without it",
+            value_name = "VALUE"
+        )]
+        new: Option<String>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_VALUE_NAME"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "scan",
+        about = "List the key/value pairs in a key range or with a key prefix"
+    )]
+    Scan {
+        #[structopt(
+            name = "START",
+            help = "The first string key of the range",
+            raw(required_unless = "\"prefix\"")
+        )]
+        start: Option<String>,
+        #[structopt(name = "END", help = "The string key the range stops before")]
+        end: Option<String>,
+        #[structopt(
+            long,
+            help = "Lists the keys starting with the prefix instead of a range",
+            value_name = "PREFIX",
+            raw(conflicts_with = "\"START\"")
+        )]
+        prefix: Option<String>,
+        #[structopt(
+            long,
+            help = "The maximum number of pairs to list",
+            value_name = "LIMIT",
+            default_value = "100"
+        )]
+        limit: usize,
         #[structopt(
             long,
             help = "Sets the server address",
@@ -62,6 +179,26 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+    #[structopt(
+        name = "backup",
+        about = "Back up the data of the server to a directory on the server"
+    )]
+    Backup {
+        #[structopt(
+            name = "DIR",
+            help = "An empty directory, relative to the backup directory of the server",
+            parse(from_os_str)
+        )]
+        dest: PathBuf,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            raw(value_name = "ADDRESS_VALUE_NAME"),
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
 }
 
 fn main() {
@@ -73,22 +210,73 @@ fn main() {
 }
 
 fn run(opt: Opt) -> Result<()> {
+    let encoding = opt.encoding;
     match opt.command {
         Command::Get { key, addr } => {
             let mut client = KvsClient::init(addr)?;
-            if let Some(value) = client.get(key)? {
-                println!("{}", value);
+            if let Some(value) = client.get(encoding.decode(key)?)? {
+                println!("{}", encoding.encode(value)?);
             } else {
                 println!("Key not found");
             }
         }
-        Command::Set { key, value, addr } => {
+        Command::Set {
+            key,
+            value,
+            ttl,
+            addr,
+        } => {
             let mut client = KvsClient::init(addr)?;
-            client.set(key, value)?
+            let (key, value) = (encoding.decode(key)?, encoding.decode(value)?);
+            match ttl {
+                Some(ttl) => client.set_with_ttl(key, value, Duration::from_secs(ttl))?,
+                None => client.set(key, value)?,
+            }
+        }
+        Command::CompareAndSwap {
+            key,
+            expected,
+            new,
+            addr,
+        } => {
+            let mut client = KvsClient::init(addr)?;
+            let swapped = client.compare_and_swap(
+                encoding.decode(key)?,
+                encoding.decode_opt(expected)?,
+                encoding.decode_opt(new)?,
+            )?;
+            if !swapped {
+                eprintln!("Value mismatch");
+                exit(1);
+            }
+        }
+        Command::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+            addr,
+        } => {
+            let mut client = KvsClient::init(addr)?;
+            let pairs = match prefix {
+                Some(prefix) => client.scan_prefix(encoding.decode(prefix)?, limit)?,
+                None => client.scan(
+                    encoding.decode(start.unwrap_or_default())?,
+                    encoding.decode_opt(end)?,
+                    limit,
+                )?,
+            };
+            for (key, value) in pairs {
+                println!("{}\t{}", encoding.encode(key)?, encoding.encode(value)?);
+            }
         }
         Command::Remove { key, addr } => {
             let mut client = KvsClient::init(addr)?;
-            client.remove(key)?
+            client.remove(encoding.decode(key)?)?
+        }
+        Command::Backup { dest, addr } => {
+            let mut client = KvsClient::init(addr)?;
+            client.backup(dest)?
         }
     }
     Ok(())
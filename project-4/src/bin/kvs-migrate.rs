@@ -0,0 +1,231 @@
+use kvs::{
+    migrate, Durability, EncryptionKey, EngineKind, KvEngine, KvStoreOptions, KvsError, Manifest,
+    MigrationSummary, MyKvStore, Result, SledKvs, LOCK_FILE_NAME, MANIFEST_FILE_NAME,
+};
+use std::env::current_dir;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use structopt::StructOpt;
+
+// The target engine is written here first, and only moved in once verified.
+const STAGING_DIR_NAME: &str = "migrate.tmp";
+// The files of the source engine are moved here until the target is in place.
+const RETIRED_DIR_NAME: &str = "migrate.old";
+// The page cache of sled during the migration.
+const SLED_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-migrate",
+    about = "Moves the data of a stopped kvs server to another storage engine"
+)]
+struct Opt {
+    #[structopt(
+        long,
+        help = "The engine to move the data to",
+        value_name = "ENGINE-NAME",
+        raw(possible_values = "&[\"kvs\", \"sled\"]")
+    )]
+    to: EngineKind,
+    #[structopt(
+        long,
+        help = "The key file the log of the kvs engine is encrypted with, \
+                or is to be encrypted with when moving to it",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    key_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "The data directory of the server, the current directory by default",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    dir: Option<PathBuf>,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    let dir = match opt.dir {
+        Some(dir) => dir,
+        None => current_dir()?,
+    };
+    recover_swap(&dir)?;
+    // A directory without a manifest is a kvs one, as for kvs-server.
+    let from = Manifest::load(&dir)?
+        .map(|manifest| manifest.engine)
+        .unwrap_or(EngineKind::Kvs);
+    if from == opt.to {
+        return Err(KvsError::InvalidInput(format!(
+            "the data directory already uses the {} engine",
+            from
+        )));
+    }
+    let encryption_key = opt.key_file.map(EncryptionKey::from_file).transpose()?;
+    let kvs_options = KvStoreOptions::default().encryption_key(encryption_key);
+
+    // A staging directory left by an interrupted migration holds nothing verified.
+    let staging = dir.join(STAGING_DIR_NAME);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    let summary = match from {
+        EngineKind::Kvs => migrate_to(
+            MyKvStore::open_with_options(&dir, kvs_options)?,
+            SledKvs::open(&staging, Durability::None, Some(SLED_CACHE_SIZE))?,
+        )?,
+        EngineKind::Sled => migrate_to(
+            SledKvs::open(&dir, Durability::None, Some(SLED_CACHE_SIZE))?,
+            MyKvStore::open_with_options(&staging, kvs_options.durability(Durability::None))?,
+        )?,
+    };
+
+    sync_dir(&staging)?;
+    swap_engine(&dir, from)?;
+    println!(
+        "Migrated {} keys from {} to {}, checksum {:08x}",
+        summary.keys, from, opt.to, summary.checksum
+    );
+    Ok(())
+}
+
+// Copy the pairs, closing both engines before their files are moved.
+fn migrate_to<S: KvEngine, T: KvEngine>(source: S, target: T) -> Result<MigrationSummary> {
+    migrate(&source, &target)
+}
+
+// Replace the files of the source engine with the staged ones of the target.
+//
+// The manifest of the target is stored last, so until then the directory
+// still belongs to the source engine, whose files are in the retired directory.
+// Storing it also drops a `meta` file left next to the manifest of the source.
+// The directory is synced after every step, and the retired files are only
+// removed once the new manifest is durable.
+fn swap_engine(dir: &Path, from: EngineKind) -> Result<()> {
+    let staging = dir.join(STAGING_DIR_NAME);
+    let retired = dir.join(RETIRED_DIR_NAME);
+    fs::create_dir_all(&retired)?;
+    sync_dir(dir)?;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if is_engine_file(from, &entry.file_name().to_string_lossy()) {
+            fs::rename(entry.path(), retired.join(entry.file_name()))?;
+        }
+    }
+    sync_dir(&retired)?;
+    sync_dir(dir)?;
+    for entry in fs::read_dir(&staging)? {
+        let entry = entry?;
+        if entry.file_name() != MANIFEST_FILE_NAME {
+            fs::rename(entry.path(), dir.join(entry.file_name()))?;
+        }
+    }
+    sync_dir(dir)?;
+    let manifest = Manifest::load(&staging)?
+        .ok_or_else(|| KvsError::Manifest("the staged engine has no manifest".to_owned()))?;
+    manifest.store(dir)?;
+    sync_dir(dir)?;
+    fs::remove_dir_all(staging)?;
+    fs::remove_dir_all(retired)?;
+    Ok(())
+}
+
+// Finish or undo a swap of the engine files interrupted by a crash.
+//
+// The retired directory holds the files of the source engine. If the manifest
+// still names that engine, the target manifest was never moved in, so the
+// staged files moved in so far are removed and the retired ones moved back.
+// Otherwise the swap only has to be cleaned up, and the manifest stored
+// again in case the crash came before the `meta` file was removed.
+fn recover_swap(dir: &Path) -> Result<()> {
+    let retired = dir.join(RETIRED_DIR_NAME);
+    if !retired.exists() {
+        return Ok(());
+    }
+    let engine = Manifest::load(dir)?
+        .map(|manifest| manifest.engine)
+        .unwrap_or(EngineKind::Kvs);
+    let mut retired_files = Vec::new();
+    for entry in fs::read_dir(&retired)? {
+        retired_files.push(entry?.file_name());
+    }
+    let needs_rollback = retired_files
+        .iter()
+        .any(|name| is_engine_file(engine, &name.to_string_lossy()));
+    if needs_rollback {
+        let target = match engine {
+            EngineKind::Kvs => EngineKind::Sled,
+            EngineKind::Sled => EngineKind::Kvs,
+        };
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if is_engine_file(target, &entry.file_name().to_string_lossy()) {
+                remove_entry(&entry.path())?;
+            }
+        }
+        for name in &retired_files {
+            fs::rename(retired.join(name), dir.join(name))?;
+        }
+        sync_dir(dir)?;
+        println!("Rolled back an interrupted migration from {}", engine);
+    } else {
+        if let Some(manifest) = Manifest::load(dir)? {
+            manifest.store(dir)?;
+        }
+        if !retired_files.is_empty() {
+            println!("Finished an interrupted migration to {}", engine);
+        }
+    }
+    let staging = dir.join(STAGING_DIR_NAME);
+    if staging.exists() {
+        fs::remove_dir_all(staging)?;
+    }
+    fs::remove_dir_all(retired)?;
+    Ok(())
+}
+
+// Remove the file, or the directory with its contents.
+fn remove_entry(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+// Sync the directory, so the renames in it survive a crash.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// Directories can not be synced on this platform.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+// Whether the engine owns the file of the data directory with the given name.
+fn is_engine_file(engine: EngineKind, name: &str) -> bool {
+    match engine {
+        EngineKind::Kvs => {
+            name == LOCK_FILE_NAME
+                || [".log", ".hint", ".temp", ".rekey"]
+                    .iter()
+                    .any(|suffix| name.ends_with(suffix))
+        }
+        EngineKind::Sled => {
+            name == "conf" || name == "db" || name == "blobs" || name.starts_with("snap.")
+        }
+    }
+}
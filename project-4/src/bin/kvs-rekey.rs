@@ -0,0 +1,58 @@
+use kvs::{EncryptionKey, EngineKind, KvsError, Manifest, MyKvStore, Result};
+use std::env::current_dir;
+use std::path::PathBuf;
+use std::process::exit;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-rekey",
+    about = "Rewrites the log of a stopped kvs server with a new encryption key"
+)]
+struct Opt {
+    #[structopt(
+        long,
+        help = "The key file the log is encrypted with now, leave out for a plaintext log",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    old_key_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "The key file to encrypt the log with, leave out to decrypt it",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    new_key_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "The data directory of the server, the current directory by default",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    dir: Option<PathBuf>,
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    let dir = match opt.dir {
+        Some(dir) => dir,
+        None => current_dir()?,
+    };
+    // Only the kvs engine encrypts its log.
+    if let Some(manifest) = Manifest::load(&dir)? {
+        if manifest.engine != EngineKind::Kvs {
+            return Err(KvsError::Unsupported("Encryption".to_owned()));
+        }
+    }
+    let old_key = opt.old_key_file.map(EncryptionKey::from_file).transpose()?;
+    let new_key = opt.new_key_file.map(EncryptionKey::from_file).transpose()?;
+    MyKvStore::rotate_key(dir, old_key.as_ref(), new_key.as_ref())
+}
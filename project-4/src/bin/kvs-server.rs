@@ -4,20 +4,26 @@ extern crate clap;
 extern crate slog;
 #[macro_use]
 extern crate slog_scope;
+use crossbeam::channel::{self, Receiver};
 use kvs::{
-    KvEngine, KvsServer, MyKvStore, RayonThreadPool, Result, SharedQueueThreadPool, SledKvs,
+    AsyncKvsServer, Durability, EncryptionKey, EngineKind, KvEngine, KvStoreOptions, KvsError,
+    KvsServer, Manifest, MyKvStore, RayonThreadPool, Result, SharedQueueThreadPool, SledKvs,
     ThreadPool,
 };
 use slog::Drain;
 use std::env::current_dir;
+use std::io;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::str::FromStr;
+use std::time::Duration;
 use std::{env, fs};
 use structopt::StructOpt;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_THREAD_POOL_SIZE: &str = "8";
+const DEFAULT_CACHE_SIZE: u64 = 64 * 1024 * 1024;
 
 arg_enum! {
     #[allow(non_camel_case_types)]
@@ -37,6 +43,31 @@ arg_enum! {
     }
 }
 
+// `async` is a keyword, so this one is not an `arg_enum!`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Runtime {
+    Sync,
+    Async,
+}
+
+impl Runtime {
+    fn variants() -> [&'static str; 2] {
+        ["sync", "async"]
+    }
+}
+
+impl FromStr for Runtime {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "sync" => Ok(Runtime::Sync),
+            "async" => Ok(Runtime::Async),
+            _ => Err(format!("valid values: {}", Runtime::variants().join(", "))),
+        }
+    }
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "kvs-server")]
 struct Opt {
@@ -69,20 +100,76 @@ struct Opt {
         raw(default_value = "DEFAULT_THREAD_POOL_SIZE")
     )]
     thread_pool_size: String,
+    #[structopt(
+        long,
+        help = "Serve clients from a thread pool (sync) or from tokio tasks (async). \
+                The async runtime runs engine calls on up to SIZE blocking threads.",
+        value_name = "RUNTIME",
+        raw(possible_values = "&Runtime::variants()")
+    )]
+    runtime: Option<Runtime>,
+    #[structopt(
+        long,
+        help = "When writes are synced to disk: none, write (every write), \
+                group (every write, sharing syncs) or an interval such as 100ms \
+                [default: none with kvs, write with sled]",
+        value_name = "DURABILITY",
+        parse(try_from_str = "parse_durability")
+    )]
+    durability: Option<Durability>,
+    #[structopt(
+        long,
+        help = "The size in bytes of the cache of recently read values, \
+                or of the page cache with sled [default: 67108864 with kvs, \
+                the default of sled with sled]",
+        value_name = "BYTES"
+    )]
+    cache_size: Option<u64>,
+    #[structopt(
+        long,
+        help = "Encrypt the log records of the kvs engine with the key in this file, \
+                given as 64 hex digits",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    key_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Copy a backup into the data directory before starting, \
+                which must not hold a store yet",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    restore_from: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Let clients back up the store to directories under this one, \
+                backup requests are refused without it",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    backup_dir: Option<PathBuf>,
 }
 
 fn main() {
     let mut opt = Opt::from_args();
-    let res = current_engine().and_then(move |engine| {
-        if opt.engine.is_none() {
-            opt.engine = engine;
-        }
-        if engine.is_some() && opt.engine != engine {
-            eprintln!("Error: the wrong engine name");
-            exit(1);
-        }
-        run(opt)
-    });
+    let res = restore_backup(opt.restore_from.as_ref())
+        .and_then(|_| current_engine())
+        .and_then(move |engine| {
+            if opt.engine.is_none() {
+                opt.engine = engine;
+            }
+            if let Some(engine) = engine {
+                if opt.engine != Some(engine) {
+                    eprintln!(
+                        "Error: the wrong engine name, the data directory holds a {} store",
+                        engine
+                    );
+                    exit(1);
+                }
+            }
+            run(opt)
+        });
     if let Err(e) = res {
         eprintln!("{}", e);
         exit(1);
@@ -97,72 +184,222 @@ fn run(opt: Opt) -> Result<()> {
     let _guard = slog_scope::set_global_logger(logger);
 
     let engine = opt.engine.unwrap_or(Engine::kvs);
+    let runtime = opt.runtime.unwrap_or(Runtime::Sync);
     let thread_pool = opt.thread_pool.unwrap_or(Pool::shared);
     let thread_pool_size: u32 = opt.thread_pool_size.parse().unwrap();
+    // Sled flushed every write before the modes existed, kvs left the syncing to the OS.
+    let durability = opt.durability.unwrap_or(match engine {
+        Engine::kvs => Durability::None,
+        Engine::sled => Durability::EveryWrite,
+    });
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
     info!("Listening on {}", opt.addr);
+    info!("Durability: {:?}", durability);
+    // The cache size only applies to sled when it is given.
+    let cache_size = match engine {
+        Engine::kvs => Some(opt.cache_size.unwrap_or(DEFAULT_CACHE_SIZE)),
+        Engine::sled => opt.cache_size,
+    };
+    match cache_size {
+        Some(cache_size) => info!("Cache size: {} bytes", cache_size),
+        None => info!("Cache size: the default of sled"),
+    }
+    info!("Encryption: {}", opt.key_file.is_some());
+    if let Some(ref backup) = opt.restore_from {
+        info!("Restored from {}", backup.display());
+    }
+    if let Some(ref backup_dir) = opt.backup_dir {
+        info!("Backups under {}", backup_dir.display());
+    }
 
+    let encryption_key = match opt.key_file {
+        Some(ref key_file) if engine == Engine::kvs => Some(EncryptionKey::from_file(key_file)?),
+        Some(_) => return Err(KvsError::Unsupported("Encryption".to_owned())),
+        None => None,
+    };
+
+    // The engines record themselves in the manifest of the directory.
     let current_dir_path = current_dir()?;
-    write_engine_meta(&current_dir_path, engine)?;
+    let signal = shutdown_signal()?;
+    let backup_dir = opt.backup_dir.clone();
+    let kvs_options = KvStoreOptions::default()
+        .durability(durability)
+        .cache_size(cache_size.unwrap_or(DEFAULT_CACHE_SIZE) as usize)
+        .encryption_key(encryption_key);
+
+    if runtime == Runtime::Async {
+        if opt.thread_pool.is_some() {
+            warn!("The thread pool is not used by the async runtime");
+        }
+        return match engine {
+            Engine::kvs => start_async(
+                AsyncKvsServer::new(MyKvStore::open_with_options(
+                    current_dir_path,
+                    kvs_options.clone(),
+                )?),
+                opt.addr,
+                backup_dir,
+                thread_pool_size,
+                signal,
+            ),
+            Engine::sled => start_async(
+                AsyncKvsServer::new(SledKvs::open(current_dir_path, durability, cache_size)?),
+                opt.addr,
+                backup_dir,
+                thread_pool_size,
+                signal,
+            ),
+        };
+    }
 
     match (engine, thread_pool) {
         (Engine::kvs, Pool::shared) => start_engine(
             KvsServer::new(
-                MyKvStore::open(current_dir_path)?,
+                MyKvStore::open_with_options(current_dir_path, kvs_options.clone())?,
                 SharedQueueThreadPool::new(thread_pool_size)?,
             ),
             opt.addr,
+            backup_dir,
+            signal,
         )?,
         (Engine::kvs, Pool::rayon) => start_engine(
             KvsServer::new(
-                MyKvStore::open(current_dir_path)?,
+                MyKvStore::open_with_options(current_dir_path, kvs_options.clone())?,
                 RayonThreadPool::new(thread_pool_size)?,
             ),
             opt.addr,
+            backup_dir,
+            signal,
         )?,
         (Engine::sled, Pool::shared) => start_engine(
             KvsServer::new(
-                SledKvs::new(sled::open(current_dir_path)?),
+                SledKvs::open(current_dir_path, durability, cache_size)?,
                 SharedQueueThreadPool::new(thread_pool_size)?,
             ),
             opt.addr,
+            backup_dir,
+            signal,
         )?,
         (Engine::sled, Pool::rayon) => start_engine(
             KvsServer::new(
-                SledKvs::new(sled::open(current_dir_path)?),
+                SledKvs::open(current_dir_path, durability, cache_size)?,
                 RayonThreadPool::new(thread_pool_size)?,
             ),
             opt.addr,
+            backup_dir,
+            signal,
         )?,
     };
 
     Ok(())
 }
 
-// Start engine with address.
-fn start_engine<E: KvEngine, P: ThreadPool>(
+// Start engine with address and backup directory, until a shutdown signal.
+fn start_engine<E: KvEngine, P: ThreadPool + Send + 'static>(
     server: KvsServer<E, P>,
     addr: SocketAddr,
+    backup_dir: Option<PathBuf>,
+    signal: Receiver<()>,
 ) -> Result<()> {
-    server.start(addr)
+    let server = match backup_dir {
+        Some(backup_dir) => server.backup_root(backup_dir),
+        None => server,
+    };
+    let handle = server.start(addr)?;
+    wait_for_signal(&signal);
+    handle.shutdown()
 }
 
-// Write engine name to meta file.
-fn write_engine_meta(current_dir_path: &PathBuf, engine_name: Engine) -> Result<()> {
-    fs::write(current_dir_path.join("meta"), format!("{}", engine_name))?;
-    Ok(())
+// Start the async server on a tokio runtime, with `blocking_threads` for the engine calls.
+fn start_async<E: KvEngine>(
+    server: AsyncKvsServer<E>,
+    addr: SocketAddr,
+    backup_dir: Option<PathBuf>,
+    blocking_threads: u32,
+    signal: Receiver<()>,
+) -> Result<()> {
+    let server = match backup_dir {
+        Some(backup_dir) => server.backup_root(backup_dir),
+        None => server,
+    };
+    let core_threads = num_cpus::get();
+    let mut runtime = tokio::runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .core_threads(core_threads)
+        .max_threads(core_threads + blocking_threads as usize)
+        .build()?;
+    let handle = runtime.block_on(server.start(addr))?;
+    wait_for_signal(&signal);
+    runtime.block_on(handle.shutdown())
 }
 
-// Get current engine name from meta file.
-fn current_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join("meta");
-    if !engine.exists() {
-        return Ok(None);
+// Returns a channel receiving SIGINT and SIGTERM.
+fn shutdown_signal() -> Result<Receiver<()>> {
+    let (sender, receiver) = channel::bounded(1);
+    ctrlc::set_handler(move || {
+        let _ = sender.try_send(());
+    })
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    Ok(receiver)
+}
+
+fn wait_for_signal(signal: &Receiver<()>) {
+    let _ = signal.recv();
+    info!("Shutting down, finishing the requests in flight");
+}
+
+// Parse a durability mode, an interval is a number of milliseconds.
+fn parse_durability(s: &str) -> std::result::Result<Durability, String> {
+    match s {
+        "none" => Ok(Durability::None),
+        "write" => Ok(Durability::EveryWrite),
+        "group" => Ok(Durability::GroupCommit),
+        _ => s
+            .strip_suffix("ms")
+            .and_then(|ms| ms.parse().ok())
+            .filter(|&ms| ms > 0)
+            .map(|ms| Durability::Interval(Duration::from_millis(ms)))
+            .ok_or_else(|| "valid values: none, write, group, <N>ms".to_owned()),
+    }
+}
+
+// Copy a backup into the current directory, unless it holds a store already.
+fn restore_backup(backup: Option<&PathBuf>) -> Result<()> {
+    let backup = match backup {
+        Some(backup) => backup,
+        None => return Ok(()),
+    };
+    let current_dir_path = current_dir()?;
+    if Manifest::load(&current_dir_path)?.is_some() {
+        return Err(KvsError::InvalidInput(
+            "restore into a data directory already holding a store".to_owned(),
+        ));
     }
+    copy_dir(backup, &current_dir_path)
+}
 
-    match fs::read_to_string(engine)?.parse() {
-        Ok(engine) => Ok(Some(engine)),
-        Err(_) => Ok(None),
+// Copy the files of a directory and of its subdirectories.
+fn copy_dir(src: &Path, dst: &Path) -> Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let dst = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &dst)?;
+        } else {
+            fs::copy(entry.path(), dst)?;
+        }
     }
+    Ok(())
+}
+
+// Get the engine of the current directory from its manifest.
+fn current_engine() -> Result<Option<Engine>> {
+    let manifest = Manifest::load(&current_dir()?)?;
+    Ok(manifest.map(|manifest| match manifest.engine {
+        EngineKind::Kvs => Engine::kvs,
+        EngineKind::Sled => Engine::sled,
+    }))
 }
@@ -1,65 +1,191 @@
+use std::collections::HashMap;
 use std::io::{BufReader, BufWriter, Write};
 use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::Duration;
 
 use crate::error::KvsError;
-use crate::request::Request;
-use crate::response::{GetResponse, RemoveResponse, SetResponse};
-use crate::Result;
-use serde::Deserialize;
-use serde_json::de::IoRead;
-use serde_json::Deserializer;
+use crate::protocol::{read_frame, read_hello, write_frame, write_hello, Codec, PROTOCOL_VERSION};
+use crate::{Request, Response, Result, WriteBatch};
 
 /// The client of key value store.
+///
+/// Requests can be pipelined: `send` queues requests without waiting, and
+/// `receive` waits for the response of a given request.
+///
+/// ```rust,no_run
+/// # use kvs::{KvsClient, Request, Response, Result};
+/// # fn try_main() -> Result<()> {
+/// let mut client = KvsClient::init("127.0.0.1:4000")?;
+/// let ids = (0..10)
+///     .map(|i| client.send(Request::Get { key: vec![i] }))
+///     .collect::<Result<Vec<_>>>()?;
+/// for id in ids {
+///     if let Response::Value(Some(value)) = client.receive(id)? {
+///         println!("{:?}", value);
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    reader: BufReader<TcpStream>,
     writer: BufWriter<TcpStream>,
+    codec: Codec,
+    next_id: u64,
+    // Responses which arrived while waiting for another one.
+    received: HashMap<u64, Response>,
 }
 
 impl KvsClient {
-    /// Init client connect.
+    /// Init client connect, with the binary codec.
     pub fn init<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        KvsClient::init_with_codec(addr, Codec::Bincode)
+    }
+
+    /// Init client connect, asking the server for the given codec.
+    ///
+    /// The server may fall back to json.
+    pub fn init_with_codec<A: ToSocketAddrs>(addr: A, codec: Codec) -> Result<Self> {
         let reader_streaming = TcpStream::connect(addr)?;
         let writer_streaming = reader_streaming.try_clone()?;
+        let mut reader = BufReader::new(reader_streaming);
+        let mut writer = BufWriter::new(writer_streaming);
+
+        write_hello(&mut writer, PROTOCOL_VERSION, codec)?;
+        let (version, codec) = read_hello(&mut reader)?;
+        if version == 0 || version > PROTOCOL_VERSION {
+            return Err(KvsError::Protocol(format!(
+                "unsupported protocol version {}",
+                version
+            )));
+        }
         Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(reader_streaming)),
-            writer: BufWriter::new(writer_streaming),
+            reader,
+            writer,
+            codec,
+            next_id: 0,
+            received: HashMap::new(),
         })
     }
 
-    /// Get the value of key from server.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        self.send_request(&Request::Get { key })?;
-        let response = GetResponse::deserialize(&mut self.reader)?;
-        match response {
-            GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(msg) => Err(KvsError::ResponseError(msg)),
+    /// Returns the codec negotiated with the server.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Queues a request without waiting for its response.
+    ///
+    /// Returns the id to `receive` the response with.
+    pub fn send(&mut self, request: Request) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        write_frame(&mut self.writer, self.codec, id, &request)?;
+        Ok(id)
+    }
+
+    /// Sends the queued requests and waits for the response to the given one.
+    pub fn receive(&mut self, id: u64) -> Result<Response> {
+        self.writer.flush()?;
+        if let Some(response) = self.received.remove(&id) {
+            return Ok(response);
+        }
+        loop {
+            match read_frame(&mut self.reader, self.codec)? {
+                Some((received_id, response)) if received_id == id => return Ok(response),
+                Some((received_id, response)) => {
+                    self.received.insert(received_id, response);
+                }
+                None => return Err(KvsError::Protocol("connection closed by server".to_owned())),
+            }
         }
     }
 
+    /// Get the value of key from server.
+    pub fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.call(Request::Get { key })?.into_value()
+    }
+
     /// Set the value to server.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.send_request(&Request::Set { key, value })?;
-        let response = SetResponse::deserialize(&mut self.reader)?;
-        match response {
-            SetResponse::Ok(()) => Ok(()),
-            SetResponse::Err(msg) => Err(KvsError::ResponseError(msg)),
-        }
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.send_set_request(key, value, None)
+    }
+
+    /// Set the value of key on server, to expire after `ttl`.
+    pub fn set_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.send_set_request(key, value, Some(ttl))
+    }
+
+    fn send_set_request(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        self.call(Request::Set { key, value, ttl })?.into_done()
     }
 
     /// Remove the key value from server.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        self.send_request(&Request::Remove { key })?;
-        self.writer.flush()?;
-        let response = RemoveResponse::deserialize(&mut self.reader)?;
-        match response {
-            RemoveResponse::Ok(()) => Ok(()),
-            RemoveResponse::Err(msg) => Err(KvsError::ResponseError(msg)),
-        }
+    pub fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        self.call(Request::Remove { key })?.into_done()
     }
-    // Send request to server.
-    fn send_request(&mut self, request: &Request) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, request)?;
-        self.writer.flush()?;
-        Ok(())
+
+    /// Apply every write of the batch atomically on server.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.call(Request::Batch { batch })?.into_done()
+    }
+
+    /// Set the value of a key to `new` on server if its current value is `expected`.
+    ///
+    /// Returns `false` if the current value is not the expected one.
+    pub fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.call(Request::CompareAndSwap { key, expected, new })?
+            .into_swapped()
+    }
+
+    /// Set the value of a key to a value on server if the key does not exist.
+    ///
+    /// Returns `false` if the key already exists.
+    pub fn set_if_absent(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Get up to `limit` key/value pairs with keys in `[start, end)` from server.
+    pub fn scan(
+        &mut self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.call(Request::Scan { start, end, limit })?.into_pairs()
+    }
+
+    /// Get up to `limit` key/value pairs whose key starts with `prefix` from server.
+    pub fn scan_prefix(
+        &mut self,
+        prefix: Vec<u8>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.call(Request::ScanPrefix { prefix, limit })?
+            .into_pairs()
+    }
+
+    /// Back up the data of server to the directory `dest` on server.
+    ///
+    /// `dest` is relative to the backup root of the server.
+    pub fn backup(&mut self, dest: impl Into<PathBuf>) -> Result<()> {
+        self.call(Request::Backup { dest: dest.into() })?
+            .into_done()
+    }
+
+    // Send the request and wait for its response.
+    fn call(&mut self, request: Request) -> Result<Response> {
+        let id = self.send(request)?;
+        self.receive(id)
     }
 }
@@ -0,0 +1,188 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::engine_kvs::kvs_command::CommandPos;
+
+// Number of independently locked shards, so readers of different keys rarely contend.
+const SHARD_COUNT: usize = 16;
+
+/// Hit and miss counters of the value cache of a `MyKvStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// Number of reads served from the cache.
+    pub hits: u64,
+    /// Number of reads that went to the log.
+    pub misses: u64,
+}
+
+/// A bounded LRU cache of values, split into shards by key.
+///
+/// Every value is cached along with the position of the record it was read
+/// from, and is only served while the index still points at that record.
+/// So an entry missed by an invalidation is never served stale.
+pub struct ValueCache {
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Shard {
+    entries: HashMap<Vec<u8>, CachedValue>,
+    // Keys by the tick of their last use, the least recently used first.
+    lru: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    // Bytes of the keys and values held.
+    size: usize,
+    capacity: usize,
+}
+
+struct CachedValue {
+    pos: CommandPos,
+    value: Vec<u8>,
+    tick: u64,
+}
+
+impl ValueCache {
+    /// Creates a cache holding up to `capacity` bytes of keys and values.
+    ///
+    /// A capacity of 0 disables the cache.
+    pub fn new(capacity: usize) -> ValueCache {
+        let shard_capacity = capacity / SHARD_COUNT;
+        let shards = if shard_capacity == 0 {
+            Vec::new()
+        } else {
+            (0..SHARD_COUNT)
+                .map(|_| Mutex::new(Shard::new(shard_capacity)))
+                .collect()
+        };
+        ValueCache {
+            shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the value of the key if it was cached from the record at `pos`.
+    pub fn get(&self, key: &[u8], pos: CommandPos) -> Option<Vec<u8>> {
+        let shard = self.shard(key)?;
+        let value = shard.lock().unwrap().get(key, pos);
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// Caches the value of the key read from the record at `pos`.
+    pub fn insert(&self, key: &[u8], pos: CommandPos, value: &[u8]) {
+        if let Some(shard) = self.shard(key) {
+            shard.lock().unwrap().insert(key, pos, value);
+        }
+    }
+
+    /// Drops the cached value of the key.
+    pub fn invalidate(&self, key: &[u8]) {
+        if let Some(shard) = self.shard(key) {
+            shard.lock().unwrap().remove(key);
+        }
+    }
+
+    /// Follows a record of the key moved by a compaction from `old_pos` to `new_pos`.
+    ///
+    /// The value is dropped if the compaction dropped the record.
+    pub fn moved(&self, key: &[u8], old_pos: CommandPos, new_pos: Option<CommandPos>) {
+        if let Some(shard) = self.shard(key) {
+            let mut shard = shard.lock().unwrap();
+            let cached_pos = shard.entries.get(key).map(|cached| cached.pos);
+            if cached_pos != Some(old_pos) {
+                return;
+            }
+            match new_pos {
+                Some(new_pos) => shard.entries.get_mut(key).unwrap().pos = new_pos,
+                None => shard.remove(key),
+            }
+        }
+    }
+
+    /// Returns the hit and miss counters.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> Option<&Mutex<Shard>> {
+        if self.shards.is_empty() {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        Some(&self.shards[hasher.finish() as usize % self.shards.len()])
+    }
+}
+
+impl Shard {
+    fn new(capacity: usize) -> Shard {
+        Shard {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+            capacity,
+        }
+    }
+
+    fn get(&mut self, key: &[u8], pos: CommandPos) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let cached = self
+            .entries
+            .get_mut(key)
+            .filter(|cached| cached.pos == pos)?;
+        let key = self
+            .lru
+            .remove(&cached.tick)
+            .expect("cached key not in the LRU");
+        self.lru.insert(tick, key);
+        cached.tick = tick;
+        Some(cached.value.clone())
+    }
+
+    fn insert(&mut self, key: &[u8], pos: CommandPos, value: &[u8]) {
+        let size = key.len() + value.len();
+        if size > self.capacity {
+            return;
+        }
+        self.remove(key);
+        while self.size + size > self.capacity {
+            let (_, oldest) = self.lru.iter().next().expect("cache size without entries");
+            let oldest = oldest.clone();
+            self.remove(&oldest);
+        }
+
+        self.tick += 1;
+        self.lru.insert(self.tick, key.to_vec());
+        self.entries.insert(
+            key.to_vec(),
+            CachedValue {
+                pos,
+                value: value.to_vec(),
+                tick: self.tick,
+            },
+        );
+        self.size += size;
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(cached) = self.entries.remove(key) {
+            self.lru.remove(&cached.tick);
+            self.size -= key.len() + cached.value.len();
+        }
+    }
+}
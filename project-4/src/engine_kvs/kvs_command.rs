@@ -1,35 +1,148 @@
 use serde::{Deserialize, Serialize};
 use std::ops::Range;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Struct representing a command.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    /// Sets a key that expires at `expires_at`, in milliseconds since the Unix epoch.
+    SetExpiring {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        expires_at: u64,
+    },
+    /// Commands written as a single record, so they are replayed all or none.
+    Batch { cmds: Vec<Command> },
+    /// A command with the sequence number of its write, in every record from format version 4 on.
+    Sequenced { seq: u64, cmd: Box<Command> },
 }
 
 impl Command {
-    pub fn set(key: String, value: String) -> Command {
+    pub fn set(key: Vec<u8>, value: Vec<u8>) -> Command {
         Command::Set { key, value }
     }
 
-    pub fn remove(key: String) -> Command {
+    pub fn set_expiring(key: Vec<u8>, value: Vec<u8>, expires_at: u64) -> Command {
+        Command::SetExpiring {
+            key,
+            value,
+            expires_at,
+        }
+    }
+
+    pub fn remove(key: Vec<u8>) -> Command {
         Command::Remove { key }
     }
+
+    pub fn batch(cmds: Vec<Command>) -> Command {
+        Command::Batch { cmds }
+    }
+
+    pub fn sequenced(seq: u64, cmd: Command) -> Command {
+        Command::Sequenced {
+            seq,
+            cmd: Box::new(cmd),
+        }
+    }
+
+    /// Returns the sequence number of the write, 0 for records written before them.
+    pub fn seq(&self) -> u64 {
+        match self {
+            Command::Sequenced { seq, .. } => *seq,
+            _ => 0,
+        }
+    }
+
+    /// Returns the keys written by this command.
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            Command::Set { key, .. }
+            | Command::SetExpiring { key, .. }
+            | Command::Remove { key } => vec![key],
+            Command::Batch { cmds } => cmds.iter().flat_map(Command::keys).collect(),
+            Command::Sequenced { cmd, .. } => cmd.keys(),
+        }
+    }
+
+    /// Returns the value this command sets the key to, if any.
+    pub fn into_value(self, key: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Command::Set { key: k, value } if k == key => Some(value),
+            Command::SetExpiring { key: k, value, .. } if k == key => Some(value),
+            Command::Batch { cmds } => cmds.into_iter().rev().find_map(|cmd| cmd.into_value(key)),
+            Command::Sequenced { cmd, .. } => cmd.into_value(key),
+            _ => None,
+        }
+    }
+}
+
+/// A command of the json log format written by earlier versions, with string keys and values.
+#[derive(Deserialize)]
+pub enum JsonCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl From<JsonCommand> for Command {
+    fn from(cmd: JsonCommand) -> Self {
+        match cmd {
+            JsonCommand::Set { key, value } => Command::set(key.into_bytes(), value.into_bytes()),
+            JsonCommand::Remove { key } => Command::remove(key.into_bytes()),
+        }
+    }
 }
 
-/// Represents the position and length of a json-serialized command in the log.
-#[derive(Debug, Clone, Copy)]
+/// Represents the position and length of the record of a command in the log.
+///
+/// `gen` is the generation number of the log segment the command lives in.
+/// `expires_at` is kept along so expired keys are found without reading the log.
+/// `seq` is the sequence number of the write, telling which snapshots see it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct CommandPos {
+    pub gen: u64,
     pub pos: u64,
     pub len: u64,
+    pub expires_at: Option<u64>,
+    pub seq: u64,
+}
+
+impl CommandPos {
+    /// Returns `true` if the key set by the command has expired at `now`.
+    pub fn is_expired(&self, now: u64) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= now,
+            None => false,
+        }
+    }
 }
 
-impl From<Range<u64>> for CommandPos {
-    fn from(range: Range<u64>) -> Self {
+impl From<(u64, Range<u64>)> for CommandPos {
+    fn from((gen, range): (u64, Range<u64>)) -> Self {
         CommandPos {
+            gen,
             pos: range.start,
             len: range.end - range.start,
+            expires_at: None,
+            seq: 0,
         }
     }
 }
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
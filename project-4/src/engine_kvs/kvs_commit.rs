@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::sync::{Condvar, Mutex};
+
+use crate::engine_kvs::kvs_command::{Command, CommandPos};
+use crate::engine_kvs::kvs_writer::KvStoreWriter;
+use crate::{KvsError, Result};
+
+/// Where a queued command was written.
+#[derive(Debug, Clone, Copy)]
+pub struct Committed {
+    /// Position of the record holding the command.
+    pub pos: CommandPos,
+    /// Number of the log write the record was flushed with, to wait for its sync.
+    pub seq: u64,
+}
+
+/// Queue of the commands waiting for the writer, so concurrent writes share one flush.
+///
+/// The first thread finding no leader leads the next group: it takes the
+/// writer lock, drains every command queued meanwhile and writes them with a
+/// single flush. The other threads wait until the leader hands them their result.
+pub struct CommitQueue {
+    state: Mutex<QueueState>,
+    done_cond: Condvar,
+}
+
+struct QueueState {
+    // Ticket of the next command queued.
+    next_ticket: u64,
+    // The commands not taken by a leader yet.
+    pending: Vec<(u64, Command)>,
+    // Whether a thread is writing a group right now.
+    leading: bool,
+    // Results of the written commands, until their thread picks them up.
+    done: HashMap<u64, Result<Committed>>,
+}
+
+impl CommitQueue {
+    pub fn new() -> CommitQueue {
+        CommitQueue {
+            state: Mutex::new(QueueState {
+                next_ticket: 0,
+                pending: Vec::new(),
+                leading: false,
+                done: HashMap::new(),
+            }),
+            done_cond: Condvar::new(),
+        }
+    }
+
+    /// Queues the command and waits until it is written to the log.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the command removes a missing key.
+    ///
+    /// It returns the error of a command whose record can not be encoded,
+    /// and propagates I/O errors of the group the command was written in.
+    pub fn submit(&self, cmd: Command, writer: &Mutex<KvStoreWriter>) -> Result<Committed> {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.pending.push((ticket, cmd));
+        loop {
+            if let Some(result) = state.done.remove(&ticket) {
+                return result;
+            }
+            if state.leading {
+                state = self.done_cond.wait(state).unwrap();
+                continue;
+            }
+
+            // Lead a group, the commands keep piling up until the writer is ours.
+            state.leading = true;
+            drop(state);
+            let mut writer = writer.lock().unwrap();
+            let group = mem::take(&mut self.state.lock().unwrap().pending);
+            let (tickets, cmds): (Vec<u64>, Vec<Command>) = group.into_iter().unzip();
+            let written = writer.write_group(cmds);
+            let seq = writer.log_sync.written();
+            drop(writer);
+
+            state = self.state.lock().unwrap();
+            state.leading = false;
+            match written {
+                Ok(positions) => {
+                    for (ticket, pos) in tickets.into_iter().zip(positions) {
+                        let result = pos.map(|pos| Committed { pos, seq });
+                        state.done.insert(ticket, result);
+                    }
+                }
+                Err(e) => {
+                    // The error itself goes to the leader, the others learn why from its message.
+                    for &other in tickets.iter().filter(|&&other| other != ticket) {
+                        let err = io::Error::new(
+                            io::ErrorKind::Other,
+                            format!("group commit failed: {}", e),
+                        );
+                        state.done.insert(other, Err(KvsError::Io(err)));
+                    }
+                    state.done.insert(ticket, Err(e));
+                }
+            }
+            self.done_cond.notify_all();
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use crossbeam_skiplist::SkipMap;
+
+use crate::engine_kvs::kvs_cache::ValueCache;
+use crate::engine_kvs::kvs_command::{now_millis, CommandPos};
+use crate::engine_kvs::kvs_hint::{hint_path, write_hint_file};
+use crate::engine_kvs::kvs_reader::KvStoreReader;
+use crate::engine_kvs::kvs_record::{
+    read_stored_frame, write_log_header, write_stored_frame, Frame, RecordAddress,
+};
+use crate::engine_kvs::kvs_snapshot::VersionHistory;
+use crate::engine_kvs::kvs_writer::{BufWriterWithPos, KvStoreWriter};
+use crate::engine_kvs::my_kvs::{log_path, sorted_gen_list};
+use crate::{KvsError, Result};
+
+// Suffix of a compaction segment being written.
+const UNFINISHED_SUFFIX: &str = ".compact.temp";
+
+/// Sending side of the compaction channel, owned by the `KvStoreWriter`.
+///
+/// Dropping the handle disconnects the channel, so the thread leaves its loop.
+pub struct CompactorHandle {
+    sender: Sender<u64>,
+}
+
+impl CompactorHandle {
+    /// Hands the sealed segments below `compaction_gen` to the compaction thread.
+    ///
+    /// Returns `false` if the thread is still busy with a previous compaction.
+    pub fn try_schedule(&self, compaction_gen: u64) -> bool {
+        match self.sender.try_send(compaction_gen) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => false,
+            Err(TrySendError::Disconnected(_)) => {
+                error!("Compaction thread is gone");
+                false
+            }
+        }
+    }
+}
+
+/// The background compaction thread, shared by the clones of a `MyKvStore`.
+///
+/// The thread only holds a weak reference to the writer and never this value,
+/// so it is always dropped on another thread. It must be dropped after the
+/// writer: the thread exits once the writer and its `CompactorHandle` are gone,
+/// and dropping waits for it, so no stale segment is removed behind the back
+/// of a store reopened on the same directory.
+pub struct CompactorThread {
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for CompactorThread {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("Compaction thread panicked");
+            }
+        }
+    }
+}
+
+/// A key seen by a compaction, with its old and new record position.
+///
+/// The new position is `None` if the key expired and its record was dropped.
+type MovedEntry = (Vec<u8>, CommandPos, Option<CommandPos>);
+
+/// The entries of the index and the versions kept for snapshots a compaction moved.
+struct Moved {
+    entries: Vec<MovedEntry>,
+    versions: Vec<MovedEntry>,
+}
+
+/// Rewrites sealed log segments in the background while writers keep going.
+pub struct KvStoreCompactor {
+    path: Arc<PathBuf>,
+    reader: KvStoreReader,
+    index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+    cache: Arc<ValueCache>,
+    history: Arc<VersionHistory>,
+    // Held for the whole compaction, see `KvStoreWriter::compaction_lock`.
+    compaction_lock: Arc<Mutex<()>>,
+    // Only used to serialize the final index swap with the writers.
+    writer: Weak<Mutex<KvStoreWriter>>,
+    receiver: Receiver<u64>,
+}
+
+/// Creates the channel between the writer and the compaction thread.
+///
+/// The thread itself is started by `spawn_compactor` once the writer is shared.
+pub fn compaction_channel() -> (CompactorHandle, Receiver<u64>) {
+    // A rendezvous channel, so a compaction is only scheduled when the thread is idle.
+    let (sender, receiver) = channel::bounded(0);
+    (CompactorHandle { sender }, receiver)
+}
+
+/// Starts the compaction thread for the given writer.
+pub fn spawn_compactor(
+    writer: &Arc<Mutex<KvStoreWriter>>,
+    reader: KvStoreReader,
+    receiver: Receiver<u64>,
+) -> Result<CompactorThread> {
+    let guard = writer.lock().unwrap();
+    let compactor = KvStoreCompactor {
+        path: Arc::clone(&guard.path),
+        reader,
+        index: Arc::clone(&guard.index),
+        cache: Arc::clone(&guard.cache),
+        history: Arc::clone(&guard.history),
+        compaction_lock: Arc::clone(&guard.compaction_lock),
+        writer: Arc::downgrade(writer),
+        receiver,
+    };
+    let handle = thread::Builder::new()
+        .name("kvs-compactor".to_owned())
+        .spawn(move || compactor.run())?;
+    Ok(CompactorThread {
+        handle: Some(handle),
+    })
+}
+
+/// Removes the compaction segments a crash left unfinished.
+pub fn remove_unfinished_compactions(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(OsStr::to_str).unwrap_or("");
+        if name.ends_with(UNFINISHED_SUFFIX) {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+// Returns the path the compaction segment is written to until it is complete.
+fn unfinished_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}{}", gen, UNFINISHED_SUFFIX))
+}
+
+/// Removes the file, if it is still there.
+pub fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+impl KvStoreCompactor {
+    fn run(self) {
+        for compaction_gen in self.receiver.iter() {
+            if let Err(e) = self.compact(compaction_gen) {
+                error!("Compaction of generation {} failed: {}", compaction_gen, e);
+            }
+        }
+        debug!("Compaction thread exits because the store is closed.");
+    }
+
+    /// Copies the live entries of every segment below `compaction_gen` into the
+    /// compaction segment, swaps their positions in the index and removes the
+    /// stale segments.
+    fn compact(&self, compaction_gen: u64) -> Result<()> {
+        let _compaction = self.compaction_lock.lock().unwrap();
+        let moved = match self.copy_live_entries(compaction_gen) {
+            Ok(Some(moved)) => moved,
+            res => {
+                // Nothing points at the compaction segment yet, the sealed
+                // segments still hold every live record.
+                self.abandon(compaction_gen)?;
+                return res.map(|_| ());
+            }
+        };
+        let hints = moved
+            .entries
+            .iter()
+            .filter_map(|(key, _, new_pos)| new_pos.as_ref().map(|new_pos| (key, new_pos)));
+        if let Err(e) = write_hint_file(&self.path, compaction_gen, hints, &self.reader.codec) {
+            self.abandon(compaction_gen)?;
+            return Err(e);
+        }
+
+        if let Some(writer) = self.writer.upgrade() {
+            // Writers only touch the index under this lock, so an entry still at
+            // its old position has not been overwritten since the snapshot.
+            let _guard = writer.lock().unwrap();
+            // An entry replaced since it was copied may be kept for a snapshot.
+            for (key, old_pos, new_pos) in moved.entries.iter().chain(&moved.versions) {
+                self.history.moved(key, *old_pos, *new_pos);
+            }
+            for (key, old_pos, new_pos) in moved.entries {
+                if self.index.get(&key).map(|e| *e.value()) == Some(old_pos) {
+                    self.cache.moved(&key, old_pos, new_pos);
+                    match new_pos {
+                        Some(new_pos) => {
+                            self.index.insert(key, new_pos);
+                        }
+                        None => {
+                            self.index.remove(&key);
+                        }
+                    }
+                }
+            }
+        } else {
+            return self.abandon(compaction_gen);
+        }
+
+        self.reader
+            .safe_point
+            .store(compaction_gen, Ordering::SeqCst);
+
+        // Remove the stale log segments and their hint files.
+        let stale_gens = sorted_gen_list(&self.path)?
+            .into_iter()
+            .filter(|&gen| gen < compaction_gen);
+        for stale_gen in stale_gens {
+            fs::remove_file(log_path(&self.path, stale_gen))?;
+            remove_if_exists(&hint_path(&self.path, stale_gen))?;
+        }
+        Ok(())
+    }
+
+    /// Removes a compaction segment whose entries were never swapped into the index.
+    fn abandon(&self, compaction_gen: u64) -> Result<()> {
+        remove_if_exists(&hint_path(&self.path, compaction_gen))?;
+        remove_if_exists(&log_path(&self.path, compaction_gen))?;
+        remove_if_exists(&unfinished_path(&self.path, compaction_gen))?;
+        Ok(())
+    }
+
+    /// Writes the compaction segment, leaving out the expired keys.
+    ///
+    /// Returns the old and new position of every live entry and every version
+    /// kept for a snapshot, or `None` if the store was dropped in the meantime.
+    fn copy_live_entries(&self, compaction_gen: u64) -> Result<Option<Moved>> {
+        // The segment is written aside, so a crash never leaves a torn one behind.
+        let unfinished_path = unfinished_path(&self.path, compaction_gen);
+        let mut compaction_writer = BufWriterWithPos::new(File::create(&unfinished_path)?)?;
+        write_log_header(&mut compaction_writer)?;
+
+        // The writer no longer appends to sealed segments, so iterating the index
+        // gives a consistent snapshot of their live entries.
+        let mut entries = Vec::new();
+        // Keys written by one batch share a record, which is only copied once.
+        let mut copied: HashMap<CommandPos, CommandPos> = HashMap::new();
+        let now = now_millis();
+        for entry in self.index.iter() {
+            let old_pos = *entry.value();
+            if old_pos.gen >= compaction_gen {
+                continue;
+            }
+            if self.writer.strong_count() == 0 {
+                return Ok(None);
+            }
+            let new_pos = self.copy_record(
+                &mut compaction_writer,
+                compaction_gen,
+                old_pos,
+                &mut copied,
+                now,
+            )?;
+            entries.push((entry.key().clone(), old_pos, new_pos));
+        }
+        // A version is handed to the history before it leaves the index, so the
+        // versions replaced during the iteration above are all collected here.
+        let mut versions = Vec::new();
+        for (key, old_pos) in self.history.versions_below(compaction_gen) {
+            if self.writer.strong_count() == 0 {
+                return Ok(None);
+            }
+            let new_pos = self.copy_record(
+                &mut compaction_writer,
+                compaction_gen,
+                old_pos,
+                &mut copied,
+                now,
+            )?;
+            versions.push((key, old_pos, new_pos));
+        }
+        // The compacted segments are deleted once the index points here.
+        compaction_writer.sync()?;
+        fs::rename(unfinished_path, log_path(&self.path, compaction_gen))?;
+        Ok(Some(Moved { entries, versions }))
+    }
+
+    /// Copies the record at `old_pos` to the compaction segment, unless it was
+    /// copied already.
+    ///
+    /// Returns its new position, or `None` if it has expired and is left out.
+    fn copy_record(
+        &self,
+        compaction_writer: &mut BufWriterWithPos<File>,
+        compaction_gen: u64,
+        old_pos: CommandPos,
+        copied: &mut HashMap<CommandPos, CommandPos>,
+        now: u64,
+    ) -> Result<Option<CommandPos>> {
+        if old_pos.is_expired(now) {
+            return Ok(None);
+        }
+        if let Some(&new_pos) = copied.get(&old_pos) {
+            return Ok(Some(new_pos));
+        }
+        let pos = compaction_writer.pos;
+        // Compressed records stay so, encrypted ones are encrypted for their new place.
+        self.reader.read_and(old_pos, |mut record| {
+            let stored = match read_stored_frame(&mut record, old_pos.len)? {
+                Frame::Valid(stored) => stored,
+                Frame::Torn | Frame::Corrupted { .. } => {
+                    return Err(KvsError::CorruptedLog {
+                        gen: old_pos.gen,
+                        pos: old_pos.pos,
+                    })
+                }
+            };
+            let from = RecordAddress::Log {
+                gen: old_pos.gen,
+                pos: old_pos.pos,
+            };
+            let to = RecordAddress::Log {
+                gen: compaction_gen,
+                pos,
+            };
+            write_stored_frame(
+                compaction_writer,
+                &self.reader.codec.reseal(stored, from, to)?,
+            )
+        })?;
+        let new_pos = CommandPos {
+            gen: compaction_gen,
+            pos,
+            len: compaction_writer.pos - pos,
+            ..old_pos
+        };
+        copied.insert(old_pos, new_pos);
+        Ok(Some(new_pos))
+    }
+}
@@ -0,0 +1,112 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+
+use crate::{KvsError, Result};
+
+/// Length of an encryption key in bytes.
+const KEY_LEN: usize = 32;
+/// Length of the random nonce stored in front of every encrypted payload.
+const NONCE_LEN: usize = 24;
+
+/// A 256-bit key encrypting the log records of a `MyKvStore`.
+///
+/// ```rust
+/// # use kvs::EncryptionKey;
+/// let key = EncryptionKey::new([7; 32]);
+/// assert_eq!(format!("{:?}", key), "EncryptionKey(..)");
+/// ```
+#[derive(Clone)]
+pub struct EncryptionKey([u8; KEY_LEN]);
+
+impl EncryptionKey {
+    /// Creates a key from its raw bytes.
+    pub fn new(bytes: [u8; KEY_LEN]) -> EncryptionKey {
+        EncryptionKey(bytes)
+    }
+
+    /// Reads a key file holding the key as 64 hex digits.
+    ///
+    /// Surrounding whitespace is ignored, so a key file can be created with
+    /// `head -c 32 /dev/urandom | xxd -p -c 32 > kvs.key`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidInput` if the file does not hold a key.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<EncryptionKey> {
+        let path = path.as_ref();
+        let invalid = || KvsError::InvalidInput(format!("key file {}", path.display()));
+        let bytes = hex::decode(fs::read_to_string(path)?.trim()).map_err(|_| invalid())?;
+        if bytes.len() != KEY_LEN {
+            return Err(invalid());
+        }
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(&bytes);
+        Ok(EncryptionKey(key))
+    }
+}
+
+// The key itself never ends up in a log line.
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Authenticated encryption of record payloads with XChaCha20-Poly1305.
+///
+/// Every payload gets a fresh random nonce, which is long enough that nonces
+/// never repeat however many records are written with one key.
+pub struct LogCipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl LogCipher {
+    pub fn new(key: &EncryptionKey) -> LogCipher {
+        LogCipher {
+            aead: XChaCha20Poly1305::new(Key::from_slice(&key.0)),
+        }
+    }
+
+    /// Encrypts the payload, `aad` being authenticated along with it.
+    ///
+    /// Returns the nonce followed by the ciphertext and its tag.
+    pub fn seal(&self, payload: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self
+            .aead
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: payload, aad })
+            .map_err(|_| KvsError::Encryption("can not encrypt log record".to_owned()))?;
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypts a payload sealed by `seal` with the same `aad`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Encryption` if the key is wrong or the payload was tampered with.
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let fail = || KvsError::Encryption("wrong key or tampered log record".to_owned());
+        if sealed.len() < NONCE_LEN {
+            return Err(fail());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.aead
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| fail())
+    }
+}
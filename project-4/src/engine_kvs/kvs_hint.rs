@@ -0,0 +1,148 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+
+use crate::engine_kvs::kvs_command::CommandPos;
+use crate::engine_kvs::kvs_reader::BufReaderWithPos;
+use crate::engine_kvs::kvs_record::{read_frame, write_frame, Frame, RecordAddress, RecordCodec};
+use crate::engine_kvs::kvs_writer::BufWriterWithPos;
+use crate::Result;
+
+/// Magic bytes at the start of every hint file.
+const HINT_MAGIC: &[u8; 4] = b"KVSH";
+/// Version of the hint file format written by this build.
+///
+/// Hint files of other versions are ignored and their segments replayed.
+const HINT_VERSION: u32 = 3;
+
+/// The position of a live record in a compaction segment.
+#[derive(Serialize, Deserialize)]
+struct HintEntry {
+    #[serde(with = "serde_bytes")]
+    key: Vec<u8>,
+    pos: u64,
+    len: u64,
+    expires_at: Option<u64>,
+    seq: u64,
+}
+
+/// The keys of a compaction segment and the positions of their records.
+pub type Hints = Vec<(Vec<u8>, CommandPos)>;
+
+/// Returns the path of the hint file of the log segment with the given generation number.
+pub fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+/// Writes the hint file of a compaction segment.
+///
+/// The file is written aside and renamed, so a hint file is always complete.
+/// Its entries hold the keys, so they are encrypted like the records.
+pub fn write_hint_file<'a>(
+    dir: &Path,
+    gen: u64,
+    entries: impl Iterator<Item = (&'a Vec<u8>, &'a CommandPos)>,
+    codec: &RecordCodec,
+) -> Result<()> {
+    let path = hint_path(dir, gen);
+    let temp_path = path.with_extension("hint.temp");
+    write_hints(&temp_path, gen, entries, codec)?;
+    fs::rename(temp_path, path)?;
+    Ok(())
+}
+
+/// Writes the hint entries of log segment `gen` to a new file at `path`.
+pub fn write_hints<'a>(
+    path: &Path,
+    gen: u64,
+    entries: impl Iterator<Item = (&'a Vec<u8>, &'a CommandPos)>,
+    codec: &RecordCodec,
+) -> Result<()> {
+    let mut writer = BufWriterWithPos::new(File::create(path)?)?;
+    writer.write_all(HINT_MAGIC)?;
+    writer.write_all(&HINT_VERSION.to_le_bytes())?;
+    for (key, cmd_pos) in entries {
+        let entry = HintEntry {
+            key: key.clone(),
+            pos: cmd_pos.pos,
+            len: cmd_pos.len,
+            expires_at: cmd_pos.expires_at,
+            seq: cmd_pos.seq,
+        };
+        let at = RecordAddress::Hint {
+            gen,
+            pos: writer.pos,
+        };
+        write_frame(
+            &mut writer,
+            bincode::DefaultOptions::new().serialize(&entry)?,
+            codec,
+            at,
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the hint file of the log segment with the given generation number.
+///
+/// Returns `None` if there is no usable hint file and the segment has to be replayed.
+pub fn read_hint_file(
+    dir: &Path,
+    gen: u64,
+    segment_len: u64,
+    codec: &RecordCodec,
+) -> Result<Option<Hints>> {
+    let path = hint_path(dir, gen);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReaderWithPos::new(file)?;
+
+    let mut header = [0u8; 8];
+    if file_len < header.len() as u64 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header)?;
+    if &header[..4] != HINT_MAGIC || header[4..] != HINT_VERSION.to_le_bytes() {
+        warn!(
+            "Ignoring hint file of unknown format for log segment {}",
+            gen
+        );
+        return Ok(None);
+    }
+
+    let mut entries = Vec::new();
+    while reader.pos < file_len {
+        let remaining = file_len - reader.pos;
+        let at = RecordAddress::Hint {
+            gen,
+            pos: reader.pos,
+        };
+        let payload = match read_frame(&mut reader, remaining, codec, at)? {
+            Frame::Valid(payload) => payload,
+            Frame::Torn | Frame::Corrupted { .. } => {
+                warn!("Ignoring corrupted hint file for log segment {}", gen);
+                return Ok(None);
+            }
+        };
+        let entry: HintEntry = bincode::DefaultOptions::new().deserialize(&payload)?;
+        if entry.pos + entry.len > segment_len {
+            warn!("Ignoring hint file beyond the end of log segment {}", gen);
+            return Ok(None);
+        }
+        let cmd_pos = CommandPos {
+            expires_at: entry.expires_at,
+            seq: entry.seq,
+            ..(gen, entry.pos..entry.pos + entry.len).into()
+        };
+        entries.push((entry.key, cmd_pos));
+    }
+    Ok(Some(entries))
+}
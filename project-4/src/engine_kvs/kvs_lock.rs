@@ -0,0 +1,39 @@
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+use fs2::FileExt;
+
+use crate::{KvsError, Result};
+
+/// Name of the file locked by the store writing a data directory.
+pub const LOCK_FILE_NAME: &str = "LOCK";
+
+/// An exclusive advisory lock on a data directory, released when dropped.
+///
+/// The lock is taken on a `LOCK` file in the directory, which is left in
+/// place, so a process that crashes does not leave the directory locked.
+pub struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Locks the directory, without waiting for another holder.
+    ///
+    /// It returns `KvsError::Locked` if another open store holds the lock,
+    /// whether in this process or another one.
+    pub fn acquire(dir: &Path) -> Result<DirLock> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOCK_FILE_NAME))?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(DirLock { _file: file }),
+            Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+                Err(KvsError::Locked(dir.display().to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
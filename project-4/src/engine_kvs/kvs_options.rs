@@ -0,0 +1,140 @@
+use std::time::Duration;
+
+use crate::engine_kvs::kvs_crypto::EncryptionKey;
+
+/// How `MyKvStore::open` treats a corrupted record in the middle of a log segment.
+///
+/// A torn record at the end of a segment is truncated, since it is the
+/// expected result of a crash during a write. Valid records after it mean its
+/// length was corrupted instead, which `Strict` refuses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryMode {
+    /// Log the corrupted record, skip it and keep replaying the log.
+    Tolerant,
+    /// Refuse to open the store with `KvsError::CorruptedLog`.
+    Strict,
+}
+
+/// When `MyKvStore` syncs the writes to disk.
+///
+/// A write returns once it reached the OS in every mode, so it survives a
+/// crash of the process. The modes differ in what a crash of the machine
+/// loses. Sealed log segments are always synced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Leave the syncing to the OS, a crash may lose any recent write.
+    None,
+    /// Sync every write before it returns.
+    EveryWrite,
+    /// Sync in the background every interval, a crash may lose the writes of the last interval.
+    Interval(Duration),
+    /// Sync every write before it returns, with one sync for the writes waiting together.
+    GroupCommit,
+}
+
+/// Options to open a `MyKvStore` with.
+///
+/// ```rust
+/// # use kvs::{Durability, EncryptionKey, KvStoreOptions, RecoveryMode};
+/// # use std::time::Duration;
+/// let options = KvStoreOptions::default()
+///     .recovery_mode(RecoveryMode::Strict)
+///     .reap_interval(Duration::from_secs(10))
+///     .durability(Durability::GroupCommit)
+///     .mmap_reads(true)
+///     .cache_size(16 * 1024 * 1024)
+///     .compress_above(Some(4096))
+///     .encryption_key(Some(EncryptionKey::new([7; 32])))
+///     .read_only(false);
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(crate) recovery_mode: RecoveryMode,
+    pub(crate) reap_interval: Duration,
+    pub(crate) durability: Durability,
+    pub(crate) mmap_reads: bool,
+    pub(crate) cache_size: usize,
+    pub(crate) compress_above: Option<usize>,
+    pub(crate) encryption_key: Option<EncryptionKey>,
+    pub(crate) read_only: bool,
+}
+
+impl KvStoreOptions {
+    /// Sets how corrupted records are handled during the log replay.
+    pub fn recovery_mode(mut self, recovery_mode: RecoveryMode) -> Self {
+        self.recovery_mode = recovery_mode;
+        self
+    }
+
+    /// Sets how often expired keys are removed from memory.
+    ///
+    /// Expired keys are hidden from reads right away either way.
+    pub fn reap_interval(mut self, reap_interval: Duration) -> Self {
+        self.reap_interval = reap_interval;
+        self
+    }
+
+    /// Sets when writes are synced to disk.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets whether sealed log segments are read through a memory map.
+    ///
+    /// The values are then decoded right from the mapped pages, the active
+    /// segment is still read with positional reads.
+    pub fn mmap_reads(mut self, mmap_reads: bool) -> Self {
+        self.mmap_reads = mmap_reads;
+        self
+    }
+
+    /// Sets how many bytes of keys and values are cached in memory, 0 disables the cache.
+    pub fn cache_size(mut self, cache_size: usize) -> Self {
+        self.cache_size = cache_size;
+        self
+    }
+
+    /// Sets the size in bytes above which records are LZ4 compressed, `None` disables it.
+    ///
+    /// A record is only stored compressed if that makes it shorter.
+    pub fn compress_above(mut self, compress_above: Option<usize>) -> Self {
+        self.compress_above = compress_above;
+        self
+    }
+
+    /// Sets the key encrypting the log records, `None` writes them in plaintext.
+    ///
+    /// Records are encrypted after compression, hint files are encrypted too.
+    /// Encrypted records can only be read with the key they were written with,
+    /// see `MyKvStore::rotate_key` to change it.
+    pub fn encryption_key(mut self, encryption_key: Option<EncryptionKey>) -> Self {
+        self.encryption_key = encryption_key;
+        self
+    }
+
+    /// Sets whether the store is opened read-only.
+    ///
+    /// A read-only store does not lock the data directory, so it can be opened
+    /// alongside the store writing it. It reads the log as it was when opened,
+    /// later writes of the other store are not seen.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            recovery_mode: RecoveryMode::Tolerant,
+            reap_interval: Duration::from_secs(1),
+            durability: Durability::None,
+            mmap_reads: false,
+            cache_size: 64 * 1024 * 1024,
+            compress_above: None,
+            encryption_key: None,
+            read_only: false,
+        }
+    }
+}
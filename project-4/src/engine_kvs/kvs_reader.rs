@@ -1,38 +1,189 @@
-use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io;
 use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use memmap::Mmap;
 
 use crate::engine_kvs::kvs_command::{Command, CommandPos};
-use crate::engine_kvs::my_kvs::LOG_FILE_NAME;
-use crate::Result;
+use crate::engine_kvs::kvs_record::{read_record, RecordAddress, RecordCodec};
+use crate::engine_kvs::my_kvs::log_path;
+use crate::{KvsError, Result};
 
+/// Reads the log segments with positional reads, so the clones of a store
+/// share one file handle per segment across threads.
+///
+/// Sealed segments are never written again, so with `mmap` they are mapped
+/// into memory instead and records are decoded right from the mapped pages.
+#[derive(Clone)]
 pub struct KvStoreReader {
     pub path: Arc<PathBuf>,
-    pub reader: RefCell<BufReaderWithPos<File>>,
+    // Generation of the latest compaction file, segments below it are stale.
+    pub safe_point: Arc<AtomicU64>,
+    // Generation of the segment the writer appends to, segments below it are sealed.
+    active_gen: Arc<AtomicU64>,
+    // Whether sealed segments are read through a memory map.
+    mmap: bool,
+    // Decrypts and decompresses the records.
+    pub codec: RecordCodec,
+    // Lazily opened log segments by generation.
+    segments: Arc<RwLock<BTreeMap<u64, Arc<Segment>>>>,
+}
+
+// An open log segment.
+enum Segment {
+    // Read with positional reads.
+    File(File),
+    // A sealed segment mapped into memory.
+    Mapped(Mmap),
 }
 
 impl KvStoreReader {
+    pub fn new(
+        path: Arc<PathBuf>,
+        safe_point: Arc<AtomicU64>,
+        active_gen: Arc<AtomicU64>,
+        mmap: bool,
+        codec: RecordCodec,
+    ) -> KvStoreReader {
+        KvStoreReader {
+            path,
+            safe_point,
+            active_gen,
+            mmap,
+            codec,
+            segments: Arc::new(RwLock::new(BTreeMap::new())),
+        }
+    }
+
+    /// Close file handles of segments that have been removed by a compaction.
+    ///
+    /// A read in flight keeps its handle, and with it the removed file, until it is done.
+    fn close_stale_handles(&self) {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        let stale = |segments: &BTreeMap<u64, Arc<Segment>>| {
+            segments
+                .keys()
+                .next()
+                .map_or(false, |&gen| gen < safe_point)
+        };
+        if !stale(&self.segments.read().unwrap()) {
+            return;
+        }
+        let mut segments = self.segments.write().unwrap();
+        while stale(&segments) {
+            let gen = *segments.keys().next().unwrap();
+            segments.remove(&gen);
+        }
+    }
+
+    // Returns the segment, opening it on first use and mapping it once it is sealed.
+    //
+    // A segment removed by a compaction fails to open with `NotFound`.
+    fn segment(&self, gen: u64) -> Result<Arc<Segment>> {
+        let map = self.mmap && gen < self.active_gen.load(Ordering::SeqCst);
+        // A segment opened while it was active is mapped once it is sealed.
+        let usable = |segment: &Segment| match segment {
+            Segment::File(_) => !map,
+            Segment::Mapped(_) => true,
+        };
+        if let Some(segment) = self.segments.read().unwrap().get(&gen) {
+            if usable(segment) {
+                return Ok(Arc::clone(segment));
+            }
+        }
+
+        let file = File::open(log_path(&self.path, gen))?;
+        let segment = if map {
+            // The writer no longer appends to a sealed segment and a compaction
+            // only removes it, so the mapped bytes never change.
+            Segment::Mapped(unsafe { Mmap::map(&file)? })
+        } else {
+            Segment::File(file)
+        };
+        let mut segments = self.segments.write().unwrap();
+        match segments.get(&gen) {
+            Some(cached) if usable(cached) => Ok(Arc::clone(cached)),
+            _ => {
+                let segment = Arc::new(segment);
+                segments.insert(gen, Arc::clone(&segment));
+                Ok(segment)
+            }
+        }
+    }
+
+    /// Opens the segments now, so they stay readable once a compaction removes them.
+    pub fn open_segments(&self, gens: &[u64]) -> Result<()> {
+        for &gen in gens {
+            self.segment(gen)?;
+        }
+        Ok(())
+    }
+
+    /// Read the record at the given `CommandPos` and pass its bytes to `f`.
+    pub fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
+    where
+        F: FnOnce(&[u8]) -> Result<R>,
+    {
+        self.close_stale_handles();
+
+        match &*self.segment(cmd_pos.gen)? {
+            Segment::File(file) => {
+                let mut buf = vec![0; cmd_pos.len as usize];
+                read_exact_at(file, &mut buf, cmd_pos.pos)?;
+                f(&buf)
+            }
+            Segment::Mapped(map) => {
+                let start = cmd_pos.pos as usize;
+                let record = map
+                    .get(start..start + cmd_pos.len as usize)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+                f(record)
+            }
+        }
+    }
+
     /// Read the log file at the given `CommandPos` and deserialize it to `Command`.
+    ///
+    /// It returns `KvsError::CorruptedLog` if the record checksum does not match.
     pub fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
-        let reader = &mut self.reader.borrow_mut().reader;
-        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-        let cmd_reader = reader.take(cmd_pos.len);
-        Ok(serde_json::from_reader(cmd_reader)?)
+        self.read_and(cmd_pos, |cmd_reader| {
+            let at = RecordAddress::Log {
+                gen: cmd_pos.gen,
+                pos: cmd_pos.pos,
+            };
+            read_record(cmd_reader, &self.codec, at)?.ok_or(KvsError::CorruptedLog {
+                gen: cmd_pos.gen,
+                pos: cmd_pos.pos,
+            })
+        })
     }
 }
 
-impl Clone for KvStoreReader {
-    fn clone(&self) -> KvStoreReader {
-        let reader =
-            BufReaderWithPos::new(File::open(&self.path.join(LOG_FILE_NAME)).unwrap()).unwrap();
-        KvStoreReader {
-            path: Arc::clone(&self.path),
-            reader: RefCell::new(reader),
+// Fill `buf` from the file at `offset`, without moving a shared cursor.
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+// Fill `buf` from the file at `offset`, without moving a shared cursor.
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
         }
     }
+    Ok(())
 }
 
 pub struct BufReaderWithPos<R: Read + Seek> {
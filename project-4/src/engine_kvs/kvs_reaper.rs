@@ -0,0 +1,88 @@
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+use crossbeam_skiplist::SkipMap;
+
+use crate::engine_kvs::kvs_command::{now_millis, CommandPos};
+use crate::engine_kvs::kvs_writer::KvStoreWriter;
+use crate::Result;
+
+/// The background thread removing expired keys, shared by the clones of a `MyKvStore`.
+///
+/// Dropping it stops the thread and waits for it to exit.
+pub struct ReaperThread {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for ReaperThread {
+    fn drop(&mut self) {
+        // Disconnect the channel so the thread wakes up and leaves its loop.
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("Reaper thread panicked");
+            }
+        }
+    }
+}
+
+/// Periodically drops the expired keys from the index.
+struct KvStoreReaper {
+    index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+    writer: Weak<Mutex<KvStoreWriter>>,
+    receiver: Receiver<()>,
+    interval: Duration,
+}
+
+/// Starts the reaper thread for the given writer, waking up every `interval`.
+pub fn spawn_reaper(
+    writer: &Arc<Mutex<KvStoreWriter>>,
+    interval: Duration,
+) -> Result<ReaperThread> {
+    // Nothing is ever sent, the channel only tells the thread to exit.
+    let (sender, receiver) = channel::bounded(0);
+    let reaper = KvStoreReaper {
+        index: Arc::clone(&writer.lock().unwrap().index),
+        writer: Arc::downgrade(writer),
+        receiver,
+        interval,
+    };
+    let handle = thread::Builder::new()
+        .name("kvs-reaper".to_owned())
+        .spawn(move || reaper.run())?;
+    Ok(ReaperThread {
+        sender: Some(sender),
+        handle: Some(handle),
+    })
+}
+
+impl KvStoreReaper {
+    fn run(self) {
+        while let Err(RecvTimeoutError::Timeout) = self.receiver.recv_timeout(self.interval) {
+            if let Err(e) = self.reap() {
+                error!("Removing expired keys failed: {}", e);
+            }
+        }
+        debug!("Reaper thread exits because the store is closed.");
+    }
+
+    fn reap(&self) -> Result<()> {
+        let now = now_millis();
+        let expired: Vec<_> = self
+            .index
+            .iter()
+            .filter(|entry| entry.value().is_expired(now))
+            .map(|entry| (entry.key().clone(), *entry.value()))
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+        match self.writer.upgrade() {
+            Some(writer) => writer.lock().unwrap().reap(expired),
+            None => Ok(()),
+        }
+    }
+}
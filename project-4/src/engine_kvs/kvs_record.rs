@@ -0,0 +1,377 @@
+use std::convert::TryInto;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+use bincode::Options;
+use crc32fast::Hasher;
+
+use crate::engine_kvs::kvs_command::{Command, JsonCommand};
+use crate::engine_kvs::kvs_crypto::{EncryptionKey, LogCipher};
+use crate::{KvsError, Result};
+
+/// Magic bytes at the start of every log segment.
+const LOG_MAGIC: &[u8; 4] = b"KVSL";
+/// Version of the on-disk record format written by this build.
+pub const FORMAT_VERSION: u32 = 4;
+/// The first binary format version, older ones read the same without the later flags.
+const FIRST_BINARY_FORMAT_VERSION: u32 = 1;
+/// Length of the segment header: magic bytes and little endian u32 format version.
+pub const LOG_HEADER_LEN: u64 = 8;
+
+/// Kind of a log segment, told apart by its header.
+pub enum LogHeader {
+    /// A new segment, possibly with a torn header.
+    Empty,
+    /// A segment in the current format version.
+    Current,
+    /// A segment of an earlier binary format version, only its header is outdated.
+    Outdated,
+    /// A segment of json records without header, written before format versions.
+    Json,
+}
+
+/// Length of the record header: payload length and CRC32, both little endian u32.
+pub const RECORD_HEADER_LEN: u64 = 8;
+/// Bit of the payload length set if the payload is LZ4 compressed.
+const COMPRESSED_FLAG: u32 = 1 << 31;
+/// Bit of the payload length set if the payload is encrypted, after compression.
+const ENCRYPTED_FLAG: u32 = 1 << 30;
+
+/// Outcome of reading one record frame during recovery.
+pub enum Frame<P = Vec<u8>> {
+    /// A complete record with a valid checksum.
+    Valid(P),
+    /// The log ends in the middle of this record, e.g. after a power loss.
+    Torn,
+    /// A complete record whose checksum does not match, spanning `len` bytes.
+    Corrupted { len: u64 },
+}
+
+/// A payload as it is stored in a record, with the flags of its header.
+#[derive(Clone)]
+pub struct StoredPayload {
+    bytes: Vec<u8>,
+    flags: u32,
+}
+
+/// Where a record is stored.
+///
+/// It is authenticated along with an encrypted payload, so a record copied to
+/// another place fails to decrypt. Moving a record means encrypting it again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordAddress {
+    /// A record at offset `pos` of log segment `gen`.
+    Log { gen: u64, pos: u64 },
+    /// An entry at offset `pos` of the hint file of log segment `gen`.
+    Hint { gen: u64, pos: u64 },
+}
+
+impl RecordAddress {
+    // The flags, the kind of file, the generation and the offset, little endian.
+    fn aad(self, flags: u32) -> [u8; 21] {
+        let (kind, gen, pos) = match self {
+            RecordAddress::Log { gen, pos } => (0, gen, pos),
+            RecordAddress::Hint { gen, pos } => (1, gen, pos),
+        };
+        let mut aad = [0u8; 21];
+        aad[..4].copy_from_slice(&flags.to_le_bytes());
+        aad[4] = kind;
+        aad[5..13].copy_from_slice(&gen.to_le_bytes());
+        aad[13..].copy_from_slice(&pos.to_le_bytes());
+        aad
+    }
+}
+
+/// How the payloads of new records are compressed and encrypted.
+///
+/// Reading follows the flags of each record, so records written with other
+/// settings read the same. Only encrypted ones need the cipher.
+#[derive(Clone, Default)]
+pub struct RecordCodec {
+    /// Payloads longer than this many bytes are compressed.
+    pub compress_above: Option<usize>,
+    /// The cipher encrypting every payload.
+    pub cipher: Option<Arc<LogCipher>>,
+}
+
+impl RecordCodec {
+    pub fn new(compress_above: Option<usize>, key: Option<&EncryptionKey>) -> RecordCodec {
+        RecordCodec {
+            compress_above,
+            cipher: key.map(|key| Arc::new(LogCipher::new(key))),
+        }
+    }
+
+    /// Compresses the payload if it is long enough and compression makes it
+    /// shorter, then encrypts it for the record at `at`.
+    pub fn encode(&self, payload: Vec<u8>, at: RecordAddress) -> Result<StoredPayload> {
+        let stored = match self.compress_above {
+            Some(threshold) if payload.len() > threshold => {
+                let compressed = lz4_flex::compress_prepend_size(&payload);
+                if compressed.len() < payload.len() {
+                    StoredPayload {
+                        bytes: compressed,
+                        flags: COMPRESSED_FLAG,
+                    }
+                } else {
+                    StoredPayload {
+                        bytes: payload,
+                        flags: 0,
+                    }
+                }
+            }
+            _ => StoredPayload {
+                bytes: payload,
+                flags: 0,
+            },
+        };
+        self.encrypt(stored, at)
+    }
+
+    /// Encrypts an unencrypted payload, if there is a cipher.
+    ///
+    /// The other flags and the address of the record are authenticated along
+    /// with the payload.
+    pub fn encrypt(&self, stored: StoredPayload, at: RecordAddress) -> Result<StoredPayload> {
+        match &self.cipher {
+            Some(cipher) => Ok(StoredPayload {
+                bytes: cipher.seal(&stored.bytes, &at.aad(stored.flags))?,
+                flags: stored.flags | ENCRYPTED_FLAG,
+            }),
+            None => Ok(stored),
+        }
+    }
+
+    /// Decrypts the payload of the record at `at` if it is encrypted.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Encryption` if there is no cipher, the wrong one,
+    /// or the record was encrypted for another address.
+    pub fn decrypt(&self, stored: StoredPayload, at: RecordAddress) -> Result<StoredPayload> {
+        if stored.flags & ENCRYPTED_FLAG == 0 {
+            return Ok(stored);
+        }
+        let cipher = self.cipher.as_ref().ok_or_else(|| {
+            KvsError::Encryption("encrypted log record but no encryption key".to_owned())
+        })?;
+        let flags = stored.flags & !ENCRYPTED_FLAG;
+        Ok(StoredPayload {
+            bytes: cipher.open(&stored.bytes, &at.aad(flags))?,
+            flags,
+        })
+    }
+
+    /// Encrypts the payload of a record moved from `from` to `to` again.
+    ///
+    /// Unencrypted payloads stay as they are.
+    pub fn reseal(
+        &self,
+        stored: StoredPayload,
+        from: RecordAddress,
+        to: RecordAddress,
+    ) -> Result<StoredPayload> {
+        if stored.flags & ENCRYPTED_FLAG == 0 {
+            return Ok(stored);
+        }
+        self.encrypt(self.decrypt(stored, from)?, to)
+    }
+
+    /// Returns the plain payload of the record stored at `at`.
+    pub fn decode(&self, stored: StoredPayload, at: RecordAddress) -> Result<Vec<u8>> {
+        let stored = self.decrypt(stored, at)?;
+        if stored.flags & COMPRESSED_FLAG == 0 {
+            return Ok(stored.bytes);
+        }
+        lz4_flex::decompress_size_prepended(&stored.bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()).into())
+    }
+}
+
+/// Writes the command as a framed record, which is stored at `at`.
+pub fn write_record<W: Write>(
+    writer: &mut W,
+    cmd: &Command,
+    codec: &RecordCodec,
+    at: RecordAddress,
+) -> Result<()> {
+    write_frame(
+        writer,
+        bincode::DefaultOptions::new().serialize(cmd)?,
+        codec,
+        at,
+    )
+}
+
+/// Writes the payload prefixed by its length and checksum, which is stored at `at`.
+pub fn write_frame<W: Write>(
+    writer: &mut W,
+    payload: Vec<u8>,
+    codec: &RecordCodec,
+    at: RecordAddress,
+) -> Result<()> {
+    write_stored_frame(writer, &codec.encode(payload, at)?)
+}
+
+/// Writes a stored payload prefixed by its length, flags and checksum.
+///
+/// It returns `KvsError::InvalidInput` if the payload is too long for its
+/// length to leave the flag bits clear.
+pub fn write_stored_frame<W: Write>(writer: &mut W, stored: &StoredPayload) -> Result<()> {
+    if stored.bytes.len() as u64 >= u64::from(ENCRYPTED_FLAG) {
+        return Err(KvsError::InvalidInput(format!(
+            "record of {} bytes, records must be shorter than {} bytes",
+            stored.bytes.len(),
+            ENCRYPTED_FLAG
+        )));
+    }
+    let mut header = [0u8; RECORD_HEADER_LEN as usize];
+    header[..4].copy_from_slice(&(stored.bytes.len() as u32 | stored.flags).to_le_bytes());
+    header[4..].copy_from_slice(&checksum(&stored.bytes).to_le_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(&stored.bytes)?;
+    Ok(())
+}
+
+/// Reads the record frame stored at `at`, `remaining` being the number of
+/// bytes left in the log.
+///
+/// The payload of a valid frame is decrypted and decompressed.
+pub fn read_frame<R: Read>(
+    reader: &mut R,
+    remaining: u64,
+    codec: &RecordCodec,
+    at: RecordAddress,
+) -> Result<Frame> {
+    match read_stored_frame(reader, remaining)? {
+        Frame::Valid(stored) => Ok(Frame::Valid(codec.decode(stored, at)?)),
+        Frame::Torn => Ok(Frame::Torn),
+        Frame::Corrupted { len } => Ok(Frame::Corrupted { len }),
+    }
+}
+
+/// Reads a record frame as it is stored, `remaining` being the number of bytes left in the log.
+pub fn read_stored_frame<R: Read>(reader: &mut R, remaining: u64) -> Result<Frame<StoredPayload>> {
+    if remaining < RECORD_HEADER_LEN {
+        return Ok(Frame::Torn);
+    }
+    let mut header = [0u8; RECORD_HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    let (len, crc, flags) = parse_header(&header);
+    let frame_len = RECORD_HEADER_LEN + len;
+    if frame_len > remaining {
+        return Ok(Frame::Torn);
+    }
+
+    let mut bytes = vec![0u8; len as usize];
+    reader.read_exact(&mut bytes)?;
+    if checksum(&bytes) == crc {
+        Ok(Frame::Valid(StoredPayload { bytes, flags }))
+    } else if frame_len == remaining {
+        // A half-flushed last record can have a complete length but garbage data.
+        Ok(Frame::Torn)
+    } else {
+        Ok(Frame::Corrupted { len: frame_len })
+    }
+}
+
+/// Returns whether a complete record with a valid checksum starts anywhere in `bytes`.
+///
+/// Nothing follows a torn record, so a valid one after it means its length
+/// was corrupted. Empty records are never written, runs of zeros do not count.
+pub fn holds_valid_frame(bytes: &[u8]) -> bool {
+    (0..bytes.len()).any(|start| {
+        let mut rest = &bytes[start..];
+        match read_stored_frame(&mut rest, (bytes.len() - start) as u64) {
+            Ok(Frame::Valid(stored)) => !stored.bytes.is_empty(),
+            _ => false,
+        }
+    })
+}
+
+/// Reads the whole record stored at `at` and deserializes it to `Command`.
+///
+/// Returns `None` if the checksum does not match.
+pub fn read_record<R: Read>(
+    mut reader: R,
+    codec: &RecordCodec,
+    at: RecordAddress,
+) -> Result<Option<Command>> {
+    let mut header = [0u8; RECORD_HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    let (len, crc, flags) = parse_header(&header);
+    let mut bytes = Vec::with_capacity(len as usize);
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    if checksum(&bytes) != crc {
+        return Ok(None);
+    }
+    Ok(Some(decode(
+        &codec.decode(StoredPayload { bytes, flags }, at)?,
+    )?))
+}
+
+/// Deserializes the payload of a valid record.
+pub fn decode(payload: &[u8]) -> Result<Command> {
+    Ok(bincode::DefaultOptions::new().deserialize(payload)?)
+}
+
+/// Deserializes the payload of a valid record in a json segment.
+pub fn decode_json(payload: &[u8]) -> Result<Command> {
+    Ok(serde_json::from_slice::<JsonCommand>(payload)?.into())
+}
+
+/// Writes the segment header for the current format version.
+pub fn write_log_header<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(LOG_MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+/// Reads the segment header from the start of the segment.
+pub fn read_log_header<R: Read>(reader: R) -> Result<LogHeader> {
+    let mut header = Vec::with_capacity(LOG_HEADER_LEN as usize);
+    reader.take(LOG_HEADER_LEN).read_to_end(&mut header)?;
+    if header.len() < LOG_MAGIC.len() {
+        return if LOG_MAGIC.starts_with(&header) {
+            Ok(LogHeader::Empty)
+        } else {
+            Ok(LogHeader::Json)
+        };
+    }
+    if &header[..LOG_MAGIC.len()] != LOG_MAGIC {
+        return Ok(LogHeader::Json);
+    }
+    if header.len() < LOG_HEADER_LEN as usize {
+        return Ok(LogHeader::Empty);
+    }
+    let version = u32::from_le_bytes(header[LOG_MAGIC.len()..].try_into().unwrap());
+    match version {
+        FORMAT_VERSION => Ok(LogHeader::Current),
+        FIRST_BINARY_FORMAT_VERSION..=FORMAT_VERSION => Ok(LogHeader::Outdated),
+        _ => Err(KvsError::UnsupportedFormat(version)),
+    }
+}
+
+/// Writes the current format version over the header of a segment.
+pub fn write_format_version<W: Write + Seek>(writer: &mut W) -> Result<()> {
+    writer.seek(SeekFrom::Start(LOG_MAGIC.len() as u64))?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+// Returns the payload length, the checksum and the flags.
+fn parse_header(header: &[u8; RECORD_HEADER_LEN as usize]) -> (u64, u32, u32) {
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let flags = len & (COMPRESSED_FLAG | ENCRYPTED_FLAG);
+    (u64::from(len & !flags), crc, flags)
+}
+
+fn checksum(payload: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(payload);
+    hasher.finalize()
+}
@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Mutex;
+
+use crate::engine_kvs::kvs_command::CommandPos;
+use crate::engine_kvs::my_kvs::MyKvStore;
+use crate::Result;
+
+/// A frozen view of a `MyKvStore`, taken by `MyKvStore::snapshot`.
+///
+/// Reads through the snapshot see every write done before it was taken and
+/// none done after, so reading several keys never sees a batch half applied.
+/// The versions it reads are kept, also across compactions, until it is dropped.
+///
+/// ```rust
+/// # use kvs::{KvEngine, MyKvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// # let store = MyKvStore::open(std::env::current_dir()?)?;
+/// store.set(b"key".to_vec(), b"old".to_vec())?;
+/// let snapshot = store.snapshot();
+/// store.set(b"key".to_vec(), b"new".to_vec())?;
+/// assert_eq!(snapshot.get(b"key".to_vec())?, Some(b"old".to_vec()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct Snapshot {
+    store: MyKvStore,
+    seq: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn new(store: MyKvStore, seq: u64) -> Snapshot {
+        Snapshot { store, seq }
+    }
+
+    /// Returns the sequence number of the last write the snapshot sees.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Gets the value the key had when the snapshot was taken.
+    ///
+    /// Returns `None` if the key did not exist then.
+    pub fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.store.read_value_at(&key, self.seq)
+    }
+
+    /// Returns up to `limit` key/value pairs with keys in `[start, end)` as they
+    /// were when the snapshot was taken, in key order.
+    pub fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.store.scan_at(start, end, limit, self.seq)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.store.release_snapshot(self.seq);
+    }
+}
+
+/// The versions of keys overwritten or removed while a snapshot still sees them.
+///
+/// The index only points at the latest version of a key. A writer hands the
+/// version it replaces to the history first, so a snapshot finds it in one or
+/// the other at any time. Versions no snapshot sees are never kept.
+pub struct VersionHistory {
+    state: Mutex<HistoryState>,
+}
+
+struct HistoryState {
+    // Number of live snapshots by their sequence number.
+    snapshots: BTreeMap<u64, usize>,
+    // The replaced versions by key.
+    versions: BTreeMap<Vec<u8>, Vec<Version>>,
+}
+
+// A version of a key, seen by the snapshots from `pos.seq` until the write `until` replaced it.
+struct Version {
+    pos: CommandPos,
+    until: u64,
+}
+
+// Returns whether one of the snapshots sees the version.
+fn is_seen(snapshots: &BTreeMap<u64, usize>, version: &Version) -> bool {
+    snapshots
+        .range(version.pos.seq..version.until)
+        .next()
+        .is_some()
+}
+
+impl VersionHistory {
+    pub fn new() -> VersionHistory {
+        VersionHistory {
+            state: Mutex::new(HistoryState {
+                snapshots: BTreeMap::new(),
+                versions: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Registers a snapshot seeing the writes up to `seq`.
+    ///
+    /// It must be called under the writer lock, so no write replaces a
+    /// version without seeing the snapshot.
+    pub fn register(&self, seq: u64) {
+        *self.state.lock().unwrap().snapshots.entry(seq).or_insert(0) += 1;
+    }
+
+    /// Unregisters a snapshot and drops the versions no other snapshot sees.
+    pub fn release(&self, seq: u64) {
+        let mut state = self.state.lock().unwrap();
+        let count = state
+            .snapshots
+            .get_mut(&seq)
+            .expect("released snapshot not registered");
+        *count -= 1;
+        if *count == 0 {
+            state.snapshots.remove(&seq);
+        }
+
+        let state = &mut *state;
+        let mut unseen = Vec::new();
+        for (key, versions) in state.versions.iter_mut() {
+            let snapshots = &state.snapshots;
+            versions.retain(|version| is_seen(snapshots, version));
+            if versions.is_empty() {
+                unseen.push(key.clone());
+            }
+        }
+        for key in unseen {
+            state.versions.remove(&key);
+        }
+    }
+
+    /// Keeps the version of the key at `pos` replaced by the write `until`, if a snapshot sees it.
+    pub fn replace(&self, key: &[u8], pos: CommandPos, until: u64) {
+        let mut state = self.state.lock().unwrap();
+        let version = Version { pos, until };
+        if is_seen(&state.snapshots, &version) {
+            state
+                .versions
+                .entry(key.to_vec())
+                .or_default()
+                .push(version);
+        }
+    }
+
+    /// Returns the version of the key a snapshot at `seq` sees, `current` being
+    /// the position the index points at.
+    pub fn visible(&self, key: &[u8], seq: u64, current: Option<CommandPos>) -> Option<CommandPos> {
+        match current {
+            Some(cmd_pos) if cmd_pos.seq <= seq => Some(cmd_pos),
+            _ => self
+                .state
+                .lock()
+                .unwrap()
+                .versions
+                .get(key)?
+                .iter()
+                .find(|version| version.pos.seq <= seq && seq < version.until)
+                .map(|version| version.pos),
+        }
+    }
+
+    /// Returns the keys with kept versions in the range, in key order.
+    pub fn keys_in(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Vec<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state
+            .versions
+            .range(range)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Returns the kept versions in log segments below `gen`.
+    pub fn versions_below(&self, gen: u64) -> Vec<(Vec<u8>, CommandPos)> {
+        let state = self.state.lock().unwrap();
+        let mut below = Vec::new();
+        for (key, versions) in &state.versions {
+            for version in versions.iter().filter(|version| version.pos.gen < gen) {
+                below.push((key.clone(), version.pos));
+            }
+        }
+        below
+    }
+
+    /// Follows a version moved by a compaction from `old_pos` to `new_pos`.
+    ///
+    /// The version is dropped if the compaction dropped the record.
+    pub fn moved(&self, key: &[u8], old_pos: CommandPos, new_pos: Option<CommandPos>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(versions) = state.versions.get_mut(key) {
+            match new_pos {
+                Some(new_pos) => {
+                    for version in versions.iter_mut().filter(|version| version.pos == old_pos) {
+                        version.pos = new_pos;
+                    }
+                }
+                None => versions.retain(|version| version.pos != old_pos),
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::fs::File;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, RecvTimeoutError, Sender};
+
+use crate::Result;
+
+/// Tracks which writes of the active log segment are synced to disk.
+///
+/// Writes are numbered in the order they are flushed to the segment. The
+/// first thread waiting for an unsynced write syncs every write flushed so
+/// far, the threads waiting meanwhile share the next sync.
+pub struct LogSync {
+    state: Mutex<SyncState>,
+    synced_cond: Condvar,
+}
+
+struct SyncState {
+    // Number of the last write flushed to the log.
+    written: u64,
+    // Number of the last write synced to disk.
+    synced: u64,
+    // Whether a thread is syncing right now.
+    syncing: bool,
+    // The active log segment.
+    file: Arc<File>,
+}
+
+impl LogSync {
+    pub fn new(file: File) -> LogSync {
+        LogSync {
+            state: Mutex::new(SyncState {
+                written: 0,
+                synced: 0,
+                syncing: false,
+                file: Arc::new(file),
+            }),
+            synced_cond: Condvar::new(),
+        }
+    }
+
+    /// Records a write flushed to the active segment and returns its number.
+    pub fn appended(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        state.written
+    }
+
+    /// Returns the number of the last write flushed to the log.
+    pub fn written(&self) -> u64 {
+        self.state.lock().unwrap().written
+    }
+
+    /// Syncs the active segment and makes `file` the active one.
+    ///
+    /// Called by the writer as it moves on to a new segment, so every write
+    /// of the sealed segments is synced.
+    pub fn roll_over(&self, file: File) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.file.sync_data()?;
+        state.synced = state.written;
+        state.file = Arc::new(file);
+        self.synced_cond.notify_all();
+        Ok(())
+    }
+
+    /// Waits until the write numbered `seq` is synced, syncing it if no other thread is.
+    pub fn wait_synced(&self, seq: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= seq {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced_cond.wait(state).unwrap();
+                continue;
+            }
+
+            // Lead a sync of everything written so far, without the lock.
+            state.syncing = true;
+            let target = state.written;
+            let file = Arc::clone(&state.file);
+            drop(state);
+            let result = file.sync_data();
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if result.is_ok() && state.synced < target {
+                state.synced = target;
+            }
+            self.synced_cond.notify_all();
+            result?;
+        }
+    }
+
+    /// Syncs every write flushed so far.
+    pub fn sync(&self) -> Result<()> {
+        let written = self.written();
+        self.wait_synced(written)
+    }
+}
+
+/// The background thread syncing the log every interval, shared by the clones of a `MyKvStore`.
+///
+/// Dropping it stops the thread, which syncs the log a last time.
+pub struct SyncerThread {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for SyncerThread {
+    fn drop(&mut self) {
+        // Disconnect the channel so the thread wakes up and leaves its loop.
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("Syncer thread panicked");
+            }
+        }
+    }
+}
+
+/// Starts the thread syncing the log every `interval`.
+pub fn spawn_syncer(log_sync: &Arc<LogSync>, interval: Duration) -> Result<SyncerThread> {
+    // Nothing is ever sent, the channel only tells the thread to exit.
+    let (sender, receiver) = channel::bounded(0);
+    let log_sync = Arc::clone(log_sync);
+    let handle = thread::Builder::new()
+        .name("kvs-syncer".to_owned())
+        .spawn(move || run_syncer(&log_sync, &receiver, interval))?;
+    Ok(SyncerThread {
+        sender: Some(sender),
+        handle: Some(handle),
+    })
+}
+
+fn run_syncer(log_sync: &LogSync, receiver: &Receiver<()>, interval: Duration) {
+    while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+        if let Err(e) = log_sync.sync() {
+            error!("Syncing the log failed: {}", e);
+        }
+    }
+    if let Err(e) = log_sync.sync() {
+        error!("Syncing the log failed: {}", e);
+    }
+    debug!("Syncer thread exits because the store is closed.");
+}
@@ -1,109 +1,244 @@
-use std::borrow::BorrowMut;
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::Read;
+use std::io;
 use std::io::{BufWriter, Seek, SeekFrom, Write};
 use std::path::PathBuf;
-use std::sync::Arc;
-use std::{fs, io};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 use crossbeam_skiplist::SkipMap;
 
-use crate::engine_kvs::kvs_command::{Command, CommandPos};
-use crate::engine_kvs::kvs_reader::BufReaderWithPos;
-use crate::engine_kvs::my_kvs::{new_log_file, LOG_FILE_NAME};
+use crate::engine_kvs::kvs_cache::ValueCache;
+use crate::engine_kvs::kvs_command::{now_millis, Command, CommandPos};
+use crate::engine_kvs::kvs_compactor::CompactorHandle;
+use crate::engine_kvs::kvs_options::Durability;
+use crate::engine_kvs::kvs_record::{write_record, RecordAddress, RecordCodec};
+use crate::engine_kvs::kvs_snapshot::VersionHistory;
+use crate::engine_kvs::kvs_sync::LogSync;
+use crate::engine_kvs::my_kvs::{index_command, new_log_file};
 use crate::{KvsError, Result};
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const SEGMENT_SIZE_THRESHOLD: u64 = 1024 * 1024;
 
 pub struct KvStoreWriter {
     // The current log file director path.
     pub path: Arc<PathBuf>,
-    pub reader: BufReaderWithPos<File>,
     pub writer: BufWriterWithPos<File>,
+    // The generation of the active log segment.
+    pub current_gen: u64,
+    // The same generation, shared with the readers to tell sealed segments.
+    pub active_gen: Arc<AtomicU64>,
     // The number of bytes representing "stale" commands that could be
     // deleted during a compaction.
     pub need_compacted: u64,
     // The command position index.
-    pub index: Arc<SkipMap<String, CommandPos>>,
+    pub index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+    // The cached values, dropped as their keys are written.
+    pub cache: Arc<ValueCache>,
+    // The replaced versions the snapshots still read.
+    pub history: Arc<VersionHistory>,
+    // The sequence number of the last write.
+    pub last_seq: u64,
+    // Handle to the background compaction thread.
+    pub compactor: CompactorHandle,
+    // Held during a compaction, so a backup never sees a segment being written or removed.
+    pub compaction_lock: Arc<Mutex<()>>,
+    // Compresses and encrypts the new records.
+    pub codec: RecordCodec,
+    pub durability: Durability,
+    // Which writes of the active segment are synced.
+    pub log_sync: Arc<LogSync>,
 }
 impl KvStoreWriter {
-    /// Sets the value of a string key into log file as string.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.need_compacted += old_cmd.value().len;
+    /// Sets the value of a key into log file.
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write_one(Command::set(key, value))
+    }
+
+    /// Drops expired keys from the index.
+    ///
+    /// Their records stay hidden by the expiry until a compaction drops them,
+    /// so no remove record is needed.
+    pub fn reap(&mut self, expired: Vec<(Vec<u8>, CommandPos)>) -> Result<()> {
+        for (key, cmd_pos) in expired {
+            // The key may have been written again since it was found expired.
+            if self.index.get(&key).map(|e| *e.value()) == Some(cmd_pos) {
+                self.index.remove(&key);
+                self.cache.invalidate(&key);
+                self.need_compacted += cmd_pos.len;
             }
-            self.index.insert(key, (pos..self.writer.pos).into());
         }
 
-        if self.need_compacted > COMPACTION_THRESHOLD {
-            self.compact()?;
-        }
-        Ok(())
+        self.maybe_roll_over()
     }
 
     /// Remove a given key from log file.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.need_compacted += old_cmd.value().len;
+    pub fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        self.write_one(Command::remove(key))
+    }
+
+    /// Writes the commands as one record each, with a single flush.
+    ///
+    /// A remove of a key that is missing, also after the commands before it,
+    /// is left out and gets `KvsError::KeyNotFound`, a command whose record
+    /// can not be encoded gets the error. Returns the position of every
+    /// command in order, the index points at them once they are flushed.
+    ///
+    /// The records are appended all at once. If that fails none of them is
+    /// left in the log.
+    pub fn write_group(&mut self, cmds: Vec<Command>) -> Result<Vec<Result<CommandPos>>> {
+        // Whether the keys written by the group so far are live.
+        let mut live: HashMap<Vec<u8>, bool> = HashMap::new();
+        let mut written: Vec<Result<(Command, CommandPos)>> = Vec::with_capacity(cmds.len());
+        let mut records = Vec::new();
+        let mut seq = self.last_seq;
+        let now = now_millis();
+        for cmd in cmds {
+            if let Command::Remove { key } = &cmd {
+                let exists = match live.get(key) {
+                    Some(&exists) => exists,
+                    // An expired key is missing, even before the reaper drops it.
+                    None => self
+                        .index
+                        .get(key)
+                        .filter(|entry| !entry.value().is_expired(now))
+                        .is_some(),
+                };
+                if !exists {
+                    written.push(Err(KvsError::KeyNotFound));
+                    continue;
+                }
+            }
+            let cmd = Command::sequenced(seq + 1, cmd);
+            let start = records.len();
+            let pos = self.writer.pos + start as u64;
+            let at = RecordAddress::Log {
+                gen: self.current_gen,
+                pos,
+            };
+            if let Err(e) = write_record(&mut records, &cmd, &self.codec, at) {
+                records.truncate(start);
+                written.push(Err(e));
+                continue;
             }
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
+            track_live(&mut live, &cmd);
+            seq += 1;
+            let cmd_pos = CommandPos {
+                seq,
+                ..(self.current_gen, pos..pos + (records.len() - start) as u64).into()
+            };
+            written.push(Ok((cmd, cmd_pos)));
         }
-    }
+        self.writer.append_all(&records)?;
+        self.last_seq = seq;
+        self.commit()?;
 
-    /// Clears stale entries in the log.
-    pub fn compact(&mut self) -> Result<()> {
-        let path = self.path.join(LOG_FILE_NAME.to_owned() + ".temp");
-        let mut temp_writer = new_log_file(&path)?;
+        let mut positions = Vec::with_capacity(written.len());
+        for entry in written {
+            positions.push(entry.map(|(cmd, cmd_pos)| {
+                for key in cmd.keys() {
+                    self.cache.invalidate(key);
+                }
+                self.need_compacted +=
+                    index_command(&self.index, Some(&self.history), cmd, cmd_pos);
+                cmd_pos
+            }));
+        }
+        self.maybe_roll_over()?;
+        Ok(positions)
+    }
 
-        let mut new_pos = 0;
+    /// Flushes the active log segment and syncs it to disk.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.log_sync.sync()
+    }
 
-        for entry in self.index.iter() {
-            let reader = self.reader.borrow_mut();
-            if reader.pos != entry.value().pos {
-                reader.seek(SeekFrom::Start(entry.value().pos))?;
-            }
+    /// Seals the active segment, so every write so far is in a sealed segment.
+    ///
+    /// Returns the generation of the sealed segment.
+    pub fn seal(&mut self) -> Result<u64> {
+        self.writer.flush()?;
+        let sealed_gen = self.current_gen;
+        self.current_gen += 1;
+        self.open_segment()?;
+        Ok(sealed_gen)
+    }
 
-            let mut entry_reader = reader.take(entry.value().len);
-            let len = io::copy(&mut entry_reader, &mut temp_writer)?;
-            self.index
-                .insert(entry.key().clone(), (new_pos..new_pos + len).into());
-            new_pos += len;
+    // Flush the records just written, syncing them if every write is synced.
+    fn commit(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        let seq = self.log_sync.appended();
+        if self.durability == Durability::EveryWrite {
+            self.log_sync.wait_synced(seq)?;
         }
+        Ok(())
+    }
 
-        temp_writer.flush()?;
+    // Write a single command, returning why it was refused.
+    fn write_one(&mut self, cmd: Command) -> Result<()> {
+        let mut positions = self.write_group(vec![cmd])?;
+        positions
+            .pop()
+            .expect("one position per command")
+            .map(|_| ())
+    }
 
-        let old_file_path = &self.get_log_file_path()?;
-        fs::remove_file(old_file_path)?;
-        fs::rename(path, old_file_path)?;
-        self.need_compacted = 0;
-        // need reset writer.
-        self.writer = new_log_file(&old_file_path)?;
+    // Move on to the segment of `current_gen`, syncing the sealed one.
+    fn open_segment(&mut self) -> Result<()> {
+        self.writer = new_log_file(&self.path, self.current_gen)?;
+        self.log_sync
+            .roll_over(self.writer.get_ref().try_clone()?)?;
+        // The sealed segment is complete on disk before readers map it.
+        self.active_gen.store(self.current_gen, Ordering::SeqCst);
         Ok(())
     }
 
-    /// Get log file path.
+    /// Seals the active segment once it grows past the size threshold.
     ///
-    /// Returns the path of the log file.
-    fn get_log_file_path(&mut self) -> Result<PathBuf> {
-        Ok(self.path.join(LOG_FILE_NAME))
+    /// Once enough stale data piles up the sealed segments are handed to the
+    /// compaction thread, so the writer lock is never held during a compaction.
+    fn maybe_roll_over(&mut self) -> Result<()> {
+        if self.need_compacted > COMPACTION_THRESHOLD {
+            // current_gen + 1 is reserved for the compaction file.
+            let compaction_gen = self.current_gen + 1;
+            if self.compactor.try_schedule(compaction_gen) {
+                self.current_gen += 2;
+                self.open_segment()?;
+                self.need_compacted = 0;
+                return Ok(());
+            }
+        }
+        if self.writer.pos > SEGMENT_SIZE_THRESHOLD {
+            self.current_gen += 1;
+            self.open_segment()?;
+        }
+        Ok(())
+    }
+}
+
+// Record whether the keys written by the command are live afterwards.
+fn track_live(live: &mut HashMap<Vec<u8>, bool>, cmd: &Command) {
+    match cmd {
+        Command::Set { key, .. } | Command::SetExpiring { key, .. } => {
+            live.insert(key.clone(), true);
+        }
+        Command::Remove { key } => {
+            live.insert(key.clone(), false);
+        }
+        Command::Batch { cmds } => {
+            for cmd in cmds {
+                track_live(live, cmd);
+            }
+        }
+        Command::Sequenced { cmd, .. } => track_live(live, cmd),
     }
 }
 
 pub struct BufWriterWithPos<W: Write + Seek> {
     writer: BufWriter<W>,
-    pos: u64,
+    pub pos: u64,
 }
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
@@ -115,6 +250,33 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
             pos,
         })
     }
+
+    pub fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+}
+
+impl BufWriterWithPos<File> {
+    /// Appends the bytes to the file past the buffer, all of them or none.
+    ///
+    /// If the write fails, the file is truncated back to where it ended.
+    pub fn append_all(&mut self, buf: &[u8]) -> Result<()> {
+        self.writer.flush()?;
+        let mut file = self.writer.get_ref();
+        if let Err(e) = file.write_all(buf) {
+            file.set_len(self.pos)?;
+            return Err(e.into());
+        }
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Flushes the buffer and syncs the file data to disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
 }
 
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
@@ -1,7 +1,23 @@
 //! This module provides various key value storage engine kvs.
+pub use kvs_cache::CacheStats;
+pub use kvs_crypto::EncryptionKey;
+pub use kvs_lock::LOCK_FILE_NAME;
+pub use kvs_options::{Durability, KvStoreOptions, RecoveryMode};
+pub use kvs_snapshot::Snapshot;
 pub use my_kvs::MyKvStore;
 
+mod kvs_cache;
 mod kvs_command;
+mod kvs_commit;
+mod kvs_compactor;
+mod kvs_crypto;
+mod kvs_hint;
+mod kvs_lock;
+mod kvs_options;
 mod kvs_reader;
+mod kvs_reaper;
+mod kvs_record;
+mod kvs_snapshot;
+mod kvs_sync;
 mod kvs_writer;
 mod my_kvs;
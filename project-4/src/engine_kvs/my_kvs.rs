@@ -1,33 +1,68 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, Range};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use crossbeam_skiplist::SkipMap;
-use failure::_core::cell::RefCell;
 use serde_json::Deserializer;
 
-use crate::engine_kvs::kvs_command::{Command, CommandPos};
+use crate::engine_kvs::kvs_cache::{CacheStats, ValueCache};
+use crate::engine_kvs::kvs_command::{now_millis, Command, CommandPos, JsonCommand};
+use crate::engine_kvs::kvs_commit::CommitQueue;
+use crate::engine_kvs::kvs_compactor::{
+    compaction_channel, remove_if_exists, remove_unfinished_compactions, spawn_compactor,
+    CompactorThread,
+};
+use crate::engine_kvs::kvs_crypto::EncryptionKey;
+use crate::engine_kvs::kvs_hint::{hint_path, read_hint_file, write_hints, Hints};
+use crate::engine_kvs::kvs_lock::DirLock;
+use crate::engine_kvs::kvs_options::{Durability, KvStoreOptions, RecoveryMode};
 use crate::engine_kvs::kvs_reader::{BufReaderWithPos, KvStoreReader};
+use crate::engine_kvs::kvs_reaper::{spawn_reaper, ReaperThread};
+use crate::engine_kvs::kvs_record::{
+    decode, decode_json, holds_valid_frame, read_frame, read_log_header, read_stored_frame,
+    write_format_version, write_log_header, write_record, write_stored_frame, Frame, LogHeader,
+    RecordAddress, RecordCodec, FORMAT_VERSION, LOG_HEADER_LEN,
+};
+use crate::engine_kvs::kvs_snapshot::{Snapshot, VersionHistory};
+use crate::engine_kvs::kvs_sync::{spawn_syncer, LogSync, SyncerThread};
 use crate::engine_kvs::kvs_writer::{BufWriterWithPos, KvStoreWriter};
-use crate::{KvEngine, KvsError, Result};
+use crate::engine_trait::{create_backup_dir, BatchOp};
+use crate::manifest::manifest_path;
+use crate::{EngineKind, KvEngine, KvsError, Manifest, Result, WriteBatch};
 
-pub const LOG_FILE_NAME: &str = "kvs.log";
+// The single log file written by earlier versions, adopted as the first segment.
+const LEGACY_LOG_FILE_NAME: &str = "kvs.log";
+// How often a read-only store replays the log again when a segment vanished.
+const READ_ONLY_OPEN_ATTEMPTS: u32 = 3;
 
-/// The `KvStore` stores string key/value pairs.
+/// The `KvStore` stores key/value pairs.
 ///
-/// Key/value pairs are persisted to disk in log files.
+/// Key/value pairs are persisted to disk in log segments named after their
+/// generation number. Writes go to the newest segment, which is sealed once it
+/// grows too large. Compaction segments come with a hint file holding the
+/// positions of their records, so opening the store does not replay them.
 /// A `SkipMap` in memory stores the keys and the value locations for fast query.
 ///
+/// Every write gets a sequence number, stored in its record. A snapshot reads
+/// the versions written up to its sequence number, the versions replaced since
+/// are kept aside for it.
+///
 /// ```rust
 /// # use kvs::{MyKvStore, Result, KvEngine};
 /// # fn try_main() -> Result<()> {
 /// use std::env::current_dir;
 /// let mut store = MyKvStore::open(current_dir()?)?;
-/// store.set("key".to_owned(), "value".to_owned())?;
-/// let val = store.get("key".to_owned())?;
-/// assert_eq!(val, Some("value".to_owned()));
+/// store.set(b"key".to_vec(), b"value".to_vec())?;
+/// let val = store.get(b"key".to_vec())?;
+/// assert_eq!(val, Some(b"value".to_vec()));
 /// # Ok(())
 /// # }
 /// ```
@@ -39,8 +74,30 @@ pub struct MyKvStore {
     reader: KvStoreReader,
     // Writer of the current log.
     writer: Arc<Mutex<KvStoreWriter>>,
+    // The writes waiting for the writer, written in groups.
+    commit_queue: Arc<CommitQueue>,
     // The command position index.
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<SkipMap<Vec<u8>, CommandPos>>,
+    // The recently read values.
+    cache: Arc<ValueCache>,
+    // The replaced versions the snapshots still read.
+    history: Arc<VersionHistory>,
+    // Held by a compaction or a backup, which never run at the same time.
+    compaction_lock: Arc<Mutex<()>>,
+    // The background compaction thread, only held to join it after the writer is dropped.
+    _compactor: Arc<CompactorThread>,
+    // The background thread removing expired keys, none for a read-only store.
+    _reaper: Option<Arc<ReaperThread>>,
+    durability: Durability,
+    // Which writes are synced, for the group commit.
+    log_sync: Arc<LogSync>,
+    // The background thread syncing the log in `Durability::Interval` mode.
+    _syncer: Option<Arc<SyncerThread>>,
+    // Whether writes are refused.
+    read_only: bool,
+    // The lock on the data directory, none for a read-only store. It is the
+    // last field, so it is released once the background threads are joined.
+    _lock: Option<Arc<DirLock>>,
 }
 
 impl MyKvStore {
@@ -50,73 +107,320 @@ impl MyKvStore {
     ///
     /// # Errors
     ///
+    /// It returns `KvsError::Locked` if another store has the directory open.
+    ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>) -> Result<MyKvStore> {
+        MyKvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// A torn record at the end of a log segment is truncated during the log replay.
+    /// The manifest of the directory is created, or updated with the options.
+    ///
+    /// The directory is locked until the store and its clones are dropped.
+    /// A read-only store takes no lock and changes no file, so it opens a
+    /// store another process is writing. The store has to exist and have
+    /// been opened for writing by this version.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Locked` if another store has the directory open
+    /// for writing.
+    ///
+    /// It returns `KvsError::ReadOnly` if a read-only store needs its log upgraded.
+    ///
+    /// It returns `KvsError::Manifest` if the directory belongs to another engine.
+    ///
+    /// It returns `KvsError::Encryption` if the store is encrypted and no key,
+    /// or a wrong one, is given.
+    ///
+    /// It returns `KvsError::CorruptedLog` if a record in the middle of the log
+    /// is corrupted and the `RecoveryMode` is `Strict`.
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+    ) -> Result<MyKvStore> {
         let path = Arc::new(path.into());
-        fs::create_dir_all(&*path)?;
-        let log_path = path.join(LOG_FILE_NAME);
+        let read_only = options.read_only;
+        // A read-only store leaves the directory to the store writing it.
+        let lock = if read_only {
+            None
+        } else {
+            fs::create_dir_all(&*path)?;
+            Some(Arc::new(DirLock::acquire(&path)?))
+        };
+        let loaded_manifest = if read_only {
+            Manifest::read(&path)?
+        } else {
+            Manifest::load(&path)?
+        };
+        if let Some(ref manifest) = loaded_manifest {
+            manifest.check_engine(EngineKind::Kvs)?;
+            if manifest.encrypted && options.encryption_key.is_none() {
+                return Err(KvsError::Encryption(
+                    "the store is encrypted but no key was given".to_owned(),
+                ));
+            }
+        }
+        let codec = RecordCodec::new(options.compress_above, options.encryption_key.as_ref());
+        if !read_only {
+            adopt_legacy_log(&path, &codec)?;
+            remove_unfinished_compactions(&path)?;
+        }
+
+        let safe_point = Arc::new(AtomicU64::new(0));
+        let active_gen = Arc::new(AtomicU64::new(0));
+        let mut attempts = 0;
+        let (replayed, reader) = loop {
+            let opened = replay(&path, &options, &codec).and_then(|replayed| {
+                active_gen.store(replayed.current_gen, Ordering::SeqCst);
+                let reader = KvStoreReader::new(
+                    Arc::clone(&path),
+                    Arc::clone(&safe_point),
+                    Arc::clone(&active_gen),
+                    options.mmap_reads,
+                    codec.clone(),
+                );
+                // The segments stay readable once the writer compacts them away.
+                if read_only {
+                    reader.open_segments(&replayed.gen_list)?;
+                }
+                Ok((replayed, reader))
+            });
+            match opened {
+                // A compaction of the writer removed a segment while it was replayed.
+                Err(KvsError::Io(ref e))
+                    if read_only
+                        && e.kind() == io::ErrorKind::NotFound
+                        && attempts < READ_ONLY_OPEN_ATTEMPTS =>
+                {
+                    attempts += 1
+                }
+                opened => break opened?,
+            }
+        };
+        let Replayed {
+            index,
+            gen_list,
+            current_gen,
+            need_compacted,
+            last_seq,
+        } = replayed;
 
-        // Use log path init the reader and writer.
-        let writer = new_log_file(&log_path)?;
-        let mut reader = BufReaderWithPos::new(File::open(&log_path)?)?;
+        if !read_only {
+            // Every segment is in the current format now, and records written
+            // with a key need it from now on.
+            let manifest = Manifest {
+                format_version: FORMAT_VERSION,
+                encrypted: options.encryption_key.is_some()
+                    || loaded_manifest.as_ref().map(|m| m.encrypted) == Some(true),
+                compress_above: options.compress_above,
+                ..loaded_manifest
+                    .clone()
+                    .unwrap_or_else(|| Manifest::new(EngineKind::Kvs, FORMAT_VERSION))
+            };
+            if loaded_manifest.as_ref() != Some(&manifest) {
+                manifest.store(&path)?;
+            }
+        } else if gen_list.is_empty() {
+            return Err(KvsError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no kvs store in {}", path.display()),
+            )));
+        }
 
-        let mut index = SkipMap::new();
-        let mut need_compacted = 0;
-        need_compacted += load(&mut reader, &mut index)?;
+        // Keep appending to the newest segment, a read-only store never writes it.
+        let writer = if read_only {
+            BufWriterWithPos::new(File::open(log_path(&path, current_gen))?)?
+        } else {
+            new_log_file(&path, current_gen)?
+        };
+        let log_sync = Arc::new(LogSync::new(writer.get_ref().try_clone()?));
 
         let index = Arc::new(index);
+        let cache = Arc::new(ValueCache::new(options.cache_size));
+        let history = Arc::new(VersionHistory::new());
 
-        let writer = KvStoreWriter {
+        let (compactor, receiver) = compaction_channel();
+        let compaction_lock = Arc::new(Mutex::new(()));
+
+        let writer = Arc::new(Mutex::new(KvStoreWriter {
             path: Arc::clone(&path),
-            reader,
             writer,
+            current_gen,
+            active_gen,
             index: Arc::clone(&index),
+            cache: Arc::clone(&cache),
+            history: Arc::clone(&history),
+            last_seq,
             need_compacted,
+            compactor,
+            compaction_lock: Arc::clone(&compaction_lock),
+            codec,
+            durability: options.durability,
+            log_sync: Arc::clone(&log_sync),
+        }));
+        let compactor = Arc::new(spawn_compactor(&writer, reader.clone(), receiver)?);
+        // Expired keys are hidden from reads, a read-only store leaves them in memory.
+        let reaper = if read_only {
+            None
+        } else {
+            Some(Arc::new(spawn_reaper(&writer, options.reap_interval)?))
         };
-
-        let reader = KvStoreReader {
-            path: Arc::clone(&path),
-            reader: RefCell::new(BufReaderWithPos::new(File::open(&log_path)?)?),
+        let syncer = match options.durability {
+            Durability::Interval(interval) if !read_only => {
+                Some(Arc::new(spawn_syncer(&log_sync, interval)?))
+            }
+            _ => None,
         };
 
         Ok(MyKvStore {
             path,
             reader,
-            writer: Arc::new(Mutex::new(writer)),
+            writer,
+            commit_queue: Arc::new(CommitQueue::new()),
             index,
+            cache,
+            history,
+            compaction_lock,
+            _compactor: compactor,
+            _reaper: reaper,
+            durability: options.durability,
+            log_sync,
+            _syncer: syncer,
+            read_only,
+            _lock: lock,
         })
     }
 }
 
+impl MyKvStore {
+    /// Rewrites the log of a closed store from `old_key` to `new_key`.
+    ///
+    /// `None` stands for plaintext records, so this also encrypts or decrypts
+    /// a whole store. Every segment and hint file is rewritten aside before the
+    /// first one is replaced, the hint files with the new record positions.
+    /// The old hint files are removed first, so a crash in between leaves the
+    /// rewritten segments to be replayed. Records already under `new_key` are kept as they
+    /// are, so rotating again finishes a rotation interrupted by a crash.
+    /// The manifest records the new key is needed once every segment is replaced.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Locked` if a store has the directory open.
+    ///
+    /// It returns `KvsError::Manifest` if the directory belongs to another engine.
+    ///
+    /// It returns `KvsError::Encryption` if a record can not be decrypted with either key.
+    ///
+    /// It returns `KvsError::CorruptedLog` if a record in the middle of the log is corrupted.
+    pub fn rotate_key(
+        path: impl Into<PathBuf>,
+        old_key: Option<&EncryptionKey>,
+        new_key: Option<&EncryptionKey>,
+    ) -> Result<()> {
+        let path = path.into();
+        let _lock = DirLock::acquire(&path)?;
+        let mut manifest = match Manifest::load(&path)? {
+            Some(manifest) => manifest,
+            None => Manifest::new(EngineKind::Kvs, FORMAT_VERSION),
+        };
+        manifest.check_engine(EngineKind::Kvs)?;
+        let old_codec = RecordCodec::new(None, old_key);
+        let new_codec = RecordCodec::new(None, new_key);
+        adopt_legacy_log(&path, &old_codec)?;
+        let gen_list = sorted_gen_list(&path)?;
+        for &gen in &gen_list {
+            upgrade_log(gen, &path, RecoveryMode::Strict, &old_codec)?;
+        }
+
+        let last_gen = gen_list.last().copied();
+        for &gen in &gen_list {
+            let last = Some(gen) == last_gen;
+            let rewritten = rewrite_segment(gen, &path, last, &old_codec, &new_codec)
+                .and_then(|moved| rewrite_hints(gen, &path, &moved, &old_codec, &new_codec));
+            if let Err(e) = rewritten {
+                for &gen in &gen_list {
+                    remove_if_exists(&rewritten_path(&path, gen))?;
+                    remove_if_exists(&rewritten_hint_path(&path, gen))?;
+                }
+                return Err(e);
+            }
+        }
+        // The old positions in the hint files are off once the segments are replaced.
+        for &gen in &gen_list {
+            remove_if_exists(&hint_path(&path, gen))?;
+        }
+        for &gen in &gen_list {
+            fs::rename(rewritten_path(&path, gen), log_path(&path, gen))?;
+        }
+        for &gen in &gen_list {
+            let rewritten_hint = rewritten_hint_path(&path, gen);
+            if rewritten_hint.exists() {
+                fs::rename(rewritten_hint, hint_path(&path, gen))?;
+            }
+        }
+        manifest.format_version = FORMAT_VERSION;
+        manifest.encrypted = new_key.is_some();
+        manifest.store(&path)?;
+        info!("Rewrote {} log segments with the new key", gen_list.len());
+        Ok(())
+    }
+
+    /// Returns the hit and miss counters of the value cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Returns a snapshot reading the store as it is now.
+    ///
+    /// Every write returned so far is visible through the snapshot, no later one is.
+    pub fn snapshot(&self) -> Snapshot {
+        // No write is half applied to the index while the writer lock is held.
+        let writer = self.writer.lock().unwrap();
+        self.history.register(writer.last_seq);
+        Snapshot::new(self.clone(), writer.last_seq)
+    }
+}
+
 impl KvEngine for MyKvStore {
-    /// Sets the value of a string key to a string.
+    /// Sets the value of a key to a value.
     ///
     /// If the key already exists, the previous value will be overwritten.
     ///
     /// # Errors
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.submit(Command::set(key, value)).map(|_| ())
     }
 
-    /// Gets the string value of a given string key.
+    /// Sets the value of a key to a value that expires after `ttl`.
+    ///
+    /// The expiry is stored in the log, so it survives a restart.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.submit(Command::set_expiring(key, value, expires_at))
+            .map(|_| ())
+    }
+
+    /// Gets the value of a given key.
     ///
     /// Returns `None` if the given key does not exist.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::IncorrectCommandType` if the given command is incorrect.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            if let Command::Set { value, .. } = self.reader.read_command(*cmd_pos.value())? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::IncorrectCommandType)
-            }
-        } else {
-            Ok(None)
-        }
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.read_value(&key)
     }
 
     /// Remove a given key.
@@ -126,52 +430,883 @@ impl KvEngine for MyKvStore {
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        self.submit(Command::remove(key)).map(|_| ())
+    }
+
+    /// Applies every write of the batch atomically.
+    ///
+    /// The batch is written as a single log record, so the log replay applies
+    /// all of it or none of it.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.ops.is_empty() {
+            return Ok(());
+        }
+        let cmds = batch
+            .ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => Command::set(key, value),
+                BatchOp::Remove { key } => Command::remove(key),
+            })
+            .collect();
+        self.submit(Command::batch(cmds)).map(|_| ())
+    }
+
+    /// Sets the value of a key to `new` if its current value is `expected`.
+    ///
+    /// The comparison is done under the writer lock, so no other write can
+    /// slip in between.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading or writing the log.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.write(|writer| {
+            // No index update is in flight while the writer lock is held.
+            let current =
+                self.read_value_with(&key, || self.index.get(&key).map(|e| *e.value()))?;
+            if current != expected {
+                return Ok(false);
+            }
+            match (expected, new) {
+                (_, Some(value)) => writer.set(key, value)?,
+                (Some(_), None) => writer.remove(key)?,
+                (None, None) => {}
+            }
+            Ok(true)
+        })
+    }
+
+    /// Returns up to `limit` key/value pairs with keys in `[start, end)`, in key order.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::IncorrectCommandType` if the given command is incorrect.
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let end = match end {
+            Some(end) if end <= start => return Ok(Vec::new()),
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        let now = now_millis();
+        let mut pairs = Vec::new();
+        for entry in self.index.range((Bound::Included(start), end)) {
+            if pairs.len() >= limit {
+                break;
+            }
+            if entry.value().is_expired(now) {
+                continue;
+            }
+            // The key may have been removed since the range lookup.
+            if let Some(value) = self.read_value(entry.key())? {
+                pairs.push((entry.key().clone(), value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Flushes the active log segment and syncs it to disk.
+    fn flush(&self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.writer.lock().unwrap().flush()
+    }
+
+    /// Copies the manifest, log segments and hint files to `dest`.
+    ///
+    /// The active segment is sealed first, so the backup holds every write
+    /// returned so far. Sealed segments never change, so they are hard linked
+    /// where the file system allows it. The newest one is copied, as a store
+    /// opened on the backup appends to it.
+    fn backup(&self, dest: &Path) -> Result<()> {
+        // The active segment of a read-only store can not be sealed.
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        create_backup_dir(dest)?;
+        fs::copy(manifest_path(&self.path), manifest_path(dest))?;
+        // No compaction writes or removes a segment until the backup is done.
+        let _compaction = self.compaction_lock.lock().unwrap();
+        let sealed_gen = self.writer.lock().unwrap().seal()?;
+        let gen_list: Vec<u64> = sorted_gen_list(&self.path)?
+            .into_iter()
+            .filter(|&gen| gen <= sealed_gen)
+            .collect();
+        for &gen in &gen_list {
+            let hint = hint_path(&self.path, gen);
+            if hint.exists() {
+                link_or_copy(&hint, &hint_path(dest, gen))?;
+            }
+            if gen == sealed_gen {
+                fs::copy(log_path(&self.path, gen), log_path(dest, gen))?;
+            } else {
+                link_or_copy(&log_path(&self.path, gen), &log_path(dest, gen))?;
+            }
+        }
+        info!(
+            "Backed up {} log segments to {}",
+            gen_list.len(),
+            dest.display()
+        );
+        Ok(())
     }
 }
 
-/// Load the whole log file and store value locations in the index map.
+impl MyKvStore {
+    // Queue a write to share its flush with the concurrent ones, then wait for
+    // its sync in group commit mode. Returns the position of its record.
+    fn submit(&self, cmd: Command) -> Result<CommandPos> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let committed = self.commit_queue.submit(cmd, &self.writer)?;
+        if self.durability == Durability::GroupCommit {
+            self.log_sync.wait_synced(committed.seq)?;
+        }
+        Ok(committed.pos)
+    }
+
+    // Run a write under the writer lock, then wait for its sync in group commit mode.
+    fn write<T>(&self, write: impl FnOnce(&mut KvStoreWriter) -> Result<T>) -> Result<T> {
+        if self.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        let (result, seq) = {
+            let mut writer = self.writer.lock().unwrap();
+            let result = write(&mut writer)?;
+            (result, self.log_sync.written())
+        };
+        if self.durability == Durability::GroupCommit {
+            self.log_sync.wait_synced(seq)?;
+        }
+        Ok(result)
+    }
+
+    /// Reads the current value of the key from the log.
+    fn read_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read_value_with(key, || {
+            self.index
+                .get(key)
+                .map(|entry| *entry.value())
+                .or_else(|| self.lookup_again(key))
+        })
+    }
+
+    /// Reads the value of the key a snapshot at `seq` sees.
+    pub(crate) fn read_value_at(&self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>> {
+        self.read_value_with(key, || {
+            let current = self.index.get(key).map(|entry| *entry.value());
+            self.history.visible(key, seq, current).or_else(|| {
+                if current.is_some() {
+                    return None;
+                }
+                self.history.visible(key, seq, self.lookup_again(key))
+            })
+        })
+    }
+
+    /// Returns up to `limit` key/value pairs with keys in `[start, end)` a
+    /// snapshot at `seq` sees, in key order.
+    pub(crate) fn scan_at(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+        seq: u64,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let end = match end {
+            Some(end) if end <= start => return Ok(Vec::new()),
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
+        let range = (Bound::Included(start), end);
+
+        let keys = {
+            // Neither the index nor the history change while the writer lock is
+            // held, so no key is missed while it moves from one to the other.
+            let _writer = self.writer.lock().unwrap();
+            // Keys removed since the snapshot are only left in the history.
+            let mut history_keys = self.history.keys_in(range.clone()).into_iter().peekable();
+            let mut index_keys = self
+                .index
+                .range(range)
+                .map(|entry| entry.key().clone())
+                .peekable();
+            let now = now_millis();
+            let mut keys = Vec::new();
+            while keys.len() < limit {
+                let order = match (index_keys.peek(), history_keys.peek()) {
+                    (Some(index_key), Some(history_key)) => index_key.cmp(history_key),
+                    (Some(_), None) => cmp::Ordering::Less,
+                    (None, Some(_)) => cmp::Ordering::Greater,
+                    (None, None) => break,
+                };
+                let key = match order {
+                    cmp::Ordering::Less => index_keys.next(),
+                    cmp::Ordering::Greater => history_keys.next(),
+                    cmp::Ordering::Equal => {
+                        history_keys.next();
+                        index_keys.next()
+                    }
+                }
+                .unwrap();
+                let current = self.index.get(&key).map(|entry| *entry.value());
+                match self.history.visible(&key, seq, current) {
+                    Some(cmd_pos) if !cmd_pos.is_expired(now) => keys.push(key),
+                    _ => {}
+                }
+            }
+            keys
+        };
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.read_value_at(&key, seq)? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Unregisters a dropped snapshot.
+    pub(crate) fn release_snapshot(&self, seq: u64) {
+        self.history.release(seq);
+    }
+
+    // Swapping the position of a key removes its entry before inserting the new
+    // one. Index updates hold the writer lock, so a key missing from the index
+    // is looked up again once the swap is done.
+    fn lookup_again(&self, key: &[u8]) -> Option<CommandPos> {
+        let _writer = self.writer.lock().unwrap();
+        self.index.get(key).map(|entry| *entry.value())
+    }
+
+    /// Reads the value of the record `lookup` finds for the key.
+    ///
+    /// The key is looked up again if a compaction removed the record meanwhile.
+    fn read_value_with(
+        &self,
+        key: &[u8],
+        lookup: impl Fn() -> Option<CommandPos>,
+    ) -> Result<Option<Vec<u8>>> {
+        loop {
+            let cmd_pos = match lookup() {
+                Some(cmd_pos) => cmd_pos,
+                None => return Ok(None),
+            };
+            // The reaper removes expired keys only from time to time.
+            if cmd_pos.is_expired(now_millis()) {
+                return Ok(None);
+            }
+            if let Some(value) = self.cache.get(key, cmd_pos) {
+                return Ok(Some(value));
+            }
+            match self.reader.read_command(cmd_pos) {
+                Ok(cmd) => {
+                    let value = cmd.into_value(key).ok_or(KvsError::IncorrectCommandType)?;
+                    self.cache.insert(key, cmd_pos, &value);
+                    return Ok(Some(value));
+                }
+                // The segment was removed by a compaction after the index lookup,
+                // the index already points into the compaction segment.
+                Err(KvsError::Io(ref e))
+                    if e.kind() == io::ErrorKind::NotFound
+                        && cmd_pos.gen < self.reader.safe_point.load(Ordering::SeqCst) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Points the index at the keys written by the command at `cmd_pos`.
+///
+/// The replaced versions go to the `history` first, if there is one.
+///
+/// Returns the number of bytes the command made stale.
+pub fn index_command(
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    history: Option<&VersionHistory>,
+    cmd: Command,
+    cmd_pos: CommandPos,
+) -> u64 {
+    match cmd {
+        Command::Set { .. } | Command::SetExpiring { .. } => {
+            index_value(index, history, cmd, cmd_pos).unwrap_or(cmd_pos.len)
+        }
+        Command::Remove { key } => index_remove(index, history, &key, cmd_pos) + cmd_pos.len,
+        Command::Batch { cmds } => {
+            let mut stale = 0;
+            let mut live = false;
+            for cmd in cmds {
+                match cmd {
+                    Command::Remove { key } => stale += index_remove(index, history, &key, cmd_pos),
+                    cmd => {
+                        if let Some(replaced) = index_value(index, history, cmd, cmd_pos) {
+                            stale += replaced;
+                            live = true;
+                        }
+                    }
+                }
+            }
+            // The batch record stays as long as it holds the value of a key.
+            if live {
+                stale
+            } else {
+                stale + cmd_pos.len
+            }
+        }
+        Command::Sequenced { seq, cmd } => {
+            index_command(index, history, *cmd, CommandPos { seq, ..cmd_pos })
+        }
+    }
+}
+
+// Stores the position of a value written by the command.
+//
+// Returns the length of the record the key pointed at before, or `None` if
+// the key holds a newer version already.
+fn index_value(
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    history: Option<&VersionHistory>,
+    cmd: Command,
+    cmd_pos: CommandPos,
+) -> Option<u64> {
+    match cmd {
+        Command::Set { key, .. } => index_set(index, history, key, cmd_pos),
+        Command::SetExpiring {
+            key, expires_at, ..
+        } => {
+            let cmd_pos = CommandPos {
+                expires_at: Some(expires_at),
+                ..cmd_pos
+            };
+            index_set(index, history, key, cmd_pos)
+        }
+        cmd => Some(index_command(index, history, cmd, cmd_pos)),
+    }
+}
+
+// Whether the key holds a version written after the command at `cmd_pos`.
+//
+// A compaction segment holds the live records in key order, with the versions
+// kept for snapshots after them, so its records are not in the order they
+// were written. Records written before sequence numbers all have 0, and the
+// later one of them wins.
+fn holds_newer(index: &SkipMap<Vec<u8>, CommandPos>, key: &[u8], cmd_pos: CommandPos) -> bool {
+    index.get(key).map(|entry| entry.value().seq > cmd_pos.seq) == Some(true)
+}
+
+// Points the key at `cmd_pos`, returns the length of the record it pointed at
+// before, or `None` if it holds a newer version.
+fn index_set(
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    history: Option<&VersionHistory>,
+    key: Vec<u8>,
+    cmd_pos: CommandPos,
+) -> Option<u64> {
+    if holds_newer(index, &key, cmd_pos) {
+        return None;
+    }
+    let stale = match replace_version(index, history, &key, cmd_pos) {
+        Some(old_pos) => old_pos.len,
+        None => 0,
+    };
+    index.insert(key, cmd_pos);
+    Some(stale)
+}
+
+// Removes the key, returns the length of the record it pointed at.
+//
+// A key holding a newer version is kept.
+fn index_remove(
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    history: Option<&VersionHistory>,
+    key: &[u8],
+    cmd_pos: CommandPos,
+) -> u64 {
+    if holds_newer(index, key, cmd_pos) {
+        return 0;
+    }
+    let stale = match replace_version(index, history, key, cmd_pos) {
+        Some(old_pos) => old_pos.len,
+        None => 0,
+    };
+    index.remove(key);
+    stale
+}
+
+// Hands the version of the key the command at `cmd_pos` replaces to the history.
+// Returns its position, unless it was written by the same command.
+fn replace_version(
+    index: &SkipMap<Vec<u8>, CommandPos>,
+    history: Option<&VersionHistory>,
+    key: &[u8],
+    cmd_pos: CommandPos,
+) -> Option<CommandPos> {
+    // A batch may write the same key twice.
+    let old_pos = index
+        .get(key)
+        .map(|entry| *entry.value())
+        .filter(|&old_pos| old_pos != cmd_pos)?;
+    if let Some(history) = history {
+        history.replace(key, old_pos, cmd_pos.seq);
+    }
+    Some(old_pos)
+}
+
+// The index and counters replayed from the log segments of a directory.
+struct Replayed {
+    index: SkipMap<Vec<u8>, CommandPos>,
+    gen_list: Vec<u64>,
+    // Generation of the newest segment, which the writer keeps appending to.
+    current_gen: u64,
+    need_compacted: u64,
+    last_seq: u64,
+}
+
+/// Replays the log segments of the directory into a new index.
+///
+/// Segments are brought to the current format first. A read-only replay
+/// changes no file, so it refuses a segment that needs an upgrade with
+/// `KvsError::ReadOnly`, and skips the empty one a writer is creating.
+fn replay(path: &Path, options: &KvStoreOptions, codec: &RecordCodec) -> Result<Replayed> {
+    let mut index = SkipMap::new();
+    let mut need_compacted = 0;
+    let mut last_seq = 0;
+
+    let gen_list = sorted_gen_list(path)?;
+    let last_gen = gen_list.last().copied();
+    let mut replayed_gens = Vec::with_capacity(gen_list.len());
+    for gen in gen_list {
+        if options.read_only {
+            match read_log_header(&mut File::open(log_path(path, gen))?)? {
+                LogHeader::Current => {}
+                LogHeader::Empty => continue,
+                _ => return Err(KvsError::ReadOnly),
+            }
+        } else {
+            upgrade_log(gen, path, options.recovery_mode, codec)?;
+        }
+        // Compaction segments come with a hint file, so only the segments
+        // written since the last compaction are replayed.
+        let segment_len = fs::metadata(log_path(path, gen))?.len();
+        need_compacted += match read_hint_file(path, gen, segment_len, codec)? {
+            Some(hints) => load_hints(hints, &mut index, &mut last_seq),
+            None => load(
+                gen,
+                path,
+                &mut index,
+                &mut last_seq,
+                Some(gen) == last_gen,
+                options,
+                codec,
+            )?,
+        };
+        replayed_gens.push(gen);
+    }
+    Ok(Replayed {
+        index,
+        current_gen: replayed_gens.last().copied().unwrap_or(1),
+        gen_list: replayed_gens,
+        need_compacted,
+        last_seq,
+    })
+}
+
+/// Load the whole log segment and store value locations in the index map.
+///
+/// A torn record at the end of the `last` segment is truncated. A corrupted
+/// record elsewhere is skipped or refused depending on the `RecoveryMode`.
+/// Earlier segments were synced when they were sealed, so a record running
+/// past their end is corrupted too, most likely its length. The rest of the
+/// segment can not be read then.
 ///
 /// Returns how many bytes can be saved after a compaction.
 fn load(
-    reader: &mut BufReaderWithPos<File>,
-    index: &mut SkipMap<String, CommandPos>,
+    gen: u64,
+    path: &Path,
+    index: &mut SkipMap<Vec<u8>, CommandPos>,
+    last_seq: &mut u64,
+    last: bool,
+    options: &KvStoreOptions,
+    codec: &RecordCodec,
 ) -> Result<u64> {
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    let log_path = log_path(path, gen);
+    let file = File::open(&log_path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReaderWithPos::new(file)?;
+    reader.seek(SeekFrom::Start(LOG_HEADER_LEN))?;
     let mut need_compacted = 0;
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.get(&key) {
-                    need_compacted += old_cmd.value().len;
-                }
-                index.insert(key, (pos..new_pos).into());
+    while reader.pos < file_len {
+        let pos = reader.pos;
+        match read_frame(
+            &mut reader,
+            file_len - pos,
+            codec,
+            RecordAddress::Log { gen, pos },
+        )? {
+            Frame::Valid(payload) => {
+                let cmd = decode(&payload)?;
+                *last_seq = cmp::max(*last_seq, cmd.seq());
+                let cmd_pos = (gen, pos..reader.pos).into();
+                need_compacted += index_command(index, None, cmd, cmd_pos);
             }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    need_compacted += old_cmd.value().len;
+            // The writer of the directory may be appending the record.
+            Frame::Torn if options.read_only => break,
+            Frame::Torn if last => {
+                // A corrupted length reads as a torn record, the records after it tell.
+                if options.recovery_mode == RecoveryMode::Strict
+                    && valid_record_after(&log_path, pos)?
+                {
+                    return Err(KvsError::CorruptedLog { gen, pos });
                 }
-                need_compacted += new_pos - pos;
+                warn!(
+                    "Truncating torn record at the end of log segment {} at offset {}",
+                    gen, pos
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(&log_path)?
+                    .set_len(pos)?;
+                break;
             }
+            Frame::Torn => match options.recovery_mode {
+                RecoveryMode::Strict => return Err(KvsError::CorruptedLog { gen, pos }),
+                RecoveryMode::Tolerant => {
+                    warn!(
+                        "Skipping the rest of log segment {} from the corrupted record at offset {}",
+                        gen, pos
+                    );
+                    need_compacted += file_len - pos;
+                    break;
+                }
+            },
+            Frame::Corrupted { len } => match options.recovery_mode {
+                RecoveryMode::Strict => return Err(KvsError::CorruptedLog { gen, pos }),
+                RecoveryMode::Tolerant => {
+                    warn!(
+                        "Skipping corrupted record in log segment {} at offset {}",
+                        gen, pos
+                    );
+                    need_compacted += len;
+                }
+            },
         }
-        pos = new_pos;
     }
     Ok(need_compacted)
 }
 
-/// Create a new log file.
+/// Store the value locations of a hint file in the index map.
+///
+/// Returns how many bytes can be saved after a compaction.
+// Whether a valid record follows the torn one at `pos` of the segment.
+fn valid_record_after(log_path: &Path, pos: u64) -> Result<bool> {
+    let mut file = File::open(log_path)?;
+    file.seek(SeekFrom::Start(pos))?;
+    let mut rest = Vec::new();
+    file.read_to_end(&mut rest)?;
+    Ok(holds_valid_frame(&rest))
+}
+
+fn load_hints(hints: Hints, index: &mut SkipMap<Vec<u8>, CommandPos>, last_seq: &mut u64) -> u64 {
+    let mut need_compacted = 0;
+    for (key, cmd_pos) in hints {
+        *last_seq = cmp::max(*last_seq, cmd_pos.seq);
+        if let Some(old_cmd) = index.get(&key) {
+            need_compacted += old_cmd.value().len;
+        }
+        index.insert(key, cmd_pos);
+    }
+    need_compacted
+}
+
+/// Brings the log segment to the current format version.
+///
+/// A segment of json records is rewritten with binary records, a torn header
+/// left by a crash right after creating the segment is rewritten. A segment
+/// of an earlier binary format version only gets its format version bumped.
+fn upgrade_log(
+    gen: u64,
+    path: &Path,
+    recovery_mode: RecoveryMode,
+    codec: &RecordCodec,
+) -> Result<()> {
+    let log_path = log_path(path, gen);
+    let mut file = File::open(&log_path)?;
+    match read_log_header(&mut file)? {
+        LogHeader::Current => Ok(()),
+        LogHeader::Outdated => {
+            let mut file = OpenOptions::new().write(true).open(&log_path)?;
+            write_format_version(&mut file)?;
+            Ok(())
+        }
+        LogHeader::Empty => {
+            let mut file = File::create(&log_path)?;
+            write_log_header(&mut file)?;
+            Ok(())
+        }
+        LogHeader::Json => {
+            info!(
+                "Migrating log segment {} to format version {}",
+                gen, FORMAT_VERSION
+            );
+            let file_len = file.metadata()?.len();
+            let mut reader = BufReaderWithPos::new(file)?;
+            reader.seek(SeekFrom::Start(0))?;
+
+            let temp_path = log_path.with_extension("log.temp");
+            let mut writer = BufWriterWithPos::new(File::create(&temp_path)?)?;
+            write_log_header(&mut writer)?;
+            while reader.pos < file_len {
+                let pos = reader.pos;
+                let at = RecordAddress::Log { gen, pos };
+                match read_frame(&mut reader, file_len - pos, &RecordCodec::default(), at)? {
+                    Frame::Valid(payload) => {
+                        let at = RecordAddress::Log {
+                            gen,
+                            pos: writer.pos,
+                        };
+                        write_record(&mut writer, &decode_json(&payload)?, codec, at)?
+                    }
+                    Frame::Torn => break,
+                    Frame::Corrupted { .. } if recovery_mode == RecoveryMode::Strict => {
+                        fs::remove_file(&temp_path)?;
+                        return Err(KvsError::CorruptedLog { gen, pos });
+                    }
+                    Frame::Corrupted { .. } => warn!(
+                        "Skipping corrupted record in log segment {} at offset {}",
+                        gen, pos
+                    ),
+                }
+            }
+            writer.flush()?;
+            fs::rename(temp_path, log_path)?;
+            Ok(())
+        }
+    }
+}
+
+/// Writes the records of the log segment aside, decrypted with `old_codec` and
+/// encrypted with `new_codec`. Compressed records stay compressed.
+///
+/// A torn record at the end of the `last` segment is dropped, unless valid
+/// records follow it.
+///
+/// Returns the records by their old position.
+fn rewrite_segment(
+    gen: u64,
+    path: &Path,
+    last: bool,
+    old_codec: &RecordCodec,
+    new_codec: &RecordCodec,
+) -> Result<MovedRecords> {
+    let file = File::open(log_path(path, gen))?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReaderWithPos::new(file)?;
+    reader.seek(SeekFrom::Start(LOG_HEADER_LEN))?;
+
+    let mut writer = BufWriterWithPos::new(File::create(rewritten_path(path, gen))?)?;
+    write_log_header(&mut writer)?;
+    let mut records = HashMap::new();
+    while reader.pos < file_len {
+        let pos = reader.pos;
+        match read_stored_frame(&mut reader, file_len - pos)? {
+            Frame::Valid(stored) => {
+                let at = RecordAddress::Log { gen, pos };
+                let plain = old_codec
+                    .decrypt(stored.clone(), at)
+                    .or_else(|e| new_codec.decrypt(stored, at).map_err(|_| e))?;
+                let new_pos = writer.pos;
+                let new_at = RecordAddress::Log { gen, pos: new_pos };
+                write_stored_frame(&mut writer, &new_codec.encrypt(plain, new_at)?)?;
+                records.insert(pos, new_pos..writer.pos);
+            }
+            Frame::Torn if last && !valid_record_after(&log_path(path, gen), pos)? => break,
+            Frame::Torn | Frame::Corrupted { .. } => {
+                return Err(KvsError::CorruptedLog { gen, pos })
+            }
+        }
+    }
+    writer.sync()?;
+    Ok(MovedRecords {
+        segment_len: file_len,
+        records,
+    })
+}
+
+// The records of a log segment rewritten with a new key.
+struct MovedRecords {
+    // Length of the segment before it was rewritten.
+    segment_len: u64,
+    // The new position of every record by its old one.
+    records: HashMap<u64, Range<u64>>,
+}
+
+// Writes the hint file of the log segment aside, with the positions of its
+// rewritten records and encrypted with `new_codec`.
+//
+// A segment without a usable hint file is replayed by the next open instead.
+fn rewrite_hints(
+    gen: u64,
+    path: &Path,
+    moved: &MovedRecords,
+    old_codec: &RecordCodec,
+    new_codec: &RecordCodec,
+) -> Result<()> {
+    // A rotation interrupted by a crash may have replaced the hint file already.
+    let hints = match read_hint_file(path, gen, moved.segment_len, old_codec) {
+        Err(KvsError::Encryption(_)) => read_hint_file(path, gen, moved.segment_len, new_codec),
+        hints => hints,
+    };
+    let hints = match hints {
+        Ok(Some(hints)) => hints,
+        Ok(None) => return Ok(()),
+        Err(KvsError::Encryption(_)) => {
+            warn!("Dropping undecryptable hint file of log segment {}", gen);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let mut entries = Vec::with_capacity(hints.len());
+    for (key, cmd_pos) in hints {
+        let new_range = match moved.records.get(&cmd_pos.pos) {
+            Some(new_range) => new_range.clone(),
+            None => {
+                warn!(
+                    "Dropping hint file of log segment {} without its records",
+                    gen
+                );
+                return Ok(());
+            }
+        };
+        let cmd_pos = CommandPos {
+            expires_at: cmd_pos.expires_at,
+            seq: cmd_pos.seq,
+            ..(gen, new_range).into()
+        };
+        entries.push((key, cmd_pos));
+    }
+    write_hints(
+        &rewritten_hint_path(path, gen),
+        gen,
+        entries.iter().map(|(key, cmd_pos)| (key, cmd_pos)),
+        new_codec,
+    )
+}
+
+// Hard links the file, or copies it if the file system can not link it there.
+fn link_or_copy(src: &Path, dst: &Path) -> Result<()> {
+    if fs::hard_link(src, dst).is_err() {
+        fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+// Returns the path a log segment is rewritten to before it replaces the segment.
+fn rewritten_path(dir: &Path, gen: u64) -> PathBuf {
+    log_path(dir, gen).with_extension("log.rekey")
+}
+
+// Returns the path a hint file is rewritten to before it replaces the hint file.
+fn rewritten_hint_path(dir: &Path, gen: u64) -> PathBuf {
+    hint_path(dir, gen).with_extension("hint.rekey")
+}
+
+/// Returns sorted generation numbers of the log segments in the given directory.
+pub fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .map(|s| s.trim_end_matches(".log"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+/// Returns the path of the log segment with the given generation number.
+pub fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+/// Converts the single json log file of earlier versions to the first segment.
+///
+/// A torn json object at the end of the legacy log is dropped.
+fn adopt_legacy_log(path: &Path, codec: &RecordCodec) -> Result<()> {
+    let legacy_path = path.join(LEGACY_LOG_FILE_NAME);
+    if !legacy_path.exists() || !sorted_gen_list(path)?.is_empty() {
+        return Ok(());
+    }
+
+    let temp_path = log_path(path, 1).with_extension("log.temp");
+    let mut writer = BufWriterWithPos::new(File::create(&temp_path)?)?;
+    write_log_header(&mut writer)?;
+    let reader = BufReader::new(File::open(&legacy_path)?);
+    for cmd in Deserializer::from_reader(reader).into_iter::<JsonCommand>() {
+        match cmd {
+            Ok(cmd) => {
+                let at = RecordAddress::Log {
+                    gen: 1,
+                    pos: writer.pos,
+                };
+                write_record(&mut writer, &cmd.into(), codec, at)?
+            }
+            Err(e) if e.is_eof() => {
+                warn!(
+                    "Dropping torn record at the end of {}",
+                    LEGACY_LOG_FILE_NAME
+                );
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    writer.flush()?;
+    fs::rename(temp_path, log_path(path, 1))?;
+    fs::remove_file(legacy_path)?;
+    Ok(())
+}
+
+/// Create a new log segment with the given generation number.
+///
+/// The segment header is written if the segment is empty.
 ///
 /// Returns the writer to the log.
-pub fn new_log_file(path: &Path) -> Result<BufWriterWithPos<File>> {
-    let writer = BufWriterWithPos::new(
+pub fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
+    let mut writer = BufWriterWithPos::new(
         OpenOptions::new()
             .create(true)
             .write(true)
             .append(true)
-            .open(&path)?,
+            .open(log_path(path, gen))?,
     )?;
+    if writer.pos == 0 {
+        write_log_header(&mut writer)?;
+        writer.flush()?;
+    }
     Ok(writer)
 }
@@ -1,38 +1,199 @@
-use crate::{KvEngine, KvsError, Result};
-use sled::{Db, Tree};
+use crate::engine_trait::{create_backup_dir, BatchOp};
+use crate::{Durability, EngineKind, KvEngine, KvsError, Manifest, Result, WriteBatch};
+use sled::{Batch, Db, Iter, Tree};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 /// Wrapper of `sled::Db`.
 #[derive(Clone)]
-pub struct SledKvs(Db);
+pub struct SledKvs {
+    db: Db,
+    durability: Durability,
+    // Read by writes and written by a backup, so a backup sees no write
+    // half done. Sled has no snapshots to copy from.
+    writes: Arc<RwLock<()>>,
+}
 
 impl SledKvs {
-    /// Creates a `SledKvsEngine` from `sled::Db`.
+    /// Creates a `SledKvsEngine` from `sled::Db`, flushing every write.
     pub fn new(db: Db) -> Self {
-        SledKvs(db)
+        SledKvs::with_durability(db, Durability::EveryWrite)
+    }
+
+    /// Creates a `SledKvsEngine` from `sled::Db`, flushing writes as `durability` asks.
+    ///
+    /// With `Durability::None` or `Durability::Interval` writes are left to
+    /// the periodic flush of sled, whose period is set by its `flush_every_ms`
+    /// option. Sled lets concurrent flushes share the sync, so
+    /// `Durability::GroupCommit` flushes every write.
+    pub fn with_durability(db: Db, durability: Durability) -> Self {
+        SledKvs {
+            db,
+            durability,
+            writes: Arc::default(),
+        }
+    }
+
+    /// Opens a sled database at the given path with the period of its flush
+    /// set after `durability` and a page cache of `cache_size` bytes, or of
+    /// the default size of sled.
+    ///
+    /// The manifest of the directory is created if it has none.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Manifest` if the directory belongs to another engine.
+    pub fn open(
+        path: impl AsRef<Path>,
+        durability: Durability,
+        cache_size: Option<u64>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        fs::create_dir_all(path)?;
+        match Manifest::load(path)? {
+            Some(manifest) => manifest.check_engine(EngineKind::Sled)?,
+            None => Manifest::new(EngineKind::Sled, 0).store(path)?,
+        }
+        let mut config = sled::Config::new().path(path);
+        if let Some(cache_size) = cache_size {
+            config = config.cache_capacity(cache_size);
+        }
+        if let Durability::Interval(interval) = durability {
+            config = config.flush_every_ms(Some(interval.as_millis() as u64));
+        }
+        Ok(SledKvs::with_durability(config.open()?, durability))
+    }
+
+    // Flush a write if every write has to be synced.
+    fn commit(&self) -> Result<()> {
+        match self.durability {
+            Durability::EveryWrite | Durability::GroupCommit => {
+                self.db.flush()?;
+            }
+            Durability::None | Durability::Interval(_) => {}
+        }
+        Ok(())
     }
 }
 
 impl KvEngine for SledKvs {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let tree: &Tree = &self.0;
-        tree.insert(key, value.into_bytes()).map(|_| ())?;
-        tree.flush()?;
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let tree: &Tree = &self.db;
+        {
+            let _write = self.writes.read().unwrap();
+            tree.insert(key, value).map(|_| ())?;
+        }
+        self.commit()?;
+        Ok(())
+    }
+
+    fn set_with_ttl(&self, _key: Vec<u8>, _value: Vec<u8>, _ttl: Duration) -> Result<()> {
+        Err(KvsError::Unsupported("Key expiration".to_owned()))
+    }
+
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let tree: &Tree = &self.db;
+        Ok(tree.get(key)?.map(|i_vec| i_vec.to_vec()))
+    }
+
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        let tree: &Tree = &self.db;
+        {
+            let _write = self.writes.read().unwrap();
+            tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        }
+        self.commit()?;
         Ok(())
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let tree: &Tree = &self.0;
-        Ok(tree
-            .get(key)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let tree: &Tree = &self.db;
+        let mut sled_batch = Batch::default();
+        for op in batch.ops {
+            match op {
+                BatchOp::Set { key, value } => sled_batch.insert(key, value),
+                BatchOp::Remove { key } => sled_batch.remove(key),
+            }
+        }
+        {
+            let _write = self.writes.read().unwrap();
+            tree.apply_batch(sled_batch)?;
+        }
+        self.commit()?;
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let tree: &Tree = &self.db;
+        let swapped = {
+            let _write = self.writes.read().unwrap();
+            tree.compare_and_swap(key, expected, new)?.is_ok()
+        };
+        if swapped {
+            self.commit()?;
+        }
+        Ok(swapped)
+    }
+
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let tree: &Tree = &self.db;
+        match end {
+            Some(end) if end <= start => Ok(Vec::new()),
+            Some(end) => collect_pairs(tree.range(start..end), limit),
+            None => collect_pairs(tree.range(start..), limit),
+        }
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        let tree: &Tree = &self.0;
-        tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+    fn scan_prefix(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let tree: &Tree = &self.db;
+        collect_pairs(tree.scan_prefix(prefix), limit)
+    }
+
+    fn flush(&self) -> Result<()> {
+        let tree: &Tree = &self.db;
         tree.flush()?;
         Ok(())
     }
+
+    /// Copies every key/value pair to a new sled database at `dest`.
+    ///
+    /// Sled has no snapshots, so writes through this engine and its clones
+    /// wait while the pairs are copied. The backup holds the store as it was
+    /// at one point in time, with every batch whole or left out. Writes to
+    /// the `sled::Db` made outside of the engine are not waited for.
+    fn backup(&self, dest: &Path) -> Result<()> {
+        create_backup_dir(dest)?;
+        Manifest::new(EngineKind::Sled, 0).store(dest)?;
+        let backup = sled::open(dest)?;
+        let tree: &Tree = &self.db;
+        let _writes = self.writes.write().unwrap();
+        for pair in tree.iter() {
+            let (key, value) = pair?;
+            backup.insert(key, value)?;
+        }
+        backup.flush()?;
+        Ok(())
+    }
+}
+
+// Collect up to `limit` pairs of a sled iterator.
+fn collect_pairs(iter: Iter, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    iter.take(limit)
+        .map(|pair| {
+            let (key, value) = pair?;
+            Ok((key.to_vec(), value.to_vec()))
+        })
+        .collect()
 }
@@ -1,20 +1,100 @@
-use crate::Result;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::{KvsError, Result, WriteBatch};
 /// Trait for a key value storage engine.
 pub trait KvEngine: Clone + Send + 'static {
-    /// Sets the value of a string key to a string.
+    /// Sets the value of a key to a value.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Sets the value of a key to a value that expires after `ttl`.
+    ///
+    /// An expired key is treated as if it had been removed.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
 
-    /// Gets the string value of a given string key.
+    /// Gets the value of a given key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
 
     /// Removes a given key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Applies every write of the batch atomically.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Sets the value of a key to `new` if its current value is `expected`.
+    ///
+    /// A `None` as `expected` stands for an absent key, a `None` as `new`
+    /// removes the key. The comparison and the write are atomic.
+    ///
+    /// Returns `false` if the current value is not the expected one.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// Sets the value of a key to a value if the key does not exist.
+    ///
+    /// Returns `false` if the key already exists.
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Returns up to `limit` key/value pairs with keys in `[start, end)`, in key order.
+    ///
+    /// An `end` of `None` scans to the last key. An `end` not greater than
+    /// `start` returns no pairs.
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Returns up to `limit` key/value pairs whose key starts with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        // Keys sharing a prefix are contiguous and start at the prefix itself.
+        let mut pairs = self.scan(prefix.clone(), None, limit)?;
+        let len = pairs
+            .iter()
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .count();
+        pairs.truncate(len);
+        Ok(pairs)
+    }
+
+    /// Writes every buffered write to disk.
+    fn flush(&self) -> Result<()>;
+
+    /// Copies the data of the engine to the directory `dest` while it keeps serving.
+    ///
+    /// The directory is created if it does not exist. It can be opened by the
+    /// same engine, or copied back with `kvs-server --restore_from`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidInput` if `dest` is not an empty directory.
+    fn backup(&self, dest: &Path) -> Result<()>;
+}
+
+/// Creates the directory of a backup, which must not hold anything yet.
+pub(crate) fn create_backup_dir(dest: &Path) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    if fs::read_dir(dest)?.next().is_some() {
+        return Err(KvsError::InvalidInput(format!(
+            "backup directory {} is not empty",
+            dest.display()
+        )));
+    }
+    Ok(())
 }
@@ -0,0 +1,109 @@
+use crate::{KvEngine, KvsError, Result, WriteBatch};
+
+// Number of pairs read from the source and written to the target at a time.
+const PAGE_SIZE: usize = 1000;
+
+/// The number of pairs an engine holds and a checksum of them, in key order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MigrationSummary {
+    /// Number of key/value pairs.
+    pub keys: u64,
+    /// CRC32 of every key and value with their lengths.
+    pub checksum: u32,
+}
+
+/// Copies every key/value pair of `source` into the empty engine `target`.
+///
+/// The pairs are streamed a page at a time, each page written as one batch.
+/// Once the copy is flushed, the source is scanned again on its own and the
+/// target read back. Both have to hold as many pairs with the same checksum
+/// as were copied. Key expiry is not carried over, a key is copied with its
+/// value if it has not expired.
+///
+/// ```rust
+/// # use kvs::{migrate, KvEngine, MyKvStore, Result, SledKvs};
+/// # fn try_main() -> Result<()> {
+/// # let dir = std::env::current_dir()?;
+/// let source = MyKvStore::open(dir.join("kvs"))?;
+/// source.set(b"key".to_vec(), b"value".to_vec())?;
+/// let target = SledKvs::new(sled::open(dir.join("sled"))?);
+/// assert_eq!(migrate(&source, &target)?.keys, 1);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// It returns `KvsError::InvalidInput` if the target holds a key already.
+///
+/// It returns `KvsError::Migration` if the target does not match the source,
+/// or the source changed during the copy.
+pub fn migrate<S: KvEngine, T: KvEngine>(source: &S, target: &T) -> Result<MigrationSummary> {
+    if !target.scan(Vec::new(), None, 1)?.is_empty() {
+        return Err(KvsError::InvalidInput(
+            "migration target is not empty".to_owned(),
+        ));
+    }
+
+    let copied = scan_pages(source, |pairs| {
+        let mut batch = WriteBatch::default();
+        for (key, value) in pairs {
+            batch.set(key, value);
+        }
+        target.write_batch(batch)
+    })?;
+    target.flush()?;
+
+    let rescanned = scan_pages(source, |_| Ok(()))?;
+    if rescanned != copied {
+        return Err(KvsError::Migration(format!(
+            "copied {} keys with checksum {:08x}, the source holds {} keys with checksum {:08x}",
+            copied.keys, copied.checksum, rescanned.keys, rescanned.checksum
+        )));
+    }
+    let verified = scan_pages(target, |_| Ok(()))?;
+    if verified != copied {
+        return Err(KvsError::Migration(format!(
+            "copied {} keys with checksum {:08x}, the target holds {} keys with checksum {:08x}",
+            copied.keys, copied.checksum, verified.keys, verified.checksum
+        )));
+    }
+    Ok(copied)
+}
+
+// Scan every pair of the engine a page at a time, handing the pages to `f`.
+//
+// A page may hold fewer pairs than asked for without being the last one, so
+// only an empty page ends the scan.
+fn scan_pages<E: KvEngine>(
+    engine: &E,
+    mut f: impl FnMut(Vec<(Vec<u8>, Vec<u8>)>) -> Result<()>,
+) -> Result<MigrationSummary> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut keys = 0;
+    let mut start = Vec::new();
+    loop {
+        let pairs = engine.scan(start, None, PAGE_SIZE)?;
+        for (key, value) in &pairs {
+            hasher.update(&(key.len() as u64).to_le_bytes());
+            hasher.update(key);
+            hasher.update(&(value.len() as u64).to_le_bytes());
+            hasher.update(value);
+        }
+        keys += pairs.len() as u64;
+        // The next page starts right after the last key of this one.
+        start = match pairs.last() {
+            Some((key, _)) => {
+                let mut next = key.clone();
+                next.push(0);
+                next
+            }
+            None => break,
+        };
+        f(pairs)?;
+    }
+    Ok(MigrationSummary {
+        keys,
+        checksum: hasher.finalize(),
+    })
+}
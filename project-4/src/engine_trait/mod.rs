@@ -1,4 +1,11 @@
 //! This module provides various key value storage engine trait.
 pub use engine::KvEngine;
+pub use migrate::{migrate, MigrationSummary};
+pub use write_batch::WriteBatch;
+
+pub(crate) use engine::create_backup_dir;
+pub(crate) use write_batch::BatchOp;
 
 mod engine;
+mod migrate;
+mod write_batch;
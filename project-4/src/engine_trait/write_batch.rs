@@ -0,0 +1,52 @@
+use serde::{Deserialize, Serialize};
+
+/// A group of writes applied to a `KvEngine` atomically.
+///
+/// Either every write of the batch is visible after a crash or none of them.
+/// The writes are applied in the order they were added, so the last write of
+/// a key wins. Removing a key that does not exist is not an error.
+///
+/// ```rust
+/// # use kvs::{KvEngine, MyKvStore, Result, WriteBatch};
+/// # fn try_main() -> Result<()> {
+/// # let store = MyKvStore::open(std::env::current_dir()?)?;
+/// let mut batch = WriteBatch::default();
+/// batch.set(b"key1".to_vec(), b"value1".to_vec());
+/// batch.remove(b"key2".to_vec());
+/// store.write_batch(batch)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    pub(crate) ops: Vec<BatchOp>,
+}
+
+/// A single write of a `WriteBatch`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    /// Adds setting the value of a key to the batch.
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Set { key, value });
+    }
+
+    /// Adds removing a key to the batch.
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Remove { key });
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch holds no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
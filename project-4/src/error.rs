@@ -11,6 +11,9 @@ pub enum KvsError {
     /// Serialization or deserialization error.
     #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
+    /// Binary serialization or deserialization error.
+    #[fail(display = "{}", _0)]
+    Bincode(#[cause] bincode::Error),
     /// Removing non-existent key error.
     #[fail(display = "Key not found")]
     KeyNotFound,
@@ -26,6 +29,44 @@ pub enum KvsError {
     /// Key or value is invalid UTF-8 sequence.
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(#[cause] FromUtf8Error),
+    /// Invalid key or value given to the client.
+    #[fail(display = "Invalid input {}", _0)]
+    InvalidInput(String),
+    /// The other side of a connection broke the wire protocol.
+    #[fail(display = "Protocol error: {}", _0)]
+    Protocol(String),
+    /// The engine does not support the operation.
+    #[fail(display = "{} is not supported by this engine", _0)]
+    Unsupported(String),
+    /// A log record failed its checksum.
+    #[fail(
+        display = "Corrupted log record in generation {} at offset {}",
+        gen, pos
+    )]
+    CorruptedLog {
+        /// Generation of the log segment holding the record.
+        gen: u64,
+        /// Offset of the record in the log segment.
+        pos: u64,
+    },
+    /// A log segment was written in an unknown format version.
+    #[fail(display = "Unsupported log format version {}", _0)]
+    UnsupportedFormat(u32),
+    /// A log record can not be encrypted or decrypted, e.g. with a wrong key.
+    #[fail(display = "Encryption error: {}", _0)]
+    Encryption(String),
+    /// The manifest of a data directory is malformed or does not match the engine.
+    #[fail(display = "Manifest error: {}", _0)]
+    Manifest(String),
+    /// A migrated engine does not hold the pairs read from the source engine.
+    #[fail(display = "Migration failed: {}", _0)]
+    Migration(String),
+    /// The data directory is locked by another open store.
+    #[fail(display = "Data directory {} is in use by another process", _0)]
+    Locked(String),
+    /// A write was made to a store opened read-only.
+    #[fail(display = "The store is opened read-only")]
+    ReadOnly,
     /// Rayon error.
     #[fail(display = "rayon error: {}", _0)]
     ThreadPoolBuildError(#[cause] rayon::ThreadPoolBuildError),
@@ -43,6 +84,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
 impl From<FromUtf8Error> for KvsError {
     fn from(err: FromUtf8Error) -> KvsError {
         KvsError::Utf8(err)
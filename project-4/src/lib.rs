@@ -5,18 +5,31 @@ extern crate slog;
 #[macro_use]
 extern crate slog_scope;
 
+pub use async_client::AsyncKvsClient;
+pub use async_server::{AsyncKvsServer, AsyncShutdownHandle};
 pub use client::KvsClient;
-pub use engine_kvs::MyKvStore;
+pub use engine_kvs::{
+    CacheStats, Durability, EncryptionKey, KvStoreOptions, MyKvStore, RecoveryMode, Snapshot,
+    LOCK_FILE_NAME,
+};
 pub use engine_sled::SledKvs;
-pub use engine_trait::KvEngine;
+pub use engine_trait::{migrate, KvEngine, MigrationSummary, WriteBatch};
 pub use error::{KvsError, Result};
-pub use server::KvsServer;
+pub use manifest::{EngineKind, Manifest, MANIFEST_FILE_NAME};
+pub use protocol::Codec;
+pub use request::Request;
+pub use response::Response;
+pub use server::{KvsServer, ShutdownHandle};
 pub use thread_pool::*;
+mod async_client;
+mod async_server;
 mod client;
 mod engine_kvs;
 mod engine_sled;
 mod engine_trait;
 mod error;
+mod manifest;
+mod protocol;
 mod request;
 mod response;
 mod server;
@@ -0,0 +1,224 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{KvsError, Result};
+
+/// Name of the manifest file in a data directory.
+pub const MANIFEST_FILE_NAME: &str = "MANIFEST";
+/// Version of the manifest written by this version.
+pub const MANIFEST_VERSION: u32 = 1;
+
+// The plain text file naming the engine, written before the manifest.
+const LEGACY_META_FILE_NAME: &str = "meta";
+
+/// The storage engines a data directory can be written by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EngineKind {
+    /// The log-structured `MyKvStore`.
+    Kvs,
+    /// `SledKvs`.
+    Sled,
+}
+
+impl fmt::Display for EngineKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EngineKind::Kvs => "kvs",
+            EngineKind::Sled => "sled",
+        })
+    }
+}
+
+impl FromStr for EngineKind {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<EngineKind> {
+        match s {
+            "kvs" => Ok(EngineKind::Kvs),
+            "sled" => Ok(EngineKind::Sled),
+            _ => Err(KvsError::Manifest(format!("unknown engine {:?}", s))),
+        }
+    }
+}
+
+/// The metadata of a data directory, kept as json in its `MANIFEST` file.
+///
+/// Engines opened on a directory check it was written by them, and record
+/// the options their files depend on.
+///
+/// ```rust
+/// # use kvs::{EngineKind, Manifest, Result};
+/// # fn try_main() -> Result<()> {
+/// # let dir = std::env::current_dir()?;
+/// if let Some(manifest) = Manifest::load(&dir)? {
+///     manifest.check_engine(EngineKind::Kvs)?;
+///     println!("kvs store created at {} ms", manifest.created_millis);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Version of the manifest itself.
+    pub version: u32,
+    /// The engine owning the directory.
+    pub engine: EngineKind,
+    /// Format version of the files of the engine, 0 for sled, whose files
+    /// carry their own.
+    pub format_version: u32,
+    /// When the directory was created, in milliseconds since the Unix epoch.
+    pub created_millis: u64,
+    /// Whether records are encrypted, so the store does not open without a key.
+    pub encrypted: bool,
+    /// The size above which records are compressed, if they are.
+    pub compress_above: Option<usize>,
+}
+
+impl Manifest {
+    /// Creates the manifest of a new directory of the engine.
+    pub fn new(engine: EngineKind, format_version: u32) -> Manifest {
+        let created_millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or(0);
+        Manifest {
+            version: MANIFEST_VERSION,
+            engine,
+            format_version,
+            created_millis,
+            encrypted: false,
+            compress_above: None,
+        }
+    }
+
+    /// Reads the manifest of the directory, `None` if it has none yet.
+    ///
+    /// A manifest of an earlier version, or the `meta` file of earlier
+    /// versions, is upgraded and written back.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Manifest` if the manifest is malformed or newer
+    /// than this version.
+    pub fn load(dir: &Path) -> Result<Option<Manifest>> {
+        let (manifest, upgraded) = match read_upgraded(dir)? {
+            Some(read) => read,
+            None => return Ok(None),
+        };
+        if upgraded {
+            manifest.store(dir)?;
+        }
+        Ok(Some(manifest))
+    }
+
+    /// Reads the manifest of the directory like `Manifest::load`, without
+    /// writing back an upgraded one.
+    pub(crate) fn read(dir: &Path) -> Result<Option<Manifest>> {
+        Ok(read_upgraded(dir)?.map(|(manifest, _)| manifest))
+    }
+
+    /// Writes the manifest to the directory, replacing the old one atomically,
+    /// and removes the `meta` file of earlier versions.
+    pub fn store(&self, dir: &Path) -> Result<()> {
+        let path = manifest_path(dir);
+        let temp_path = path.with_extension("temp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        // A crash after the rename never leaves an empty manifest behind.
+        file.sync_all()?;
+        fs::rename(temp_path, path)?;
+        match fs::remove_file(dir.join(LEGACY_META_FILE_NAME)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => Ok(res?),
+        }
+    }
+
+    /// Checks the directory belongs to the engine.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Manifest` if it belongs to another engine.
+    pub fn check_engine(&self, engine: EngineKind) -> Result<()> {
+        if self.engine != engine {
+            return Err(KvsError::Manifest(format!(
+                "the data directory holds a {} store, not a {} one",
+                self.engine, engine
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the path of the manifest of the directory.
+pub fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join(MANIFEST_FILE_NAME)
+}
+
+// Read the manifest of the directory, upgraded to the current version.
+//
+// Returns whether it was upgraded.
+fn read_upgraded(dir: &Path) -> Result<Option<(Manifest, bool)>> {
+    let value = match fs::read(manifest_path(dir)) {
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .map_err(|e| KvsError::Manifest(format!("malformed manifest: {}", e)))?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => match legacy_manifest(dir)? {
+            Some(value) => value,
+            None => return Ok(None),
+        },
+        Err(e) => return Err(e.into()),
+    };
+    upgrade(value).map(Some)
+}
+
+// Turn the `meta` file naming the engine into a manifest of version 0.
+fn legacy_manifest(dir: &Path) -> Result<Option<Value>> {
+    let engine = match fs::read_to_string(dir.join(LEGACY_META_FILE_NAME)) {
+        Ok(engine) => engine,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let engine: EngineKind = engine.trim().parse()?;
+    Ok(Some(serde_json::json!({
+        "version": 0,
+        "engine": engine,
+    })))
+}
+
+// Upgrade a manifest to the current version, one version at a time.
+//
+// Returns whether it was upgraded.
+fn upgrade(mut value: Value) -> Result<(Manifest, bool)> {
+    let malformed = |e: serde_json::Error| KvsError::Manifest(format!("malformed manifest: {}", e));
+    let version = value
+        .get("version")
+        .and_then(Value::as_u64)
+        .ok_or_else(|| KvsError::Manifest("manifest without a version".to_owned()))?;
+    if version > u64::from(MANIFEST_VERSION) {
+        return Err(KvsError::Manifest(format!(
+            "manifest version {} is newer than the supported {}",
+            version, MANIFEST_VERSION
+        )));
+    }
+    for from in version..u64::from(MANIFEST_VERSION) {
+        value = match from {
+            // The `meta` file only named the engine. Its format version and
+            // options are unknown, the engine records them once it opens.
+            0 => {
+                let engine: EngineKind =
+                    serde_json::from_value(value["engine"].clone()).map_err(malformed)?;
+                serde_json::to_value(Manifest::new(engine, 0))?
+            }
+            _ => unreachable!("no upgrade from manifest version {}", from),
+        };
+    }
+    let manifest = serde_json::from_value(value).map_err(malformed)?;
+    Ok((manifest, version < u64::from(MANIFEST_VERSION)))
+}
@@ -0,0 +1,200 @@
+//! The framed wire protocol between `KvsClient` and `KvsServer`.
+//!
+//! A connection starts with a handshake: the client sends `PROTOCOL_MAGIC`,
+//! the highest protocol version it speaks and the codec it would like, the
+//! server answers the same way with the version and codec to use. Then every
+//! message is a frame of a little endian u32 payload length, a little endian
+//! u64 request id and the payload encoded with the negotiated codec.
+//!
+//! A connection starting with anything else than `PROTOCOL_MAGIC` is served
+//! with the bare concatenated json of earlier versions.
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{KvsError, Result};
+
+/// Magic bytes opening the handshake of the framed protocol.
+pub const PROTOCOL_MAGIC: &[u8; 4] = b"KVSP";
+/// Highest version of the framed protocol spoken by this build.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Length of the handshake message: magic, version and codec.
+const HELLO_LEN: usize = 6;
+/// Length of the frame header: payload length and request id.
+const FRAME_HEADER_LEN: usize = 12;
+/// Largest payload accepted, so a garbled length cannot exhaust memory.
+const MAX_FRAME_LEN: u32 = 256 * 1024 * 1024;
+
+/// How requests and responses are encoded in the frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// Json, readable by any tool.
+    Json,
+    /// Compact binary encoding.
+    Bincode,
+}
+
+impl Codec {
+    fn to_byte(self) -> u8 {
+        match self {
+            Codec::Json => 0,
+            Codec::Bincode => 1,
+        }
+    }
+
+    /// Unknown codecs fall back to json.
+    fn from_byte(byte: u8) -> Codec {
+        match byte {
+            1 => Codec::Bincode,
+            _ => Codec::Json,
+        }
+    }
+
+    fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            Codec::Json => Ok(serde_json::to_vec(value)?),
+            Codec::Bincode => Ok(bincode::DefaultOptions::new().serialize(value)?),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T> {
+        match self {
+            Codec::Json => Ok(serde_json::from_slice(payload)?),
+            Codec::Bincode => Ok(bincode::DefaultOptions::new().deserialize(payload)?),
+        }
+    }
+}
+
+/// Writes the handshake message of either side.
+pub fn write_hello<W: Write>(writer: &mut W, version: u8, codec: Codec) -> Result<()> {
+    writer.write_all(&hello(version, codec))?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the handshake message of the other side.
+///
+/// Returns the protocol version and codec it sent.
+pub fn read_hello<R: Read>(reader: &mut R) -> Result<(u8, Codec)> {
+    let mut hello = [0u8; HELLO_LEN];
+    reader.read_exact(&mut hello)?;
+    parse_hello(&hello)
+}
+
+/// Writes a message with its request id, without flushing.
+pub fn write_frame<W: Write, T: Serialize>(
+    writer: &mut W,
+    codec: Codec,
+    id: u64,
+    message: &T,
+) -> Result<()> {
+    writer.write_all(&encode_frame(codec, id, message)?)?;
+    Ok(())
+}
+
+/// Reads a message and its request id.
+///
+/// Returns `None` if the connection was closed between two frames.
+pub fn read_frame<R: Read, T: DeserializeOwned>(
+    reader: &mut R,
+    codec: Codec,
+) -> Result<Option<(u64, T)>> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    // Only a clean close before the first header byte ends the stream.
+    match reader.read(&mut header)? {
+        0 => return Ok(None),
+        n => reader.read_exact(&mut header[n..])?,
+    }
+    let (len, id) = parse_frame_header(&header)?;
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some((id, codec.decode(&payload)?)))
+}
+
+/// Writes the handshake message of either side, on an async stream.
+pub async fn write_hello_async<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    version: u8,
+    codec: Codec,
+) -> Result<()> {
+    writer.write_all(&hello(version, codec)).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads the handshake message of the other side, on an async stream.
+pub async fn read_hello_async<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(u8, Codec)> {
+    let mut hello = [0u8; HELLO_LEN];
+    reader.read_exact(&mut hello).await?;
+    parse_hello(&hello)
+}
+
+/// Writes a message with its request id on an async stream, without flushing.
+pub async fn write_frame_async<W: AsyncWrite + Unpin, T: Serialize>(
+    writer: &mut W,
+    codec: Codec,
+    id: u64,
+    message: &T,
+) -> Result<()> {
+    writer.write_all(&encode_frame(codec, id, message)?).await?;
+    Ok(())
+}
+
+/// Reads a message and its request id from an async stream.
+///
+/// Returns `None` if the connection was closed between two frames.
+pub async fn read_frame_async<R: AsyncRead + Unpin, T: DeserializeOwned>(
+    reader: &mut R,
+    codec: Codec,
+) -> Result<Option<(u64, T)>> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    match reader.read(&mut header).await? {
+        0 => return Ok(None),
+        n => {
+            reader.read_exact(&mut header[n..]).await?;
+        }
+    }
+    let (len, id) = parse_frame_header(&header)?;
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Some((id, codec.decode(&payload)?)))
+}
+
+fn hello(version: u8, codec: Codec) -> [u8; HELLO_LEN] {
+    let mut hello = [0u8; HELLO_LEN];
+    hello[..4].copy_from_slice(PROTOCOL_MAGIC);
+    hello[4] = version;
+    hello[5] = codec.to_byte();
+    hello
+}
+
+fn parse_hello(hello: &[u8; HELLO_LEN]) -> Result<(u8, Codec)> {
+    if &hello[..4] != PROTOCOL_MAGIC {
+        return Err(KvsError::Protocol("unexpected handshake".to_owned()));
+    }
+    Ok((hello[4], Codec::from_byte(hello[5])))
+}
+
+// Encode a whole frame: header and payload.
+fn encode_frame<T: Serialize>(codec: Codec, id: u64, message: &T) -> Result<Vec<u8>> {
+    let payload = codec.encode(message)?;
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&id.to_le_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+// Returns the payload length and the request id of a frame.
+fn parse_frame_header(header: &[u8; FRAME_HEADER_LEN]) -> Result<(usize, u64)> {
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let id = u64::from_le_bytes(header[4..].try_into().unwrap());
+    if len > MAX_FRAME_LEN {
+        return Err(KvsError::Protocol(format!("frame of {} bytes", len)));
+    }
+    Ok((len as usize, id))
+}
@@ -1,6 +1,111 @@
 use serde::{Deserialize, Serialize};
-#[derive(Debug, Serialize, Deserialize)]
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::WriteBatch;
+
+/// A request to `KvsServer`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
+    /// Gets the value of a key.
+    Get {
+        /// The key to get.
+        key: Vec<u8>,
+    },
+    /// Sets the value of a key.
+    Set {
+        /// The key to set.
+        key: Vec<u8>,
+        /// The new value of the key.
+        value: Vec<u8>,
+        /// How long until the key expires, it never does if `None`.
+        ttl: Option<Duration>,
+    },
+    /// Removes a key.
+    Remove {
+        /// The key to remove.
+        key: Vec<u8>,
+    },
+    /// Lists the key/value pairs with keys in `[start, end)`.
+    Scan {
+        /// The first key of the range.
+        start: Vec<u8>,
+        /// The key the range stops before, the range is unbounded if `None`.
+        end: Option<Vec<u8>>,
+        /// The maximum number of pairs to list.
+        limit: usize,
+    },
+    /// Lists the key/value pairs whose key starts with a prefix.
+    ScanPrefix {
+        /// The prefix of the keys.
+        prefix: Vec<u8>,
+        /// The maximum number of pairs to list.
+        limit: usize,
+    },
+    /// Applies every write of a batch atomically.
+    Batch {
+        /// The writes to apply.
+        batch: WriteBatch,
+    },
+    /// Sets or removes a key if its current value is the expected one.
+    CompareAndSwap {
+        /// The key to swap.
+        key: Vec<u8>,
+        /// The expected current value, `None` for an absent key.
+        expected: Option<Vec<u8>>,
+        /// The new value, `None` to remove the key.
+        new: Option<Vec<u8>>,
+    },
+    /// Backs up the data of the server to a directory on the server.
+    Backup {
+        /// The directory of the backup below the backup root of the server,
+        /// which must not hold anything yet.
+        dest: PathBuf,
+    },
+}
+
+/// A request of the bare json protocol of earlier versions.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum JsonRequest {
+    /// A request of the tools of earlier versions, with string keys and values.
+    Legacy(LegacyRequest),
+    /// A `Request` with keys and values as byte arrays.
+    Bytes(Request),
+}
+
+impl JsonRequest {
+    /// Whether the request is answered with strings, as earlier versions did.
+    pub fn is_legacy(&self) -> bool {
+        match self {
+            JsonRequest::Legacy(_) => true,
+            JsonRequest::Bytes(_) => false,
+        }
+    }
+}
+
+impl From<JsonRequest> for Request {
+    fn from(request: JsonRequest) -> Request {
+        match request {
+            JsonRequest::Legacy(LegacyRequest::Get { key }) => Request::Get {
+                key: key.into_bytes(),
+            },
+            JsonRequest::Legacy(LegacyRequest::Set { key, value }) => Request::Set {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                ttl: None,
+            },
+            JsonRequest::Legacy(LegacyRequest::Remove { key }) => Request::Remove {
+                key: key.into_bytes(),
+            },
+            JsonRequest::Bytes(request) => request,
+        }
+    }
+}
+
+/// A request as the tools of earlier versions send it.
+#[derive(Debug, Deserialize)]
+pub enum LegacyRequest {
     Get { key: String },
     Set { key: String, value: String },
     Remove { key: String },
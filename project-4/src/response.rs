@@ -1,19 +1,74 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
-pub enum GetResponse {
-    Ok(Option<String>),
-    Err(String),
+use crate::{KvsError, Result};
+
+/// The response of `KvsServer` to a `Request`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+    /// The value of a `Get`, `None` if the key does not exist.
+    Value(Option<Vec<u8>>),
+    /// A `Set`, `Remove`, `Batch` or `Backup` succeeded.
+    Done,
+    /// Whether a `CompareAndSwap` wrote.
+    Swapped(bool),
+    /// The pairs of a `Scan` or `ScanPrefix`.
+    Pairs(Vec<(Vec<u8>, Vec<u8>)>),
+    /// The request failed with the given message.
+    Error(String),
 }
 
+impl Response {
+    /// Turns an `Error` response into an `Err`.
+    pub(crate) fn into_result(self) -> Result<Response> {
+        match self {
+            Response::Error(msg) => Err(KvsError::ResponseError(msg)),
+            response => Ok(response),
+        }
+    }
+
+    pub(crate) fn into_value(self) -> Result<Option<Vec<u8>>> {
+        match self.into_result()? {
+            Response::Value(value) => Ok(value),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub(crate) fn into_done(self) -> Result<()> {
+        match self.into_result()? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub(crate) fn into_swapped(self) -> Result<bool> {
+        match self.into_result()? {
+            Response::Swapped(swapped) => Ok(swapped),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    pub(crate) fn into_pairs(self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match self.into_result()? {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> KvsError {
+    KvsError::Protocol(format!("unexpected response {:?}", response))
+}
+
+/// A response of the bare json protocol of earlier versions.
 #[derive(Debug, Serialize, Deserialize)]
-pub enum SetResponse {
-    Ok(()),
+pub enum JsonResponse<T> {
+    Ok(T),
     Err(String),
 }
 
+/// A response to a `LegacyRequest`, with the value as a string.
 #[derive(Debug, Serialize, Deserialize)]
-pub enum RemoveResponse {
-    Ok(()),
+pub enum LegacyResponse {
+    Ok(Option<String>),
     Err(String),
 }
@@ -1,79 +1,335 @@
 use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
 
-use crate::request::Request;
-use crate::response::{GetResponse, RemoveResponse, SetResponse};
-use crate::{KvEngine, Result, ThreadPool};
+use crate::protocol::{
+    read_frame, read_hello, write_frame, write_hello, PROTOCOL_MAGIC, PROTOCOL_VERSION,
+};
+use crate::request::JsonRequest;
+use crate::response::{JsonResponse, LegacyResponse};
+use crate::{KvEngine, KvsError, Request, Response, Result, ThreadPool};
 
 /// The server of key value store.
 pub struct KvsServer<E: KvEngine, P: ThreadPool> {
     engine: E,
     thread_pool: P,
+    backup_root: Option<Arc<Path>>,
 }
 
-impl<E: KvEngine, P: ThreadPool> KvsServer<E, P> {
+impl<E: KvEngine, P: ThreadPool + Send + 'static> KvsServer<E, P> {
     /// Create a serve with logger.
     pub fn new(engine: E, thread_pool: P) -> Self {
         KvsServer {
             engine,
             thread_pool,
+            backup_root: None,
         }
     }
 
-    /// Init the listener.
-    pub fn start<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
+    /// Lets clients back up the store to directories under `root`.
+    ///
+    /// Backup requests are refused unless a root is set.
+    pub fn backup_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.backup_root = Some(Arc::from(root.into()));
+        self
+    }
+
+    /// Listen on the address and serve the clients from the thread pool.
+    ///
+    /// Connections are accepted on a thread of their own, the returned
+    /// handle stops the server.
+    pub fn start<A: ToSocketAddrs>(self, addr: A) -> Result<ShutdownHandle> {
         let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let shutting_down = Arc::new(AtomicBool::new(false));
+        let acceptor = {
+            let shutting_down = Arc::clone(&shutting_down);
+            thread::Builder::new()
+                .name("kvs-acceptor".to_owned())
+                .spawn(move || self.serve(listener, &shutting_down))?
+        };
+        Ok(ShutdownHandle {
+            local_addr,
+            shutting_down,
+            acceptor: Some(acceptor),
+        })
+    }
+
+    // Accept connections until shut down, then drain them.
+    fn serve(self, listener: TcpListener, shutting_down: &AtomicBool) -> Result<()> {
+        let connections: Connections = Arc::default();
+        let mut next_id = 0;
         for stream in listener.incoming() {
+            if shutting_down.load(Ordering::SeqCst) {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            let connection = match Connection::register(&connections, next_id, &stream) {
+                Ok(connection) => connection,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            next_id += 1;
             let engine = self.engine.clone();
-            self.thread_pool.spawn(move || match stream {
-                Ok(stream) => {
-                    if let Err(e) = handle(engine, stream) {
-                        error!("Error on serving client: {}", e);
-                    }
+            let backup_root = self.backup_root.clone();
+            self.thread_pool.spawn(move || {
+                if let Err(e) = handle(engine, stream, backup_root.as_deref()) {
+                    error!("Error on serving client: {}", e);
                 }
-                Err(e) => error!("Connection failed: {}", e),
+                drop(connection);
             })
         }
-        Ok(())
+        drop(listener);
+
+        // Clients see no more requests read, the ones already read are answered.
+        for stream in connections.lock().unwrap().values() {
+            if let Err(e) = stream.shutdown(Shutdown::Read) {
+                debug!("Closing a connection failed: {}", e);
+            }
+        }
+        let KvsServer {
+            engine,
+            thread_pool,
+            ..
+        } = self;
+        drop(thread_pool);
+        engine.flush()
+    }
+}
+
+/// A handle to stop a `KvsServer`.
+///
+/// Dropping the handle stops the server as well.
+pub struct ShutdownHandle {
+    local_addr: SocketAddr,
+    shutting_down: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<Result<()>>>,
+}
+
+impl ShutdownHandle {
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops the server.
+    ///
+    /// New connections are refused, the requests already received are
+    /// answered, then the engine is flushed and the thread pool joined.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        let acceptor = match self.acceptor.take() {
+            Some(acceptor) => acceptor,
+            None => return Ok(()),
+        };
+        self.shutting_down.store(true, Ordering::SeqCst);
+        // Wake the acceptor up from `accept` with a connection of our own.
+        let mut addr = self.local_addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        if let Err(e) = TcpStream::connect(addr) {
+            debug!("Waking the acceptor up failed: {}", e);
+        }
+        match acceptor.join() {
+            Ok(result) => result,
+            Err(_) => {
+                error!("Acceptor thread panicked");
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Drop for ShutdownHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            error!("Shutting the server down failed: {}", e);
+        }
+    }
+}
+
+// The open connections by id, so a shutdown can stop reading from them.
+type Connections = Arc<Mutex<HashMap<u64, TcpStream>>>;
+
+// Removes a connection from the open ones once it is served.
+struct Connection {
+    id: u64,
+    connections: Connections,
+}
+
+impl Connection {
+    fn register(connections: &Connections, id: u64, stream: &TcpStream) -> io::Result<Self> {
+        connections.lock().unwrap().insert(id, stream.try_clone()?);
+        Ok(Connection {
+            id,
+            connections: Arc::clone(connections),
+        })
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.connections.lock().unwrap().remove(&self.id);
     }
 }
 
 /// Handle the stream.
-pub fn handle<E: KvEngine>(engine: E, tcp: TcpStream) -> Result<()> {
+///
+/// Clients of the framed protocol open with its handshake, anything else is
+/// served with the bare json protocol. Backups go under `backup_root`, they
+/// are refused without one.
+pub fn handle<E: KvEngine>(engine: E, tcp: TcpStream, backup_root: Option<&Path>) -> Result<()> {
     let peer_addr = tcp.peer_addr()?;
     debug!("Get the tcp stream form {}", peer_addr);
-    let reader = BufReader::new(&tcp);
-    let mut writer = BufWriter::new(&tcp);
-    let request_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+    let mut reader = BufReader::new(&tcp);
+    let writer = BufWriter::new(&tcp);
+    if reader.fill_buf()?.first() == Some(&PROTOCOL_MAGIC[0]) {
+        handle_framed(engine, reader, writer, backup_root)
+    } else {
+        handle_json(engine, reader, writer, backup_root)
+    }
+}
 
-    for request_item in request_reader {
-        match request_item? {
-            Request::Get { key } => {
-                let response = match engine.get(key) {
-                    Ok(value) => GetResponse::Ok(value),
-                    Err(e) => GetResponse::Err(format!("{}", e)),
-                };
-                serde_json::to_writer(&mut writer, &response)?;
-                writer.flush()?;
-            }
-            Request::Set { key, value } => {
-                let response = match engine.set(key, value) {
-                    Ok(_) => SetResponse::Ok(()),
-                    Err(e) => SetResponse::Err(format!("{}", e)),
-                };
-                serde_json::to_writer(&mut writer, &response)?;
-                writer.flush()?;
-            }
-            Request::Remove { key } => {
-                let response = match engine.remove(key) {
-                    Ok(_) => RemoveResponse::Ok(()),
-                    Err(e) => RemoveResponse::Err(format!("{}", e)),
-                };
-                serde_json::to_writer(&mut writer, &response)?;
-                writer.flush()?;
-            }
+// Serve the framed protocol.
+fn handle_framed<E: KvEngine>(
+    engine: E,
+    mut reader: BufReader<&TcpStream>,
+    mut writer: BufWriter<&TcpStream>,
+    backup_root: Option<&Path>,
+) -> Result<()> {
+    let (version, codec) = read_hello(&mut reader)?;
+    if version == 0 {
+        write_hello(&mut writer, 0, codec)?;
+        return Err(KvsError::Protocol(
+            "unsupported protocol version".to_owned(),
+        ));
+    }
+    write_hello(&mut writer, version.min(PROTOCOL_VERSION), codec)?;
+
+    while let Some((id, request)) = read_frame(&mut reader, codec)? {
+        let response = execute(&engine, request, backup_root);
+        write_frame(&mut writer, codec, id, &response)?;
+        // Answer pipelined requests in one go.
+        if reader.buffer().is_empty() {
+            writer.flush()?;
         }
     }
     Ok(())
 }
+
+// Serve the bare json protocol of earlier versions.
+fn handle_json<E: KvEngine>(
+    engine: E,
+    reader: BufReader<&TcpStream>,
+    mut writer: BufWriter<&TcpStream>,
+    backup_root: Option<&Path>,
+) -> Result<()> {
+    let request_reader = Deserializer::from_reader(reader).into_iter::<JsonRequest>();
+    for request_item in request_reader {
+        let request = request_item?;
+        let legacy = request.is_legacy();
+        let response = execute(&engine, request.into(), backup_root);
+        write_json_response(&mut writer, response, legacy)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+// Write a response the way the bare json protocol does.
+//
+// Requests of the tools of earlier versions get strings back, as they did.
+pub(crate) fn write_json_response<W: Write>(
+    writer: W,
+    response: Response,
+    legacy: bool,
+) -> Result<()> {
+    if legacy {
+        let response = match response {
+            Response::Value(Some(value)) => match String::from_utf8(value) {
+                Ok(value) => LegacyResponse::Ok(Some(value)),
+                Err(_) => LegacyResponse::Err("the value is not valid UTF-8".to_owned()),
+            },
+            Response::Value(None) | Response::Done => LegacyResponse::Ok(None),
+            Response::Error(msg) => LegacyResponse::Err(msg),
+            response => LegacyResponse::Err(format!("unexpected response {:?}", response)),
+        };
+        serde_json::to_writer(writer, &response)?;
+        return Ok(());
+    }
+    match response {
+        Response::Value(value) => serde_json::to_writer(writer, &JsonResponse::Ok(value))?,
+        Response::Done => serde_json::to_writer(writer, &JsonResponse::Ok(()))?,
+        Response::Swapped(swapped) => serde_json::to_writer(writer, &JsonResponse::Ok(swapped))?,
+        Response::Pairs(pairs) => serde_json::to_writer(writer, &JsonResponse::Ok(pairs))?,
+        Response::Error(msg) => serde_json::to_writer(writer, &JsonResponse::<()>::Err(msg))?,
+    }
+    Ok(())
+}
+
+// Run the request on the engine, with backups going under `backup_root`.
+pub(crate) fn execute<E: KvEngine>(
+    engine: &E,
+    request: Request,
+    backup_root: Option<&Path>,
+) -> Response {
+    let result = match request {
+        Request::Get { key } => engine.get(key).map(Response::Value),
+        Request::Set { key, value, ttl } => match ttl {
+            Some(ttl) => engine.set_with_ttl(key, value, ttl),
+            None => engine.set(key, value),
+        }
+        .map(|_| Response::Done),
+        Request::Remove { key } => engine.remove(key).map(|_| Response::Done),
+        Request::Batch { batch } => engine.write_batch(batch).map(|_| Response::Done),
+        Request::CompareAndSwap { key, expected, new } => engine
+            .compare_and_swap(key, expected, new)
+            .map(Response::Swapped),
+        Request::Scan { start, end, limit } => engine.scan(start, end, limit).map(Response::Pairs),
+        Request::ScanPrefix { prefix, limit } => {
+            engine.scan_prefix(prefix, limit).map(Response::Pairs)
+        }
+        Request::Backup { dest } => backup_dest(backup_root, &dest)
+            .and_then(|dest| engine.backup(&dest))
+            .map(|_| Response::Done),
+    };
+    result.unwrap_or_else(|e| Response::Error(format!("{}", e)))
+}
+
+// Resolve the directory of a backup request under the backup root.
+//
+// Clients only name directories below the root, never absolute paths or
+// ones climbing out of it.
+fn backup_dest(backup_root: Option<&Path>, dest: &Path) -> Result<PathBuf> {
+    let backup_root = backup_root
+        .ok_or_else(|| KvsError::InvalidInput("backups are disabled on this server".to_owned()))?;
+    let below_root = dest
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if dest.as_os_str().is_empty() || !below_root {
+        return Err(KvsError::InvalidInput(format!(
+            "backup directory {} is not a relative path below the backup root",
+            dest.display()
+        )));
+    }
+    Ok(backup_root.join(dest))
+}
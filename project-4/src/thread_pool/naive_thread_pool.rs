@@ -1,20 +1,39 @@
+use std::mem;
 use std::thread;
 
+use crossbeam::sync::WaitGroup;
+
 use crate::Result;
 use crate::ThreadPool;
 
 /// NativeThreadPool is a simple non-shared thread pool.
-pub struct NaiveThreadPool;
+///
+/// Dropping it waits for the spawned threads to exit.
+pub struct NaiveThreadPool {
+    threads: WaitGroup,
+}
 
 impl ThreadPool for NaiveThreadPool {
     fn new(_size: u32) -> Result<Self> {
-        Ok(NaiveThreadPool)
+        Ok(NaiveThreadPool {
+            threads: WaitGroup::new(),
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        let thread = self.threads.clone();
+        thread::spawn(move || {
+            job();
+            drop(thread);
+        });
+    }
+}
+
+impl Drop for NaiveThreadPool {
+    fn drop(&mut self) {
+        mem::replace(&mut self.threads, WaitGroup::new()).wait();
     }
 }
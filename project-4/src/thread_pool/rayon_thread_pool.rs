@@ -1,10 +1,16 @@
+use std::mem;
+
+use crossbeam::sync::WaitGroup;
 use rayon::ThreadPool as RThreadPool;
 
 use crate::{Result, ThreadPool};
 
 /// Rayon thread pool.
+///
+/// Dropping it waits for the spawned jobs to finish.
 pub struct RayonThreadPool {
     thread_pool: RThreadPool,
+    jobs: WaitGroup,
 }
 
 impl ThreadPool for RayonThreadPool {
@@ -17,6 +23,7 @@ impl ThreadPool for RayonThreadPool {
             thread_pool: rayon::ThreadPoolBuilder::new()
                 .num_threads(size as usize)
                 .build()?,
+            jobs: WaitGroup::new(),
         })
     }
 
@@ -24,6 +31,17 @@ impl ThreadPool for RayonThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        self.thread_pool.spawn(job)
+        let running = self.jobs.clone();
+        self.thread_pool.spawn(move || {
+            job();
+            drop(running);
+        })
+    }
+}
+
+impl Drop for RayonThreadPool {
+    fn drop(&mut self) {
+        // Rayon does not join its threads, so wait for the jobs instead.
+        mem::replace(&mut self.jobs, WaitGroup::new()).wait();
     }
 }
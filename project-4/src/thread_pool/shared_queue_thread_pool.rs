@@ -1,12 +1,16 @@
 use crate::{Result, ThreadPool};
 use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam::sync::WaitGroup;
+use std::mem;
 use std::thread;
 
 /// A shared queue thread pool.
 ///
 /// If a spawn failed because panic, it will start a new thread.
+/// Dropping it lets the threads finish the queued tasks and waits for them to exit.
 pub struct SharedQueueThreadPool {
-    sender: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    sender: Option<Sender<Box<dyn FnOnce() + Send + 'static>>>,
+    workers: WaitGroup,
 }
 
 impl ThreadPool for SharedQueueThreadPool {
@@ -19,11 +23,18 @@ impl ThreadPool for SharedQueueThreadPool {
     {
         assert!(size > 0, "size must more than 0");
         let (sender, receiver) = channel::unbounded::<Box<dyn FnOnce() + Send + 'static>>();
+        let workers = WaitGroup::new();
         for _ in 0..size {
-            let task_receiver = TaskReceiver(receiver.clone());
+            let task_receiver = TaskReceiver {
+                receiver: receiver.clone(),
+                _worker: workers.clone(),
+            };
             thread::Builder::new().spawn(move || run_tasks(task_receiver))?;
         }
-        Ok(SharedQueueThreadPool { sender })
+        Ok(SharedQueueThreadPool {
+            sender: Some(sender),
+            workers,
+        })
     }
 
     /// Spawns a function into the thread pool.
@@ -36,13 +47,27 @@ impl ThreadPool for SharedQueueThreadPool {
         F: FnOnce() + Send + 'static,
     {
         self.sender
+            .as_ref()
+            .expect("The thread pool is destroyed.")
             .send(Box::new(job))
             .expect("The thread pool is full.");
     }
 }
 
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        // Disconnect the queue, the threads exit once it is empty.
+        self.sender.take();
+        mem::replace(&mut self.workers, WaitGroup::new()).wait();
+    }
+}
+
+// The replacement thread of a panicked one takes its place in the wait group.
 #[derive(Clone)]
-struct TaskReceiver(Receiver<Box<dyn FnOnce() + Send + 'static>>);
+struct TaskReceiver {
+    receiver: Receiver<Box<dyn FnOnce() + Send + 'static>>,
+    _worker: WaitGroup,
+}
 
 impl Drop for TaskReceiver {
     fn drop(&mut self) {
@@ -57,11 +82,14 @@ impl Drop for TaskReceiver {
 
 fn run_tasks(receiver: TaskReceiver) {
     loop {
-        match receiver.0.recv() {
+        match receiver.receiver.recv() {
             Ok(task) => {
                 task();
             }
-            Err(_) => debug!("Thread exits because the thread pool is destroyed."),
+            Err(_) => {
+                debug!("Thread exits because the thread pool is destroyed.");
+                break;
+            }
         }
     }
 }
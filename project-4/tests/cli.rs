@@ -1,4 +1,5 @@
 use assert_cmd::prelude::*;
+use kvs::{EngineKind, KvEngine, Manifest, MyKvStore, SledKvs};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -212,18 +213,20 @@ fn cli_wrong_engine() {
     }
 }
 
-fn cli_access_server(engine: &str, addr: &str) {
+fn cli_access_server(engine: &str, runtime: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(&["--engine", engine, "--runtime", runtime, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // The store must be released before it is reopened.
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -298,7 +301,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(&["--engine", engine, "--runtime", runtime, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
@@ -328,10 +331,412 @@ fn cli_access_server(engine: &str, addr: &str) {
 
 #[test]
 fn cli_access_server_kvs_engine() {
-    cli_access_server("kvs", "127.0.0.1:4004");
+    cli_access_server("kvs", "sync", "127.0.0.1:4004");
 }
 
 #[test]
 fn cli_access_server_sled_engine() {
-    cli_access_server("sled", "127.0.0.1:4005");
+    cli_access_server("sled", "sync", "127.0.0.1:4005");
+}
+
+#[test]
+fn cli_access_server_async_runtime() {
+    cli_access_server("kvs", "async", "127.0.0.1:4013");
+}
+
+// SIGTERM stops the server cleanly, with the writes on disk.
+#[cfg(unix)]
+#[test]
+fn cli_graceful_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4019";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+
+    Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+
+    let store = MyKvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get(b"key1".to_vec()).unwrap(),
+        Some(b"value1".to_vec())
+    );
+}
+
+#[test]
+fn cli_scan() {
+    let addr = "127.0.0.1:4006";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    for (key, value) in &[("a", "1"), ("b1", "2"), ("b2", "3"), ("c", "4")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, value, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "a", "c", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a\t1\nb1\t2\nb2\t3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "b", "--limit", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("b1\t2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "b", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("b1\t2\nb2\t3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_compare_and_swap() {
+    let addr = "127.0.0.1:4007";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "key1", "--new", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "key1", "--new", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Value mismatch"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "cas",
+            "key1",
+            "--expected",
+            "value1",
+            "--new",
+            "value2",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "key1", "--expected", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_binary_encoding() {
+    let addr = "127.0.0.1:4008";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "00ff", "c328", "--encoding", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "AP8=", "--encoding", "base64", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("wyg=\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "scan",
+            "--prefix",
+            "00",
+            "--encoding",
+            "hex",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("00ff\tc328\n");
+
+    // The value is not valid UTF-8
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "00ff", "--encoding", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("c328\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("UTF-8"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "0g", "--encoding", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid input"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_set_with_ttl() {
+    let addr = "127.0.0.1:4009";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--ttl", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    thread::sleep(Duration::from_millis(1500));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_wrong_durability() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--durability", "sometimes"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_key_file_with_sled() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("kvs.key"), "00".repeat(32)).unwrap();
+    let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+    cmd.args(&["--engine", "sled", "--key_file", "kvs.key"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not supported"));
+}
+
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = MyKvStore::open(temp_dir.path()).unwrap();
+    store.set(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+    drop(store);
+    fs::write(temp_dir.path().join("meta"), "kvs").unwrap();
+
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .args(&["--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Migrated 1 keys from kvs to sled"));
+    let manifest = Manifest::load(temp_dir.path()).unwrap().unwrap();
+    assert_eq!(manifest.engine, EngineKind::Sled);
+    assert!(!temp_dir.path().join("meta").exists());
+    let store = SledKvs::new(sled::open(temp_dir.path()).unwrap());
+    assert_eq!(
+        store.get(b"key1".to_vec()).unwrap(),
+        Some(b"value1".to_vec())
+    );
+    drop(store);
+
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .args(&["--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already uses the sled engine"));
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .args(&["--to", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let store = MyKvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(
+        store.get(b"key1".to_vec()).unwrap(),
+        Some(b"value1".to_vec())
+    );
+}
+
+#[test]
+fn cli_migrate_interrupted() {
+    let temp_dir = TempDir::new().unwrap();
+    let store = MyKvStore::open(temp_dir.path()).unwrap();
+    store.set(b"key1".to_vec(), b"value1".to_vec()).unwrap();
+    drop(store);
+
+    // The source files were moved aside, but no staged file was moved in.
+    let retired = temp_dir.path().join("migrate.old");
+    fs::create_dir(&retired).unwrap();
+    for entry in fs::read_dir(temp_dir.path()).unwrap() {
+        let entry = entry.unwrap();
+        if entry.path().extension() == Some("log".as_ref()) {
+            fs::rename(entry.path(), retired.join(entry.file_name())).unwrap();
+        }
+    }
+    fs::create_dir(temp_dir.path().join("migrate.tmp")).unwrap();
+    fs::write(temp_dir.path().join("conf"), "staged").unwrap();
+
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .args(&["--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Rolled back an interrupted migration from kvs"))
+        .stdout(contains("Migrated 1 keys from kvs to sled"));
+    assert!(!retired.exists());
+    let store = SledKvs::new(sled::open(temp_dir.path()).unwrap());
+    assert_eq!(
+        store.get(b"key1".to_vec()).unwrap(),
+        Some(b"value1".to_vec())
+    );
 }
@@ -1,6 +1,13 @@
-use kvs::{KvEngine, MyKvStore, Result};
+use kvs::{
+    migrate, CacheStats, Durability, EncryptionKey, EngineKind, KvEngine, KvStoreOptions, KvsError,
+    Manifest, MyKvStore, RecoveryMode, Result, SledKvs, WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -10,17 +17,17 @@ fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = MyKvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = MyKvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
 
     Ok(())
 }
@@ -31,17 +38,17 @@ fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = MyKvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    store.set(b"key1".to_vec(), b"value2".to_vec())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value2".to_vec()));
 
     // Open from disk again and check persistent data
     drop(store);
     let store = MyKvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-    store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value2".to_vec()));
+    store.set(b"key1".to_vec(), b"value3".to_vec())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value3".to_vec()));
 
     Ok(())
 }
@@ -52,13 +59,13 @@ fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = MyKvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert_eq!(store.get(b"key2".to_vec())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
     let store = MyKvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get(b"key2".to_vec())?, None);
 
     Ok(())
 }
@@ -67,7 +74,7 @@ fn get_non_existent_value() -> Result<()> {
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = MyKvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    assert!(store.remove(b"key1".to_vec()).is_err());
     Ok(())
 }
 
@@ -75,9 +82,9 @@ fn remove_non_existent_key() -> Result<()> {
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = MyKvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert!(store.remove(b"key1".to_vec()).is_ok());
+    assert_eq!(store.get(b"key1".to_vec())?, None);
     Ok(())
 }
 
@@ -102,8 +109,8 @@ fn compaction() -> Result<()> {
     let mut current_size = dir_size();
     for iter in 0..1000 {
         for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
+            let key = format!("key{}", key_id).into_bytes();
+            let value = format!("{}", iter).into_bytes();
             store.set(key, value)?;
         }
 
@@ -118,8 +125,8 @@ fn compaction() -> Result<()> {
         // reopen and check content
         let store = MyKvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+            let key = format!("key{}", key_id).into_bytes();
+            assert_eq!(store.get(key)?, Some(format!("{}", iter).into_bytes()));
         }
         return Ok(());
     }
@@ -127,85 +134,1884 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Keep overwriting keys from several threads while compactions run in the background.
+// Test data correctness after the compactions and after reopening.
+#[test]
+fn concurrent_set_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for iter in 0..100 {
+                for key_id in 0..250 {
+                    let key = format!("key{}_{}", thread_id, key_id).into_bytes();
+                    store.set(key, format!("{}", iter).into_bytes()).unwrap();
+                }
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let check = |store: &MyKvStore| -> Result<()> {
+        for thread_id in 0..4 {
+            for key_id in 0..250 {
+                let key = format!("key{}_{}", thread_id, key_id).into_bytes();
+                assert_eq!(store.get(key)?, Some(b"99".to_vec()));
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    drop(store);
+    let store = MyKvStore::open(temp_dir.path())?;
+    check(&store)
+}
+
+// Keep reading keys from several threads while a writer triggers compactions.
+// Test every read sees a value, also once its segment was removed.
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    for key_id in 0..250 {
+        store.set(format!("key{}", key_id).into_bytes(), b"0".to_vec())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for iter in 1..200 {
+                for key_id in 0..250 {
+                    let key = format!("key{}", key_id).into_bytes();
+                    store.set(key, format!("{}", iter).into_bytes())?;
+                }
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..20 {
+                    for key_id in 0..250 {
+                        let key = format!("key{}", key_id).into_bytes();
+                        assert!(store.get(key)?.is_some());
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    writer.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+
+    for key_id in 0..250 {
+        let key = format!("key{}", key_id).into_bytes();
+        assert_eq!(store.get(key)?, Some(b"199".to_vec()));
+    }
+    Ok(())
+}
+
+// Write enough data to fill several log segments.
+// Test data correctness across segments after reopening.
+#[test]
+fn log_segments_roll_over() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+
+    let value = "v".repeat(1024).into_bytes();
+    for key_id in 0..2000 {
+        store.set(format!("key{}", key_id).into_bytes(), value.clone())?;
+    }
+
+    let segments = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+        .count();
+    assert!(segments > 1, "expect the log to be split into segments");
+
+    drop(store);
+    let store = MyKvStore::open(temp_dir.path())?;
+    for key_id in 0..2000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes())?,
+            Some(value.clone())
+        );
+    }
+    Ok(())
+}
+
+// Read keys from sealed segments through a memory map, while compactions remove them.
+// Test data correctness before and after reopening.
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().mmap_reads(true);
+    let store = MyKvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let value = |iter: u32| iter.to_le_bytes().repeat(256);
+    for iter in 0..5 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id).into_bytes(), value(iter))?;
+        }
+        for key_id in 0..1000 {
+            assert_eq!(
+                store.get(format!("key{}", key_id).into_bytes())?,
+                Some(value(iter))
+            );
+        }
+    }
+
+    drop(store);
+    let store = MyKvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes())?,
+            Some(value(4))
+        );
+    }
+    Ok(())
+}
+
+// Read hot keys through the value cache while they are overwritten and compacted.
+// Test reads are served from the cache and never see a stale value.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().cache_size(1024 * 1024);
+    let store = MyKvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set(b"hot".to_vec(), b"0".to_vec())?;
+    for _ in 0..10 {
+        assert_eq!(store.get(b"hot".to_vec())?, Some(b"0".to_vec()));
+    }
+    let stats = store.cache_stats();
+    assert_eq!((stats.hits, stats.misses), (9, 1));
+
+    // Enough overwrites for compactions to move the keys.
+    let value = "v".repeat(1024).into_bytes();
+    for iter in 1..2000 {
+        store.set(format!("key{}", iter % 100).into_bytes(), value.clone())?;
+        store.set(b"hot".to_vec(), format!("{}", iter).into_bytes())?;
+        assert_eq!(
+            store.get(b"hot".to_vec())?,
+            Some(format!("{}", iter).into_bytes())
+        );
+        assert_eq!(
+            store.get(format!("key{}", iter % 100).into_bytes())?,
+            Some(value.clone())
+        );
+    }
+    store.remove(b"hot".to_vec())?;
+    assert_eq!(store.get(b"hot".to_vec())?, None);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().cache_size(0);
+    let store = MyKvStore::open_with_options(temp_dir.path(), options)?;
+    store.set(b"key".to_vec(), b"value".to_vec())?;
+    store.get(b"key".to_vec())?;
+    assert_eq!(store.cache_stats(), CacheStats::default());
+    Ok(())
+}
+
+// Simulate a crash in the middle of a write.
+// Test the torn record is truncated when reopening.
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .append(true)
+        .open(&log_path)?
+        .write_all(&[42, 0, 0, 0, 1, 2, 3, 4, b'{'])?;
+
+    let store = MyKvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log_path)?.len(), len);
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+
+    drop(store);
+    let store = MyKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key3".to_vec())?, Some(b"value3".to_vec()));
+    Ok(())
+}
+
+// Flip a byte of the first record.
+// Test the strict mode refuses the log and the tolerant mode skips the record.
+#[test]
+fn recover_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log_path)?;
+    content[20] ^= 0xff;
+    fs::write(&log_path, content)?;
+
+    let strict = KvStoreOptions::default().recovery_mode(RecoveryMode::Strict);
+    match MyKvStore::open_with_options(temp_dir.path(), strict) {
+        Err(KvsError::CorruptedLog { gen: 1, pos: 8 }) => {}
+        _ => panic!("expect the corrupted record to be refused"),
+    }
+
+    let store = MyKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    Ok(())
+}
+
+// Flip the length of a record in the middle of a sealed segment, so it runs past its end.
+// Test the record is not taken for a torn tail: the strict mode refuses the log
+// and the tolerant mode keeps the segment and the records around it.
+#[test]
+fn recover_corrupted_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    let value = vec![b'v'; 1000];
+    for key_id in 0..1100 {
+        store.set(format!("key{}", key_id).into_bytes(), value.clone())?;
+    }
+    drop(store);
+    assert!(temp_dir.path().join("2.log").exists());
+
+    let log_path = temp_dir.path().join("1.log");
+    let pos = corrupt_length(&log_path, 9)?;
+    let log_len = fs::metadata(&log_path)?.len();
+
+    let strict = KvStoreOptions::default().recovery_mode(RecoveryMode::Strict);
+    match MyKvStore::open_with_options(temp_dir.path(), strict) {
+        Err(KvsError::CorruptedLog { gen: 1, pos: at }) => assert_eq!(at, pos as u64),
+        res => panic!("corrupted length taken for a torn tail: {:?}", res.is_ok()),
+    }
+
+    let store = MyKvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log_path)?.len(), log_len);
+    assert_eq!(store.get(b"key8".to_vec())?, Some(value.clone()));
+    assert_eq!(store.get(b"key9".to_vec())?, None);
+    assert_eq!(store.get(b"key1099".to_vec())?, Some(value));
+    Ok(())
+}
+
+// Flip the length of a record in the middle of the active segment, so it runs past its end.
+// Test the strict mode refuses the log without truncating it, and the tolerant
+// mode truncates it at the record.
+#[test]
+fn recover_corrupted_length_in_last_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id).into_bytes(), b"value".to_vec())?;
+    }
+    drop(store);
+    assert!(!temp_dir.path().join("2.log").exists());
+
+    let log_path = temp_dir.path().join("1.log");
+    let pos = corrupt_length(&log_path, 9)?;
+    let log_len = fs::metadata(&log_path)?.len();
+
+    let strict = KvStoreOptions::default().recovery_mode(RecoveryMode::Strict);
+    match MyKvStore::open_with_options(temp_dir.path(), strict) {
+        Err(KvsError::CorruptedLog { gen: 1, pos: at }) => assert_eq!(at, pos as u64),
+        res => panic!("corrupted length taken for a torn tail: {:?}", res.is_ok()),
+    }
+    assert_eq!(fs::metadata(&log_path)?.len(), log_len);
+
+    let store = MyKvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&log_path)?.len(), pos as u64);
+    assert_eq!(store.get(b"key8".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(store.get(b"key9".to_vec())?, None);
+    assert_eq!(store.get(b"key19".to_vec())?, None);
+    Ok(())
+}
+
+// Set the length of the record after the first `skip` ones of a segment far
+// past its end, returning the position of the record.
+fn corrupt_length(log_path: &Path, skip: usize) -> Result<usize> {
+    let mut content = fs::read(log_path)?;
+    let mut pos = 8;
+    for _ in 0..skip {
+        let header = [
+            content[pos],
+            content[pos + 1],
+            content[pos + 2],
+            content[pos + 3],
+        ];
+        pos += 8 + (u32::from_le_bytes(header) & 0x3fff_ffff) as usize;
+    }
+    content[pos..pos + 4].copy_from_slice(&0x0fff_ffffu32.to_le_bytes());
+    fs::write(log_path, &content)?;
+    Ok(pos)
+}
+
+// Open a directory written by the single json log format.
+// Test the legacy log is converted including a torn last record.
+#[test]
+fn open_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("kvs.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key1"}}{"Set":{"key":"ke"#,
+    )?;
+
+    let store = MyKvStore::open(temp_dir.path())?;
+    assert!(!temp_dir.path().join("kvs.log").exists());
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    Ok(())
+}
+
+// Open a segment of framed json records written before the format version header.
+// Test the segment is migrated to the current format.
+#[test]
+fn open_json_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut content = Vec::new();
+    for record in &[
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+        r#"{"Set":{"key":"key2","value":"value2"}}"#,
+        r#"{"Set":{"key":"key1","value":"value3"}}"#,
+    ] {
+        content.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        content.extend_from_slice(&crc32fast::hash(record.as_bytes()).to_le_bytes());
+        content.extend_from_slice(record.as_bytes());
+    }
+    let log_path = temp_dir.path().join("1.log");
+    fs::write(&log_path, &content)?;
+
+    let store = MyKvStore::open(temp_dir.path())?;
+    assert!(fs::read(&log_path)?.starts_with(b"KVSL"));
+    assert!(fs::metadata(&log_path)?.len() < content.len() as u64);
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value3".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    Ok(())
+}
+
+// Open a segment whose header has the format version before compressed records.
+// Test the segment is read as it is and its header is brought to the current version.
+#[test]
+fn open_uncompressed_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log_path)?;
+    content[4..8].copy_from_slice(&1u32.to_le_bytes());
+    fs::write(&log_path, &content)?;
+
+    let options = KvStoreOptions::default().compress_above(Some(16));
+    let store = MyKvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(fs::read(&log_path)?[4..8], 4u32.to_le_bytes());
+    store.set(b"key2".to_vec(), "value2".repeat(100).into_bytes())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(
+        store.get(b"key2".to_vec())?,
+        Some("value2".repeat(100).into_bytes())
+    );
+    Ok(())
+}
+
+// Write large compressible values along with small ones, and compact them.
+// Test the values read back the same, also after reopening.
+#[test]
+fn compressed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().compress_above(Some(1024));
+    let store = MyKvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let value =
+        |iter: u32| format!(r#"{{"iter":{},"padding":"{}"}}"#, iter, "x".repeat(4096)).into_bytes();
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id).into_bytes(), value(iter))?;
+            store.set(format!("small{}", key_id).into_bytes(), b"small".to_vec())?;
+        }
+    }
+    // 100 * 100 records of 4KB stay well below 40MB once compressed.
+    let dir_size: u64 = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    assert!(
+        dir_size < 4 * 1024 * 1024,
+        "expect the values to be compressed"
+    );
+
+    let check = |store: &MyKvStore| -> Result<()> {
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id).into_bytes())?,
+                Some(value(99))
+            );
+            assert_eq!(
+                store.get(format!("small{}", key_id).into_bytes())?,
+                Some(b"small".to_vec())
+            );
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    drop(store);
+    check(&MyKvStore::open_with_options(
+        temp_dir.path(),
+        options.mmap_reads(true),
+    )?)?;
+    // Compressed records are read without the option, too.
+    check(&MyKvStore::open(temp_dir.path())?)
+}
+
+// Write a record too long for the length field of its frame.
+// Test it is refused without touching the log.
+#[test]
+fn record_size_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    match store.set(b"key2".to_vec(), vec![0; 1 << 30]) {
+        Err(KvsError::InvalidInput(_)) => {}
+        res => panic!("record of 1 GiB written: {:?}", res),
+    }
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+    drop(store);
+
+    let store = MyKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+    assert_eq!(store.get(b"key3".to_vec())?, Some(b"value3".to_vec()));
+    Ok(())
+}
+
+// Returns whether any file in the directory contains the bytes.
+fn dir_contains(dir: &Path, needle: &[u8]) -> bool {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| fs::read(entry.path()).ok())
+        .any(|data| data.windows(needle.len()).any(|window| window == needle))
+}
+
+// Overwrite keys with an encryption key until a compaction writes a hint file.
+// Test neither the log nor the hint file holds plaintext, and only the right key opens the store.
+#[test]
+fn encrypted_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = EncryptionKey::new([1; 32]);
+    let options = KvStoreOptions::default()
+        .compress_above(Some(64))
+        .encryption_key(Some(key));
+    let store = MyKvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    let mut iter = 0;
+    loop {
+        for key_id in 0..1000 {
+            store.set(
+                format!("secret-key{}", key_id).into_bytes(),
+                format!("secret-value{}", iter).repeat(10).into_bytes(),
+            )?;
+        }
+        let has_hint_file = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.path().extension() == Some("hint".as_ref()));
+        if has_hint_file {
+            break;
+        }
+        iter += 1;
+        assert!(iter < 1000, "No compaction detected");
+    }
+    drop(store);
+    assert!(!dir_contains(temp_dir.path(), b"secret"));
+
+    let store = MyKvStore::open_with_options(temp_dir.path(), options.mmap_reads(true))?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("secret-key{}", key_id).into_bytes())?,
+            Some(format!("secret-value{}", iter).repeat(10).into_bytes())
+        );
+    }
+    drop(store);
+
+    for &wrong_key in &[None, Some([2; 32])] {
+        let wrong_key = wrong_key.map(EncryptionKey::new);
+        let options = KvStoreOptions::default().encryption_key(wrong_key);
+        match MyKvStore::open_with_options(temp_dir.path(), options) {
+            Err(KvsError::Encryption(_)) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("expect the store not to open"),
+        }
+    }
+    Ok(())
+}
+
+// Overwrite a key in an encrypted store and copy the record of the old value
+// over the record of the new one.
+// Test the replayed record fails to decrypt instead of rolling the key back.
+#[test]
+fn replay_encrypted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().encryption_key(Some(EncryptionKey::new([1; 32])));
+    let store = MyKvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set(b"key".to_vec(), b"value1".to_vec())?;
+    store.set(b"key".to_vec(), b"value2".to_vec())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log_path)?;
+    let record_len = (content.len() - 8) / 2;
+    let (first, second) = content[8..].split_at_mut(record_len);
+    second.copy_from_slice(first);
+    fs::write(&log_path, &content)?;
+
+    match MyKvStore::open_with_options(temp_dir.path(), options) {
+        Err(KvsError::Encryption(_)) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("expect the replayed record not to decrypt"),
+    }
+    Ok(())
+}
+
+// Encrypt a plaintext store, rotate its key and decrypt it again.
+#[test]
+fn rotate_encryption_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key1 = EncryptionKey::new([1; 32]);
+    let key2 = EncryptionKey::new([2; 32]);
+    let open = |key: &EncryptionKey| {
+        MyKvStore::open_with_options(
+            temp_dir.path(),
+            KvStoreOptions::default().encryption_key(Some(key.clone())),
+        )
+    };
+    let check = |store: MyKvStore| -> Result<()> {
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("secret-key{}", key_id).into_bytes())?,
+                Some(b"secret-value".to_vec())
+            );
+        }
+        assert_eq!(store.get(b"secret-key100".to_vec())?, None);
+        Ok(())
+    };
+
+    let store = MyKvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions::default().compress_above(Some(0)),
+    )?;
+    for key_id in 0..=100 {
+        store.set(
+            format!("secret-key{}", key_id).into_bytes(),
+            b"secret-value".to_vec(),
+        )?;
+    }
+    store.remove(b"secret-key100".to_vec())?;
+    drop(store);
+
+    MyKvStore::rotate_key(temp_dir.path(), None, Some(&key1))?;
+    assert!(!dir_contains(temp_dir.path(), b"secret"));
+    check(open(&key1)?)?;
+
+    MyKvStore::rotate_key(temp_dir.path(), Some(&key1), Some(&key2))?;
+    assert!(open(&key1).is_err());
+    check(open(&key2)?)?;
+
+    // A wrong old key leaves the log as it was.
+    match MyKvStore::rotate_key(temp_dir.path(), Some(&key1), None) {
+        Err(KvsError::Encryption(_)) => {}
+        res => panic!("unexpected result: {:?}", res.map_err(|e| e.to_string())),
+    }
+    check(open(&key2)?)?;
+
+    MyKvStore::rotate_key(temp_dir.path(), Some(&key2), None)?;
+    check(MyKvStore::open(temp_dir.path())?)
+}
+
+// Rotate the key of a store with a compaction hint file.
+// Test the hint file is rewritten and points at the rewritten records.
+#[test]
+fn rotate_key_with_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    let hint_file = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.path().extension() == Some("hint".as_ref()))
+            .map(|entry| entry.into_path())
+    };
+    let mut iter = 0;
+    let hint_path = loop {
+        for key_id in 0..1000 {
+            store.set(
+                format!("key{}", key_id).into_bytes(),
+                format!("{}", iter).into_bytes(),
+            )?;
+        }
+        iter += 1;
+        if let Some(path) = hint_file() {
+            break path;
+        }
+        assert!(iter < 1000, "No compaction detected");
+    };
+    drop(store);
+
+    let key = EncryptionKey::new([1; 32]);
+    MyKvStore::rotate_key(temp_dir.path(), None, Some(&key))?;
+    assert!(hint_path.exists());
+    let store = MyKvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions::default().encryption_key(Some(key.clone())),
+    )?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes())?,
+            Some(format!("{}", iter - 1).into_bytes())
+        );
+    }
+    drop(store);
+
+    MyKvStore::rotate_key(temp_dir.path(), Some(&key), None)?;
+    assert!(hint_path.exists());
+    let store = MyKvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes())?,
+            Some(format!("{}", iter - 1).into_bytes())
+        );
+    }
+    Ok(())
+}
+
+// Overwrite keys until a compaction writes a hint file.
+// Test the store reopens from the hint file, and from the log if the hint file is broken.
+#[test]
+fn compaction_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+
+    let hint_file = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.path().extension() == Some("hint".as_ref()))
+            .map(|entry| entry.into_path())
+    };
+
+    let mut iter = 0;
+    let hint_path = loop {
+        for key_id in 0..1000 {
+            store.set(
+                format!("key{}", key_id).into_bytes(),
+                format!("{}", iter).into_bytes(),
+            )?;
+        }
+        if let Some(path) = hint_file() {
+            break path;
+        }
+        iter += 1;
+        assert!(iter < 1000, "No compaction detected");
+    };
+    drop(store);
+
+    let store = MyKvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes())?,
+            Some(format!("{}", iter).into_bytes())
+        );
+    }
+    drop(store);
+
+    fs::write(&hint_path, b"garbage")?;
+    let store = MyKvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes())?,
+            Some(format!("{}", iter).into_bytes())
+        );
+    }
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = MyKvStore::open(temp_dir.path())?;
-    let barrier = Arc::new(Barrier::new(1001));
-    for i in 0..1000 {
-        let store = store.clone();
-        let barrier = barrier.clone();
-        thread::spawn(move || {
-            store
-                .set(format!("key{}", i), format!("value{}", i))
-                .unwrap();
-            barrier.wait();
-        });
-    }
-    barrier.wait();
+    let barrier = Arc::new(Barrier::new(1001));
+    for i in 0..1000 {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            store
+                .set(
+                    format!("key{}", i).into_bytes(),
+                    format!("value{}", i).into_bytes(),
+                )
+                .unwrap();
+            barrier.wait();
+        });
+    }
+    barrier.wait();
+
+    for i in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", i).into_bytes())?,
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = MyKvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", i).into_bytes())?,
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+
+    Ok(())
+}
+
+// Set and remove keys from many threads at once, so their writes share flushes.
+// Test every writer gets its own result, also after reopening.
+#[test]
+fn concurrent_set_and_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(16));
+    let handles: Vec<_> = (0..16u8)
+        .map(|t| {
+            let store = store.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || -> Result<()> {
+                barrier.wait();
+                for i in 0..100u8 {
+                    store.set(vec![t, i], vec![i; 100])?;
+                    if i % 2 == 0 {
+                        store.remove(vec![t, i])?;
+                        match store.remove(vec![t, i]) {
+                            Err(KvsError::KeyNotFound) => {}
+                            _ => panic!("removed a missing key"),
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    drop(store);
+    let store = MyKvStore::open(temp_dir.path())?;
+    for t in 0..16u8 {
+        for i in 0..100u8 {
+            let expected = if i % 2 == 0 { None } else { Some(vec![i; 100]) };
+            assert_eq!(store.get(vec![t, i])?, expected);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn concurrent_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store
+            .set(
+                format!("key{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+            .unwrap();
+    }
+
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id).into_bytes()).unwrap(),
+                    Some(format!("value{}", key_id).into_bytes())
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = MyKvStore::open(temp_dir.path())?;
+    let mut handles = Vec::new();
+    for thread_id in 0..100 {
+        let store = store.clone();
+        let handle = thread::spawn(move || {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                assert_eq!(
+                    store.get(format!("key{}", key_id).into_bytes()).unwrap(),
+                    Some(format!("value{}", key_id).into_bytes())
+                );
+            }
+        });
+        handles.push(handle);
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Ok(())
+}
+
+fn scan_keys(engine: impl KvEngine) -> Result<()> {
+    for key in &["a", "b1", "b2", "b3", "c"] {
+        engine.set(
+            key.as_bytes().to_vec(),
+            format!("value_{}", key).into_bytes(),
+        )?;
+    }
+    engine.remove(b"b2".to_vec())?;
+
+    let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<String> {
+        pairs
+            .into_iter()
+            .map(|(key, _)| String::from_utf8(key).unwrap())
+            .collect()
+    };
+    assert_eq!(
+        engine.scan(b"b".to_vec(), Some(b"c".to_vec()), 10)?,
+        vec![
+            (b"b1".to_vec(), b"value_b1".to_vec()),
+            (b"b3".to_vec(), b"value_b3".to_vec())
+        ]
+    );
+    assert_eq!(
+        keys(engine.scan(b"a".to_vec(), None, 10)?),
+        vec!["a", "b1", "b3", "c"]
+    );
+    assert_eq!(keys(engine.scan(b"a".to_vec(), None, 2)?), vec!["a", "b1"]);
+    assert!(engine
+        .scan(b"c".to_vec(), Some(b"a".to_vec()), 10)?
+        .is_empty());
+    assert_eq!(
+        keys(engine.scan_prefix(b"b".to_vec(), 10)?),
+        vec!["b1", "b3"]
+    );
+    assert_eq!(keys(engine.scan_prefix(b"b".to_vec(), 1)?), vec!["b1"]);
+    assert!(engine.scan_prefix(b"d".to_vec(), 10)?.is_empty());
+    Ok(())
+}
+
+// Should return the live keys of a range or prefix in key order
+#[test]
+fn scan_range_and_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_keys(MyKvStore::open(temp_dir.path())?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_keys(SledKvs::new(sled::open(temp_dir.path())?))
+}
+
+fn batch_writes(engine: impl KvEngine) -> Result<()> {
+    engine.set(b"key1".to_vec(), b"value1".to_vec())?;
+    engine.set(b"key2".to_vec(), b"value2".to_vec())?;
+
+    let mut batch = WriteBatch::default();
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    batch.set(b"key1".to_vec(), b"value4".to_vec());
+    batch.set(b"key1".to_vec(), b"value5".to_vec());
+    batch.remove(b"key2".to_vec());
+    batch.remove(b"key6".to_vec());
+    assert_eq!(batch.len(), 5);
+    engine.write_batch(batch)?;
+    engine.write_batch(WriteBatch::default())?;
+
+    assert_eq!(engine.get(b"key1".to_vec())?, Some(b"value5".to_vec()));
+    assert_eq!(engine.get(b"key2".to_vec())?, None);
+    assert_eq!(engine.get(b"key3".to_vec())?, Some(b"value3".to_vec()));
+    assert_eq!(engine.get(b"key6".to_vec())?, None);
+    Ok(())
+}
+
+// Should apply every write of a batch
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    batch_writes(MyKvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = MyKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value5".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+    assert_eq!(store.get(b"key3".to_vec())?, Some(b"value3".to_vec()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    batch_writes(SledKvs::new(sled::open(temp_dir.path())?))
+}
+
+// Cut the last byte of a batch record.
+// Test none of its writes survive the log replay.
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    let mut batch = WriteBatch::default();
+    batch.set(b"key1".to_vec(), b"value2".to_vec());
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    store.write_batch(batch)?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(len - 1)?;
+
+    let store = MyKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"key2".to_vec())?, None);
+    Ok(())
+}
+
+// Keys of batches should survive compaction
+#[test]
+fn compaction_with_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in (0..1000).step_by(10) {
+            let mut batch = WriteBatch::default();
+            for i in key_id..key_id + 10 {
+                batch.set(
+                    format!("key{}", i).into_bytes(),
+                    format!("{}", iter).into_bytes(),
+                );
+            }
+            batch.remove(format!("key{}", key_id).into_bytes());
+            store.write_batch(batch)?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+
+        drop(store);
+        let store = MyKvStore::open(temp_dir.path())?;
+        for key_id in 0..1000 {
+            let expected = if key_id % 10 == 0 {
+                None
+            } else {
+                Some(format!("{}", iter).into_bytes())
+            };
+            assert_eq!(store.get(format!("key{}", key_id).into_bytes())?, expected);
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+fn conditional_writes(engine: impl KvEngine) -> Result<()> {
+    assert!(engine.set_if_absent(b"key1".to_vec(), b"value1".to_vec())?);
+    assert!(!engine.set_if_absent(b"key1".to_vec(), b"value2".to_vec())?);
+    assert_eq!(engine.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+
+    assert!(!engine.compare_and_swap(
+        b"key1".to_vec(),
+        Some(b"value2".to_vec()),
+        Some(b"value3".to_vec())
+    )?);
+    assert!(engine.compare_and_swap(
+        b"key1".to_vec(),
+        Some(b"value1".to_vec()),
+        Some(b"value3".to_vec())
+    )?);
+    assert_eq!(engine.get(b"key1".to_vec())?, Some(b"value3".to_vec()));
+
+    assert!(engine.compare_and_swap(b"key1".to_vec(), Some(b"value3".to_vec()), None)?);
+    assert_eq!(engine.get(b"key1".to_vec())?, None);
+    assert!(engine.compare_and_swap(b"key1".to_vec(), None, None)?);
+    assert!(!engine.compare_and_swap(b"key1".to_vec(), Some(b"value3".to_vec()), None)?);
+    Ok(())
+}
+
+// Should only write if the current value is the expected one
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    conditional_writes(MyKvStore::open(temp_dir.path())?)?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    conditional_writes(SledKvs::new(sled::open(temp_dir.path())?))
+}
+
+// Concurrent increments through compare-and-swap should never get lost
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    store.set(b"counter".to_vec(), b"0".to_vec())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..100 {
+                    loop {
+                        let current = store.get(b"counter".to_vec())?.unwrap();
+                        let count: u64 =
+                            String::from_utf8(current.clone()).unwrap().parse().unwrap();
+                        let next = (count + 1).to_string().into_bytes();
+                        if store.compare_and_swap(b"counter".to_vec(), Some(current), Some(next))? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get(b"counter".to_vec())?, Some(b"800".to_vec()));
+    Ok(())
+}
+
+fn binary_data(engine: impl KvEngine) -> Result<()> {
+    let key = vec![0, 159, 146, 150, 255];
+    let value: Vec<u8> = (0..=255).collect();
+    engine.set(key.clone(), value.clone())?;
+    engine.set(vec![], vec![0xff])?;
+    assert_eq!(engine.get(key.clone())?, Some(value.clone()));
+    assert_eq!(engine.get(vec![])?, Some(vec![0xff]));
+    assert_eq!(
+        engine.scan_prefix(vec![0], 10)?,
+        vec![(key.clone(), value.clone())]
+    );
+    engine.remove(vec![])?;
+    assert_eq!(engine.get(vec![])?, None);
+    Ok(())
+}
+
+// Should store keys and values that are not UTF-8
+#[test]
+fn non_utf8_data() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_data(MyKvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = MyKvStore::open(temp_dir.path())?;
+    let value: Vec<u8> = (0..=255).collect();
+    assert_eq!(store.get(vec![0, 159, 146, 150, 255])?, Some(value));
+    assert_eq!(store.get(vec![])?, None);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_data(SledKvs::new(sled::open(temp_dir.path())?))
+}
+
+// Should hide keys once their TTL is over, also after reopening
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
 
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
-    }
+    store.set_with_ttl(
+        b"key1".to_vec(),
+        b"value1".to_vec(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        b"key2".to_vec(),
+        b"value2".to_vec(),
+        Duration::from_secs(3600),
+    )?;
+    store.set_with_ttl(
+        b"key3".to_vec(),
+        b"value3".to_vec(),
+        Duration::from_millis(200),
+    )?;
+    store.set(b"key3".to_vec(), b"value4".to_vec())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert!(!store.set_if_absent(b"key1".to_vec(), b"value5".to_vec())?);
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    assert_eq!(store.get(b"key3".to_vec())?, Some(b"value4".to_vec()));
+    assert_eq!(
+        store.scan(b"key".to_vec(), None, 10)?,
+        vec![
+            (b"key2".to_vec(), b"value2".to_vec()),
+            (b"key3".to_vec(), b"value4".to_vec())
+        ]
+    );
 
     // Open from disk again and check persistent data
     drop(store);
     let store = MyKvStore::open(temp_dir.path())?;
-    for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value2".to_vec()));
+    assert!(store.set_if_absent(b"key1".to_vec(), b"value5".to_vec())?);
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value5".to_vec()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvs::new(sled::open(temp_dir.path())?);
+    match store.set_with_ttl(b"key1".to_vec(), b"value1".to_vec(), Duration::from_secs(1)) {
+        Err(KvsError::Unsupported(_)) => {}
+        res => panic!("expect an unsupported error, got {:?}", res),
+    }
+    Ok(())
+}
+
+// Let keys expire between live ones, before the reaper drops them.
+// Test a scan skips them and still returns as many live keys as its limit.
+#[test]
+fn scan_skips_expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().reap_interval(Duration::from_secs(3600));
+    let store = MyKvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..10 {
+        let key = format!("key{}", i).into_bytes();
+        if i % 2 == 0 {
+            store.set_with_ttl(key, b"expired".to_vec(), Duration::from_millis(1))?;
+        } else {
+            store.set(key, b"live".to_vec())?;
+        }
     }
+    thread::sleep(Duration::from_millis(20));
 
+    let pair = |key: &str| (key.as_bytes().to_vec(), b"live".to_vec());
+    let expected = vec![pair("key1"), pair("key3"), pair("key5")];
+    assert_eq!(store.scan(b"key".to_vec(), None, 3)?, expected);
+    assert_eq!(store.scan_prefix(b"key".to_vec(), 3)?, expected);
+    assert_eq!(store.scan(b"key".to_vec(), None, 10)?.len(), 5);
     Ok(())
 }
 
+// Let a key expire before the reaper drops it.
+// Test removing it fails like for any missing key.
 #[test]
-fn concurrent_get() -> Result<()> {
+fn remove_expired_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().reap_interval(Duration::from_secs(3600));
+    let store = MyKvStore::open_with_options(temp_dir.path(), options)?;
+    store.set_with_ttl(
+        b"key1".to_vec(),
+        b"value1".to_vec(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(20));
+
+    match store.remove(b"key1".to_vec()) {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("removed an expired key: {:?}", res),
+    }
+    store.set(b"key1".to_vec(), b"value2".to_vec())?;
+    store.remove(b"key1".to_vec())?;
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    Ok(())
+}
+
+// Write expiring keys only, so only the reaper can trigger a compaction.
+// Test the expired keys are dropped from the log.
+#[test]
+fn reap_expired_keys() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::default().reap_interval(Duration::from_millis(20));
+    let store = MyKvStore::open_with_options(temp_dir.path(), options)?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let value = vec![b'v'; 1024];
+    for key_id in 0..2000 {
+        let key = format!("key{}", key_id).into_bytes();
+        store.set_with_ttl(key, value.clone(), Duration::from_millis(100))?;
+    }
+    let full_size = dir_size();
+
+    let start = Instant::now();
+    while dir_size() >= full_size / 2 {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "No compaction detected"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    drop(store);
     let store = MyKvStore::open(temp_dir.path())?;
-    for i in 0..100 {
-        store
-            .set(format!("key{}", i), format!("value{}", i))
-            .unwrap();
+    for key_id in 0..2000 {
+        assert_eq!(store.get(format!("key{}", key_id).into_bytes())?, None);
     }
+    Ok(())
+}
 
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
-        let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
+// Writes survive a reopen whatever the durability mode.
+#[test]
+fn durability_modes() -> Result<()> {
+    let modes = [
+        Durability::None,
+        Durability::EveryWrite,
+        Durability::Interval(Duration::from_millis(10)),
+        Durability::GroupCommit,
+    ];
+    // Large enough for the log to roll over to new segments.
+    let value = |i: u32| i.to_le_bytes().repeat(1024);
+    for &durability in modes.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions::default().durability(durability);
+        let store = MyKvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+        let barrier = Arc::new(Barrier::new(4));
+        let handles: Vec<_> = (0..4u8)
+            .map(|t| {
+                let store = store.clone();
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || -> Result<()> {
+                    barrier.wait();
+                    for i in 0..100u32 {
+                        store.set(vec![t, i as u8], value(i))?;
+                    }
+                    store.remove(vec![t, 0])
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        drop(store);
+
+        let store = MyKvStore::open_with_options(temp_dir.path(), options)?;
+        for t in 0..4u8 {
+            assert_eq!(store.get(vec![t, 0])?, None);
+            for i in 1..100u32 {
                 assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
+                    store.get(vec![t, i as u8])?,
+                    Some(value(i)),
+                    "{:?}",
+                    durability
                 );
             }
-        });
-        handles.push(handle);
+        }
+
+        let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = SledKvs::open(sled_dir.path(), durability, Some(1024 * 1024))?;
+        engine.set(b"key".to_vec(), b"value".to_vec())?;
+        // The other modes flush the write before it returns.
+        if let Durability::None | Durability::Interval(_) = durability {
+            engine.flush()?;
+        }
+        drop(engine);
+        // The threads of sled let go of the directory a while after the drop.
+        let engine =
+            wait_for_sled(|| SledKvs::open(sled_dir.path(), durability, Some(1024 * 1024)))?;
+        assert_eq!(engine.get(b"key".to_vec())?, Some(b"value".to_vec()));
     }
-    for handle in handles {
-        handle.join().unwrap();
+    Ok(())
+}
+
+// Write after taking a snapshot, then compact the segments it reads from.
+// Test the snapshot keeps reading the values as they were when it was taken.
+#[test]
+fn snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    let pair = |key: &str, value: &str| (key.as_bytes().to_vec(), value.as_bytes().to_vec());
+
+    for key in &["a", "b", "c"] {
+        store.set(key.as_bytes().to_vec(), b"1".to_vec())?;
     }
+    let snapshot = store.snapshot();
+    let mut batch = WriteBatch::default();
+    batch.set(b"a".to_vec(), b"2".to_vec());
+    batch.remove(b"b".to_vec());
+    batch.set(b"d".to_vec(), b"2".to_vec());
+    store.write_batch(batch)?;
+    store.set(b"c".to_vec(), b"2".to_vec())?;
+    let later = store.snapshot();
+    assert!(later.seq() > snapshot.seq());
 
-    // Open from disk again and check persistent data
+    let check = || -> Result<()> {
+        assert_eq!(snapshot.get(b"a".to_vec())?, Some(b"1".to_vec()));
+        assert_eq!(snapshot.get(b"b".to_vec())?, Some(b"1".to_vec()));
+        assert_eq!(snapshot.get(b"d".to_vec())?, None);
+        assert_eq!(
+            snapshot.scan(Vec::new(), None, 10)?,
+            vec![pair("a", "1"), pair("b", "1"), pair("c", "1")]
+        );
+        assert_eq!(snapshot.scan(b"b".to_vec(), None, 1)?, vec![pair("b", "1")]);
+        assert_eq!(later.get(b"b".to_vec())?, None);
+        assert_eq!(
+            later.scan(Vec::new(), None, 10)?,
+            vec![pair("a", "2"), pair("c", "2"), pair("d", "2")]
+        );
+        Ok(())
+    };
+    check()?;
+    assert_eq!(store.get(b"a".to_vec())?, Some(b"2".to_vec()));
+    assert_eq!(store.get(b"b".to_vec())?, None);
+
+    // Overwrite other keys until the first segment is compacted away.
+    let mut iter = 0;
+    while temp_dir.path().join("1.log").exists() {
+        for key_id in 0..1000 {
+            store.set(
+                format!("key{}", key_id).into_bytes(),
+                format!("{}", iter).into_bytes(),
+            )?;
+        }
+        iter += 1;
+        assert!(iter < 1000, "No compaction detected");
+    }
+    store.set(b"a".to_vec(), b"3".to_vec())?;
+    check()?;
+    assert_eq!(snapshot.get(b"key0".to_vec())?, None);
+
+    drop(snapshot);
+    drop(later);
+    assert_eq!(store.snapshot().get(b"a".to_vec())?, Some(b"3".to_vec()));
     drop(store);
+
     let store = MyKvStore::open(temp_dir.path())?;
-    let mut handles = Vec::new();
-    for thread_id in 0..100 {
+    assert_eq!(store.get(b"a".to_vec())?, Some(b"3".to_vec()));
+    assert_eq!(store.get(b"b".to_vec())?, None);
+    Ok(())
+}
+
+// Write both keys of a pair in one batch while other threads read them.
+// Test a snapshot never sees one key of the pair written without the other.
+#[test]
+fn concurrent_snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    store.set(b"a".to_vec(), b"0".to_vec())?;
+    store.set(b"b".to_vec(), b"0".to_vec())?;
+
+    let writer = {
         let store = store.clone();
-        let handle = thread::spawn(move || {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
-                    Some(format!("value{}", key_id))
-                );
+        thread::spawn(move || -> Result<()> {
+            for i in 1..=1000 {
+                let mut batch = WriteBatch::default();
+                batch.set(b"a".to_vec(), format!("{}", i).into_bytes());
+                batch.set(b"b".to_vec(), format!("{}", i).into_bytes());
+                store.write_batch(batch)?;
             }
-        });
-        handles.push(handle);
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..1000 {
+                    let snapshot = store.snapshot();
+                    let a = snapshot.get(b"a".to_vec())?;
+                    let b = snapshot.get(b"b".to_vec())?;
+                    assert!(a.is_some());
+                    assert_eq!(a, b);
+                    let pairs = snapshot.scan(Vec::new(), None, 2)?;
+                    assert_eq!(pairs.len(), 2);
+                    assert_eq!(pairs[0].1, pairs[1].1);
+                    assert_eq!(snapshot.get(b"a".to_vec())?, a);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    writer.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
     }
-    for handle in handles {
-        handle.join().unwrap();
+    Ok(())
+}
+
+// Compact while a snapshot keeps old versions, then lose the hint files.
+// Test the replay of the compaction segment keeps the newest versions.
+#[test]
+fn replay_compaction_without_hints() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    store.set(b"a".to_vec(), b"1".to_vec())?;
+    let mut batch = WriteBatch::default();
+    batch.set(b"x".to_vec(), b"1".to_vec());
+    batch.set(b"y".to_vec(), b"1".to_vec());
+    store.write_batch(batch)?;
+    let snapshot = store.snapshot();
+    store.set(b"a".to_vec(), b"2".to_vec())?;
+    store.set(b"x".to_vec(), b"2".to_vec())?;
+
+    // Overwrite other keys until the first segment is compacted away.
+    let mut iter = 0;
+    while temp_dir.path().join("1.log").exists() {
+        for key_id in 0..1000 {
+            store.set(
+                format!("key{}", key_id).into_bytes(),
+                format!("{}", iter).into_bytes(),
+            )?;
+        }
+        iter += 1;
+        assert!(iter < 1000, "No compaction detected");
+    }
+    assert_eq!(snapshot.get(b"a".to_vec())?, Some(b"1".to_vec()));
+    drop(snapshot);
+    drop(store);
+
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("hint".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+    let store = MyKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"a".to_vec())?, Some(b"2".to_vec()));
+    assert_eq!(store.get(b"x".to_vec())?, Some(b"2".to_vec()));
+    assert_eq!(store.get(b"y".to_vec())?, Some(b"1".to_vec()));
+    assert_eq!(
+        store.get(b"key0".to_vec())?,
+        Some(format!("{}", iter - 1).into_bytes())
+    );
+    Ok(())
+}
+
+// Open a sled database, waiting for the threads of sled to let go of the
+// lock of its directory after an earlier handle was dropped.
+fn wait_for_sled<T>(open: impl Fn() -> Result<T>) -> Result<T> {
+    let start = Instant::now();
+    loop {
+        match open() {
+            Err(KvsError::Sled(sled::Error::Io(ref e)))
+                if e.kind() == io::ErrorKind::Other
+                    && start.elapsed() < Duration::from_secs(10) =>
+            {
+                thread::sleep(Duration::from_millis(10));
+            }
+            res => return res,
+        }
+    }
+}
+
+fn backup_copy<E: KvEngine>(engine: E, open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = temp_dir.path().join("backup");
+    for i in 0..100 {
+        engine.set(format!("key{}", i).into_bytes(), b"before".to_vec())?;
+    }
+    engine.remove(b"key0".to_vec())?;
+    engine.backup(&backup_dir)?;
+    engine.set(b"key1".to_vec(), b"after".to_vec())?;
+    match engine.backup(&backup_dir) {
+        Err(KvsError::InvalidInput(_)) => {}
+        res => panic!("backup into a non-empty directory: {:?}", res),
+    }
+
+    let backup = open(&backup_dir)?;
+    assert_eq!(backup.get(b"key0".to_vec())?, None);
+    assert_eq!(backup.get(b"key1".to_vec())?, Some(b"before".to_vec()));
+    assert_eq!(backup.scan(Vec::new(), None, 1000)?.len(), 99);
+
+    // The backup and the store do not share writes.
+    backup.set(b"key2".to_vec(), b"backup".to_vec())?;
+    assert_eq!(engine.get(b"key2".to_vec())?, Some(b"before".to_vec()));
+    Ok(())
+}
+
+// Should back up the writes done before the backup and none done after
+#[test]
+fn backup_and_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    backup_copy(MyKvStore::open(temp_dir.path())?, |path| {
+        MyKvStore::open(path)
+    })?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    backup_copy(SledKvs::new(sled::open(temp_dir.path())?), |path| {
+        wait_for_sled(|| Ok(SledKvs::new(sled::open(path)?)))
+    })
+}
+
+// Take backups while batches trigger compactions.
+// Test every backup holds both keys of a pair written by one batch.
+#[test]
+fn backup_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    store.set(b"a".to_vec(), b"0".to_vec())?;
+    store.set(b"b".to_vec(), b"0".to_vec())?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 1..=2000 {
+                let value = format!("{:01000}", i).into_bytes();
+                let mut batch = WriteBatch::default();
+                batch.set(b"a".to_vec(), value.clone());
+                batch.set(b"b".to_vec(), value);
+                store.write_batch(batch)?;
+            }
+            Ok(())
+        })
+    };
+    let backup_root = TempDir::new().expect("unable to create temporary working directory");
+    for i in 0..10 {
+        let backup_dir = backup_root.path().join(format!("backup{}", i));
+        store.backup(&backup_dir)?;
+        let backup = MyKvStore::open(&backup_dir)?;
+        let a = backup.get(b"a".to_vec())?;
+        assert!(a.is_some());
+        assert_eq!(a, backup.get(b"b".to_vec())?);
+    }
+    writer.join().unwrap()?;
+    Ok(())
+}
+
+// Take sled backups while batches are written.
+// Test every backup holds both keys of a pair written by one batch.
+#[test]
+fn sled_backup_during_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvs::with_durability(sled::open(temp_dir.path())?, Durability::None);
+    for i in 0..1000 {
+        store.set(format!("key{}", i).into_bytes(), b"0".to_vec())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 1..=2000 {
+                let value = i.to_string().into_bytes();
+                let mut batch = WriteBatch::default();
+                batch.set(b"key0".to_vec(), value.clone());
+                batch.set(b"key999".to_vec(), value);
+                store.write_batch(batch)?;
+            }
+            Ok(())
+        })
+    };
+    let backup_root = TempDir::new().expect("unable to create temporary working directory");
+    for i in 0..10 {
+        let backup_dir = backup_root.path().join(format!("backup{}", i));
+        store.backup(&backup_dir)?;
+        let backup = wait_for_sled(|| Ok(SledKvs::new(sled::open(&backup_dir)?)))?;
+        let first = backup.get(b"key0".to_vec())?;
+        assert!(first.is_some());
+        assert_eq!(first, backup.get(b"key999".to_vec())?);
+    }
+    writer.join().unwrap()?;
+    Ok(())
+}
+
+// Should copy every pair from one engine to the other and back
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let kvs_store = MyKvStore::open(temp_dir.path().join("kvs"))?;
+    for i in 0..2500 {
+        kvs_store.set(
+            format!("key{}", i).into_bytes(),
+            format!("value{}", i).into_bytes(),
+        )?;
+    }
+    kvs_store.set(vec![0, 255], vec![255, 0])?;
+    kvs_store.remove(b"key0".to_vec())?;
+
+    let sled_store = SledKvs::new(sled::open(temp_dir.path().join("sled"))?);
+    let summary = migrate(&kvs_store, &sled_store)?;
+    assert_eq!(summary.keys, 2500);
+    assert_eq!(sled_store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(sled_store.get(vec![0, 255])?, Some(vec![255, 0]));
+    assert_eq!(sled_store.get(b"key0".to_vec())?, None);
+
+    // A target holding keys already is refused.
+    match migrate(&sled_store, &kvs_store) {
+        Err(KvsError::InvalidInput(_)) => {}
+        res => panic!("migration into a store holding keys: {:?}", res),
+    }
+
+    let kvs_copy = MyKvStore::open(temp_dir.path().join("kvs_copy"))?;
+    assert_eq!(migrate(&sled_store, &kvs_copy)?, summary);
+    assert_eq!(
+        kvs_copy.scan(Vec::new(), None, 3000)?,
+        kvs_store.scan(Vec::new(), None, 3000)?
+    );
+    Ok(())
+}
+
+// An engine whose scans return one pair less than asked for, as an engine
+// leaving out expired keys after taking the page may.
+#[derive(Clone)]
+struct ShortPages(MyKvStore);
+
+impl KvEngine for ShortPages {
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.0.set_with_ttl(key, value, ttl)
+    }
+
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.0.get(key)
+    }
+
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        self.0.remove(key)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.0.write_batch(batch)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.0.compare_and_swap(key, expected, new)
+    }
+
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.0.scan(start, end, limit.saturating_sub(1))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+
+    fn backup(&self, dest: &Path) -> Result<()> {
+        self.0.backup(dest)
+    }
+}
+
+// Should copy every pair from an engine returning short pages
+#[test]
+fn migrate_short_pages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = ShortPages(MyKvStore::open(temp_dir.path().join("source"))?);
+    for i in 0..2500 {
+        source.set(
+            format!("key{}", i).into_bytes(),
+            format!("value{}", i).into_bytes(),
+        )?;
+    }
+
+    let target = MyKvStore::open(temp_dir.path().join("target"))?;
+    assert_eq!(migrate(&source, &target)?.keys, 2500);
+    assert_eq!(
+        target.scan(Vec::new(), None, 3000)?,
+        source.0.scan(Vec::new(), None, 3000)?
+    );
+    Ok(())
+}
+
+// Should record the engine and options in the manifest and refuse other engines
+#[test]
+fn store_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_eq!(Manifest::load(temp_dir.path())?, None);
+    drop(MyKvStore::open(temp_dir.path())?);
+    let manifest = Manifest::load(temp_dir.path())?.expect("manifest of a kvs store");
+    assert_eq!(manifest.engine, EngineKind::Kvs);
+    assert_eq!(manifest.format_version, 4);
+    assert!(!manifest.encrypted);
+
+    let options = KvStoreOptions::default()
+        .compress_above(Some(64))
+        .encryption_key(Some(EncryptionKey::new([1; 32])));
+    drop(MyKvStore::open_with_options(temp_dir.path(), options)?);
+    let reopened = Manifest::load(temp_dir.path())?.expect("manifest of a kvs store");
+    assert!(reopened.encrypted);
+    assert_eq!(reopened.compress_above, Some(64));
+    assert_eq!(reopened.created_millis, manifest.created_millis);
+    match MyKvStore::open(temp_dir.path()) {
+        Err(KvsError::Encryption(_)) => {}
+        res => panic!("encrypted store opened without a key: {:?}", res.is_ok()),
+    }
+
+    match SledKvs::open(temp_dir.path(), Durability::None, Some(1 << 20)) {
+        Err(KvsError::Manifest(_)) => {}
+        res => panic!("sled opened a kvs store: {:?}", res.is_ok()),
+    }
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(SledKvs::open(
+        temp_dir.path(),
+        Durability::None,
+        Some(1 << 20),
+    )?);
+    match MyKvStore::open(temp_dir.path()) {
+        Err(KvsError::Manifest(_)) => {}
+        res => panic!("kvs opened a sled store: {:?}", res.is_ok()),
+    }
+    Ok(())
+}
+
+// Should upgrade the meta file of earlier versions and reject unknown manifests
+#[test]
+fn upgrade_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("meta"), "sled\n")?;
+    let manifest = Manifest::load(temp_dir.path())?.expect("manifest from the meta file");
+    assert_eq!(manifest.engine, EngineKind::Sled);
+    assert_eq!(manifest.version, 1);
+    assert!(!temp_dir.path().join("meta").exists());
+    assert_eq!(Manifest::load(temp_dir.path())?, Some(manifest));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("meta"), "rocksdb")?;
+    match Manifest::load(temp_dir.path()) {
+        Err(KvsError::Manifest(_)) => {}
+        res => panic!("unknown engine accepted: {:?}", res),
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("MANIFEST"),
+        r#"{"version": 2, "engine": "kvs"}"#,
+    )?;
+    match MyKvStore::open(temp_dir.path()) {
+        Err(KvsError::Manifest(_)) => {}
+        res => panic!("newer manifest accepted: {:?}", res.is_ok()),
+    }
+    Ok(())
+}
+
+// Should refuse a second store writing the same directory until the first is dropped
+#[test]
+fn lock_data_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = MyKvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    match MyKvStore::open(temp_dir.path()) {
+        Err(KvsError::Locked(_)) => {}
+        res => panic!("second store opened a locked directory: {:?}", res.is_ok()),
+    }
+    match MyKvStore::rotate_key(temp_dir.path(), None, Some(&EncryptionKey::new([1; 32]))) {
+        Err(KvsError::Locked(_)) => {}
+        res => panic!("key rotated in a locked directory: {:?}", res),
+    }
+
+    // A clone shares the lock of the store.
+    let clone = store.clone();
+    drop(store);
+    assert!(MyKvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+    let store = MyKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+// Should read the store alongside its writer, and refuse writes
+#[test]
+fn read_only_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let read_only = KvStoreOptions::default().read_only(true);
+    assert!(MyKvStore::open_with_options(temp_dir.path(), read_only.clone()).is_err());
+
+    let store = MyKvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i).into_bytes(), b"old".to_vec())?;
+    }
+    store.flush()?;
+    let reader = MyKvStore::open_with_options(temp_dir.path(), read_only.clone())?;
+    match reader.set(b"key1".to_vec(), b"new".to_vec()) {
+        Err(KvsError::ReadOnly) => {}
+        res => panic!("read-only store written: {:?}", res),
+    }
+    match reader.remove(b"key1".to_vec()) {
+        Err(KvsError::ReadOnly) => {}
+        res => panic!("read-only store written: {:?}", res),
+    }
+
+    // The read-only store keeps reading the segments the writer compacts away.
+    for iter in 0..5 {
+        for i in 0..1000 {
+            let value = format!("{:01000}", iter);
+            store.set(format!("key{}", i).into_bytes(), value.into_bytes())?;
+        }
+    }
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while temp_dir.path().join("1.log").exists() {
+        assert!(
+            Instant::now() < deadline,
+            "no compaction removed the first segment"
+        );
+        thread::sleep(Duration::from_millis(10));
     }
+    assert_eq!(reader.get(b"key1".to_vec())?, Some(b"old".to_vec()));
+    assert_eq!(reader.scan(Vec::new(), None, 2000)?.len(), 1000);
 
+    store.flush()?;
+    let reader = MyKvStore::open_with_options(temp_dir.path(), read_only)?;
+    assert_eq!(
+        reader.get(b"key999".to_vec())?,
+        Some(format!("{:01000}", 4).into_bytes())
+    );
     Ok(())
 }
@@ -0,0 +1,281 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AsyncKvsClient, AsyncKvsServer, AsyncShutdownHandle, Codec, KvEngine, KvsClient, KvsServer,
+    MyKvStore, Request, Response, Result, ShutdownHandle,
+};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+// Start a server on a store in a fresh directory.
+//
+// The server stops when the handle is dropped.
+fn start_server(addr: &str) -> Result<(TempDir, ShutdownHandle)> {
+    let temp_dir = TempDir::new()?;
+    let engine = MyKvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let handle = KvsServer::new(engine, pool).start(addr)?;
+    Ok((temp_dir, handle))
+}
+
+// Start an async server on a store in a fresh directory, on a runtime of its own.
+fn start_async_server(addr: &str) -> Result<(TempDir, Runtime, AsyncShutdownHandle)> {
+    let temp_dir = TempDir::new()?;
+    let engine = MyKvStore::open(temp_dir.path())?;
+    let mut runtime = Runtime::new()?;
+    let handle = runtime.block_on(AsyncKvsServer::new(engine).start(addr))?;
+    Ok((temp_dir, runtime, handle))
+}
+
+// Many requests may be in flight on one connection before any response is read.
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let addr = "127.0.0.1:4010";
+    let _server = start_server(addr)?;
+    let mut client = KvsClient::init(addr)?;
+    assert_eq!(client.codec(), Codec::Bincode);
+
+    let sets = (0..100u32)
+        .map(|i| {
+            client.send(Request::Set {
+                key: format!("key{}", i).into_bytes(),
+                value: format!("value{}", i).into_bytes(),
+                ttl: None,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let gets = (0..100u32)
+        .map(|i| {
+            client.send(Request::Get {
+                key: format!("key{}", i).into_bytes(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Read the responses out of order.
+    for (i, id) in gets.into_iter().enumerate().rev() {
+        assert_eq!(
+            client.receive(id)?,
+            Response::Value(Some(format!("value{}", i).into_bytes()))
+        );
+    }
+    for id in sets {
+        assert_eq!(client.receive(id)?, Response::Done);
+    }
+    Ok(())
+}
+
+// A client may ask for json frames instead of bincode.
+#[test]
+fn json_codec() -> Result<()> {
+    let addr = "127.0.0.1:4011";
+    let _server = start_server(addr)?;
+    let mut client = KvsClient::init_with_codec(addr, Codec::Json)?;
+    assert_eq!(client.codec(), Codec::Json);
+
+    client.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert_eq!(client.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert!(client.set_if_absent(b"key2".to_vec(), b"value2".to_vec())?);
+    assert_eq!(client.scan_prefix(b"key".to_vec(), 10)?.len(), 2);
+    client.remove(b"key1".to_vec())?;
+    assert_eq!(client.get(b"key1".to_vec())?, None);
+    assert!(client.remove(b"key1".to_vec()).is_err());
+    Ok(())
+}
+
+// Clients of earlier versions send bare json requests without a handshake.
+#[test]
+fn legacy_json_requests() -> Result<()> {
+    let addr = "127.0.0.1:4012";
+    let _server = start_server(addr)?;
+    legacy_json(addr)
+}
+
+#[test]
+fn async_server_legacy_json_requests() -> Result<()> {
+    let addr = "127.0.0.1:4014";
+    let _server = start_async_server(addr)?;
+    legacy_json(addr)
+}
+
+fn legacy_json(addr: &str) -> Result<()> {
+    let stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut response = || -> Result<Vec<u8>> {
+        let mut line = Vec::new();
+        reader.read_until(b'}', &mut line)?;
+        Ok(line)
+    };
+
+    // The requests and responses of the tools of earlier versions, verbatim.
+    writer.write_all(br#"{"Set":{"key":"key1","value":"value1"}}"#)?;
+    writer.write_all(br#"{"Get":{"key":"key1"}}"#)?;
+    writer.write_all(br#"{"Get":{"key":"key2"}}"#)?;
+    writer.write_all(br#"{"Remove":{"key":"key2"}}"#)?;
+    writer.flush()?;
+    assert_eq!(response()?, br#"{"Ok":null}"#.to_vec());
+    assert_eq!(response()?, br#"{"Ok":"value1"}"#.to_vec());
+    assert_eq!(response()?, br#"{"Ok":null}"#.to_vec());
+    assert_eq!(response()?, br#"{"Err":"Key not found"}"#.to_vec());
+
+    // Any request with keys and values as byte arrays.
+    writer.write_all(br#"{"Get":{"key":[107,101,121,49]}}"#)?;
+    writer.flush()?;
+    assert_eq!(response()?, br#"{"Ok":[118,97,108,117,101,49]}"#.to_vec());
+    Ok(())
+}
+
+#[test]
+fn async_client_and_server() -> Result<()> {
+    let addr = "127.0.0.1:4015";
+    let (_temp_dir, mut runtime, _handle) = start_async_server(addr)?;
+    runtime.block_on(async_client(addr))
+}
+
+async fn async_client(addr: &str) -> Result<()> {
+    let mut client = AsyncKvsClient::connect(addr).await?;
+
+    client.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    assert_eq!(
+        client.get(b"key1".to_vec()).await?,
+        Some(b"value1".to_vec())
+    );
+    assert!(
+        !client
+            .set_if_absent(b"key1".to_vec(), b"value2".to_vec())
+            .await?
+    );
+    client.remove(b"key1".to_vec()).await?;
+    assert!(client.remove(b"key1".to_vec()).await.is_err());
+
+    let mut ids = Vec::new();
+    for i in 0..100u32 {
+        let key = format!("key{}", i).into_bytes();
+        ids.push(
+            client
+                .send(Request::Set {
+                    key: key.clone(),
+                    value: key,
+                    ttl: None,
+                })
+                .await?,
+        );
+    }
+    for id in ids {
+        assert_eq!(client.receive(id).await?, Response::Done);
+    }
+    assert_eq!(client.scan_prefix(b"key".to_vec(), 1000).await?.len(), 100);
+    Ok(())
+}
+
+// Idle connections hold no thread of the async server.
+#[test]
+fn async_server_idle_connections() -> Result<()> {
+    let addr = "127.0.0.1:4016";
+    let _server = start_async_server(addr)?;
+    let _idle = (0..64)
+        .map(|_| KvsClient::init(addr))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut client = KvsClient::init(addr)?;
+    client.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert_eq!(client.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+// A shutdown answers the requests already sent, closes the connections and
+// flushes the store.
+#[test]
+fn graceful_shutdown() -> Result<()> {
+    let addr = "127.0.0.1:4017";
+    let (temp_dir, handle) = start_server(addr)?;
+    let mut client = KvsClient::init(addr)?;
+    let mut idle = KvsClient::init(addr)?;
+    let ids = (0..100u32)
+        .map(|i| {
+            client.send(Request::Set {
+                key: format!("key{}", i).into_bytes(),
+                value: b"value".to_vec(),
+                ttl: None,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(client.receive(ids[0])?, Response::Done);
+
+    handle.shutdown()?;
+    for id in ids.into_iter().skip(1) {
+        assert_eq!(client.receive(id)?, Response::Done);
+    }
+    assert!(idle.get(b"key1".to_vec()).is_err());
+    assert!(KvsClient::init(addr).is_err());
+
+    let store = MyKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key99".to_vec())?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn async_graceful_shutdown() -> Result<()> {
+    let addr = "127.0.0.1:4018";
+    let (temp_dir, mut runtime, handle) = start_async_server(addr)?;
+    let mut client = KvsClient::init(addr)?;
+    let mut idle = KvsClient::init(addr)?;
+    client.set(b"key1".to_vec(), b"value1".to_vec())?;
+
+    runtime.block_on(handle.shutdown())?;
+    assert!(idle.get(b"key1".to_vec()).is_err());
+    assert!(client.get(b"key1".to_vec()).is_err());
+    assert!(KvsClient::init(addr).is_err());
+
+    let store = MyKvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+// A client can back up the store of the server while it serves, only to
+// directories below the backup root of the server.
+#[test]
+fn backup_request() -> Result<()> {
+    let addr = "127.0.0.1:4019";
+    let temp_dir = TempDir::new()?;
+    let backup_root = TempDir::new()?;
+    let engine = MyKvStore::open(temp_dir.path())?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    let _handle = KvsServer::new(engine, pool)
+        .backup_root(backup_root.path())
+        .start(addr)?;
+    let mut client = KvsClient::init(addr)?;
+    client.set(b"key1".to_vec(), b"value1".to_vec())?;
+
+    client.backup("daily/1")?;
+    client.set(b"key1".to_vec(), b"value2".to_vec())?;
+    assert!(client.backup("daily/1").is_err());
+    assert!(client.backup(temp_dir.path()).is_err());
+    assert!(client.backup("../escaped").is_err());
+    assert!(client.backup("daily/../../escaped").is_err());
+    assert!(!backup_root
+        .path()
+        .parent()
+        .unwrap()
+        .join("escaped")
+        .exists());
+
+    let backup = MyKvStore::open(backup_root.path().join("daily/1"))?;
+    assert_eq!(backup.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+// A server without a backup root refuses every backup request.
+#[test]
+fn backup_request_without_root() -> Result<()> {
+    let addr = "127.0.0.1:4020";
+    let (temp_dir, _handle) = start_server(addr)?;
+    let mut client = KvsClient::init(addr)?;
+    let backup_dir = temp_dir.path().join("backup");
+    assert!(client.backup(&backup_dir).is_err());
+    assert!(client.backup("backup").is_err());
+    assert!(!backup_dir.exists());
+    Ok(())
+}